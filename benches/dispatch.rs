@@ -0,0 +1,53 @@
+//! Compares stepping through `execute_istr` with the lowered dispatch loop
+//! behind `run`. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+use vcpu::structures::interpreter::Interpreter;
+use vcpu::structures::structures::GeneralStructure;
+
+/// A counting loop with a call per iteration, about 7 instructions a turn.
+const LOOP: &str = "
+    mov rcx, 0
+    mov rbx, 0
+again:
+    add rcx, 1
+    call bump
+    cmp rcx, 1000000
+    jne again
+    pnl rbx
+    hlt
+bump:
+    add rbx, rcx
+    ret
+";
+
+fn vm() -> GeneralStructure {
+    let program = Interpreter::from_source(String::from(LOOP)).expect("benchmark program");
+    let mut vm = GeneralStructure::init(program.code, &program.data);
+    vm.env_mut().set_output(Box::new(std::io::sink()));
+    vm
+}
+
+fn time(run: impl Fn(&mut GeneralStructure)) -> Duration {
+    (0..5)
+        .map(|_| {
+            let mut vm = vm();
+            let start = Instant::now();
+            run(&mut vm);
+            assert!(vm.finished());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let stepped = time(|vm| while vm.step().unwrap() {});
+    let decoded = time(|vm| vm.run().unwrap());
+    println!("step loop:     {:>10.2?}", stepped);
+    println!("decoded loop:  {:>10.2?}", decoded);
+    println!(
+        "speedup:       {:>10.2}x",
+        stepped.as_secs_f64() / decoded.as_secs_f64()
+    );
+}
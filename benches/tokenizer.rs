@@ -0,0 +1,53 @@
+//! Parses and tokenizes a generated 100k-line program in one pass. Run
+//! with `cargo bench`.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use vcpu::structures::parser::Parser;
+use vcpu::structures::tokenizer::Tokenizer;
+
+const LINES: usize = 100_000;
+
+/// Every tenth line is a label, the rest mix mnemonics, registers in all
+/// spellings, memory operands, numbers and jumps back to the labels.
+fn source() -> String {
+    let mut source = String::new();
+    for i in 0..LINES {
+        let label = i / 10;
+        let line = match i % 10 {
+            0 => format!("block{}:", label),
+            1 => String::from("    mov rax, 1"),
+            2 => String::from("    add EAX, 0x10"),
+            3 => format!("    mov [rbx+{}], rcx", i % 64),
+            4 => String::from("    Cmp rax, rcx ; compare"),
+            5 => format!("    jne block{}", label),
+            6 => String::from("    push dx"),
+            7 => String::from("    pop rdx"),
+            8 => format!("    call block{}", label / 2),
+            _ => String::from("    syscall"),
+        };
+        writeln!(source, "{}", line).unwrap();
+    }
+    source
+}
+
+fn main() {
+    let source = source();
+    let best: Duration = (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            let mut parser = Parser::init(source.clone());
+            parser.parse();
+            let mut tokenizer = Tokenizer::init(parser);
+            tokenizer.tokenize();
+            assert!(tokenizer.errors.is_empty());
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    println!("{} lines:      {:>10.2?}", LINES, best);
+    println!(
+        "lines/second:     {:>10.0}",
+        LINES as f64 / best.as_secs_f64()
+    );
+}
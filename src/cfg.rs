@@ -0,0 +1,353 @@
+use crate::structures::data_types::GeneralData;
+use crate::structures::flow_structure::OpCode;
+use crate::structures::interpreter::Program;
+use crate::testing::TEST_PREFIX;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    /// On to the next instruction, including a branch not taken.
+    Fall,
+    /// JMP, or a JE/JNE that is taken.
+    Jump,
+    Call,
+    /// From a CALL to the instruction after it, once the callee returns.
+    Return,
+    /// A jump or call through a register or memory, to any label.
+    Indirect,
+}
+
+#[derive(Debug, Clone)]
+pub struct Edge {
+    /// Index of the block jumped to.
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// Instructions `start..end`, entered only at `start` and left only after
+/// the last one.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    /// Labels naming `start`.
+    pub labels: Vec<String>,
+    pub edges: Vec<Edge>,
+    /// Control can run past the last instruction of the program from here.
+    pub falls_off: bool,
+}
+
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    /// The block each instruction belongs to.
+    block_of: Vec<usize>,
+}
+
+/// Something suspicious about the control flow, not an error.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: warning: {}", self.line, self.message)
+    }
+}
+
+/// Where a jump or call to `target` may land. A register or memory target
+/// may be any label.
+pub fn targets(program: &Program, target: Option<&GeneralData>) -> Vec<usize> {
+    match target {
+        Some(GeneralData::Int64(pc)) => usize::try_from(*pc).into_iter().collect(),
+        _ => {
+            let labels: BTreeSet<usize> = program.labels.values().copied().collect();
+            labels.into_iter().collect()
+        }
+    }
+}
+
+/// The instruction indices control may go to after `pc`, some possibly
+/// past the end of the code. RET has none, CALL goes to its callee and
+/// to the instruction after it.
+fn successors(program: &Program, pc: usize) -> Vec<(usize, EdgeKind)> {
+    let istr = &program.code[pc];
+    let target = istr.arguments.first();
+    let kind = match target {
+        Some(GeneralData::Int64(_)) => EdgeKind::Jump,
+        _ => EdgeKind::Indirect,
+    };
+    let jumps = |kind: EdgeKind| {
+        targets(program, target)
+            .into_iter()
+            .map(move |to| (to, kind))
+    };
+    match istr.op_code {
+        OpCode::JMP => jumps(kind).collect(),
+        OpCode::JE | OpCode::JNE => jumps(kind).chain([(pc + 1, EdgeKind::Fall)]).collect(),
+        OpCode::CALL => {
+            let kind = if kind == EdgeKind::Jump {
+                EdgeKind::Call
+            } else {
+                kind
+            };
+            jumps(kind).chain([(pc + 1, EdgeKind::Return)]).collect()
+        }
+        OpCode::RET | OpCode::HLT | OpCode::EXIT => Vec::new(),
+        _ => vec![(pc + 1, EdgeKind::Fall)],
+    }
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Cfg {
+        let len = program.code.len();
+        let mut leaders: BTreeSet<usize> = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        leaders.extend(program.labels.values().copied());
+        for pc in 0..len {
+            let next = successors(program, pc);
+            if next.iter().any(|(_, kind)| *kind != EdgeKind::Fall) || next.is_empty() {
+                leaders.extend(next.iter().map(|(to, _)| *to));
+                leaders.insert(pc + 1);
+            }
+        }
+        leaders.retain(|pc| *pc < len);
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut block_of: Vec<usize> = vec![0; len];
+        let mut blocks: Vec<Block> = Vec::with_capacity(starts.len());
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(len);
+            block_of[*start..end].fill(i);
+            let mut labels: Vec<String> = program
+                .labels
+                .iter()
+                .filter(|(_, pos)| **pos == *start)
+                .map(|(name, _)| name.clone())
+                .collect();
+            labels.sort();
+            blocks.push(Block {
+                start: *start,
+                end,
+                labels,
+                edges: Vec::new(),
+                falls_off: false,
+            });
+        }
+        for block in &mut blocks {
+            for (to, kind) in successors(program, block.end - 1) {
+                if to >= len {
+                    block.falls_off |= kind != EdgeKind::Indirect;
+                    continue;
+                }
+                let edge = Edge {
+                    to: block_of[to],
+                    kind,
+                };
+                if !block
+                    .edges
+                    .iter()
+                    .any(|e| e.to == edge.to && e.kind == kind)
+                {
+                    block.edges.push(edge);
+                }
+            }
+        }
+        Cfg { blocks, block_of }
+    }
+
+    /// The block holding instruction `pc`.
+    pub fn block_of(&self, pc: usize) -> Option<usize> {
+        self.block_of.get(pc).copied()
+    }
+
+    /// Which blocks can run, starting from the first instruction or a
+    /// test.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut work: Vec<usize> = (0..self.blocks.len())
+            .filter(|id| {
+                *id == 0
+                    || self.blocks[*id]
+                        .labels
+                        .iter()
+                        .any(|name| name.starts_with(TEST_PREFIX))
+            })
+            .collect();
+        while let Some(id) = work.pop() {
+            if std::mem::replace(&mut seen[id], true) {
+                continue;
+            }
+            work.extend(self.blocks[id].edges.iter().map(|e| e.to));
+        }
+        seen
+    }
+
+    /// Unreachable blocks and labels nothing jumps to.
+    pub fn lint(&self, program: &Program) -> Vec<Warning> {
+        let mut warnings: Vec<Warning> = Vec::new();
+        let line = |pc: usize| program.code[pc].line;
+        let reachable = self.reachable();
+
+        for (block, reached) in self.blocks.iter().zip(&reachable) {
+            if !reached {
+                warnings.push(Warning {
+                    line: line(block.start),
+                    message: String::from("unreachable code"),
+                });
+            }
+        }
+
+        // A label is used when the assembler resolved some operand or DQ
+        // item to it. The entry point is run anyway and tests are run by
+        // `vcpu test`.
+        let used: BTreeSet<&str> = program
+            .references
+            .iter()
+            .map(|reference| reference.label.as_str())
+            .collect();
+        let mut labels: Vec<(&String, &usize)> = program
+            .labels
+            .iter()
+            .filter(|(name, pos)| {
+                **pos != 0
+                    && **pos < program.code.len()
+                    && !name.starts_with(TEST_PREFIX)
+                    && !used.contains(name.as_str())
+            })
+            .collect();
+        labels.sort_by_key(|(name, pos)| (**pos, *name));
+        for (name, pos) in labels {
+            warnings.push(Warning {
+                line: line(*pos),
+                message: format!("label '{}' is never jumped to", name),
+            });
+        }
+
+        warnings.sort_by_key(|w| w.line);
+        warnings
+    }
+
+    /// The graph in Graphviz DOT. Unreachable blocks are grey and edges
+    /// back to an earlier block, closing a loop, are blue.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut out =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        let reachable = self.reachable();
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut text = String::new();
+            for name in &block.labels {
+                text.push_str(&format!("{}:\\l", escape(name)));
+            }
+            for pc in block.start..block.end {
+                let istr = &program.code[pc];
+                text.push_str(&format!("#{}  {}\\l", pc, escape(&istr.to_string())));
+            }
+            let style = if reachable[id] {
+                ""
+            } else {
+                ", style=dashed, color=grey, fontcolor=grey"
+            };
+            out.push_str(&format!("    b{} [label=\"{}\"{}];\n", id, text, style));
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for edge in &block.edges {
+                let mut attrs: Vec<&str> = match edge.kind {
+                    EdgeKind::Fall => vec![],
+                    EdgeKind::Jump => vec!["label=\"jump\""],
+                    EdgeKind::Call => vec!["label=\"call\"", "style=bold"],
+                    EdgeKind::Return => vec!["style=dashed"],
+                    EdgeKind::Indirect => vec!["style=dotted"],
+                };
+                if edge.to <= id && edge.kind != EdgeKind::Return {
+                    attrs.push("color=blue");
+                }
+                let attrs = if attrs.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", attrs.join(", "))
+                };
+                out.push_str(&format!("    b{} -> b{}{};\n", id, edge.to, attrs));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' | '\\' | '{' | '}' | '<' | '>' | '|' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cfg, EdgeKind};
+    use crate::test_support::assemble;
+
+    fn lint(source: &str) -> Vec<String> {
+        let program = assemble(source);
+        let cfg = Cfg::build(&program);
+        cfg.lint(&program).iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn blocks_and_edges() {
+        let program = assemble(
+            "mov rax, 1\nloop: cmp rax, 3\nje done\ncall step\njmp loop\n\
+                                step: add rax, 1\nret\ndone: hlt\n",
+        );
+        let cfg = Cfg::build(&program);
+        let spans: Vec<(usize, usize)> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(spans, [(0, 1), (1, 3), (3, 4), (4, 5), (5, 7), (7, 8)]);
+        let edges: Vec<(usize, EdgeKind)> =
+            cfg.blocks[1].edges.iter().map(|e| (e.to, e.kind)).collect();
+        assert_eq!(edges, [(5, EdgeKind::Jump), (2, EdgeKind::Fall)]);
+        let edges: Vec<(usize, EdgeKind)> =
+            cfg.blocks[2].edges.iter().map(|e| (e.to, e.kind)).collect();
+        assert_eq!(edges, [(4, EdgeKind::Call), (3, EdgeKind::Return)]);
+        assert_eq!(cfg.block_of(6), Some(4));
+        assert_eq!(cfg.reachable(), [true; 6]);
+    }
+
+    #[test]
+    fn unreachable_code_and_unused_labels() {
+        assert_eq!(
+            lint("jmp end\nmov rax, 1\nunused: nop\nend: hlt\n"),
+            [
+                "2: warning: unreachable code",
+                "3: warning: unreachable code",
+                "3: warning: label 'unused' is never jumped to"
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_are_used_by_reference_not_by_value() {
+        // `mov rax, 2` holds the index of `two` but does not name it.
+        assert_eq!(
+            lint("mov rax, 2\nnop\ntwo: nop\nthree: nop\nhlt\nptr: dq three\n"),
+            ["3: warning: label 'two' is never jumped to"]
+        );
+        assert!(lint("mov rax, 1\ntest_it: ret\n").is_empty());
+    }
+
+    #[test]
+    fn running_off_the_end_is_not_a_warning() {
+        assert!(lint("mov rax, 1\nmsg: db \"hi\"\n").is_empty());
+    }
+}
@@ -0,0 +1,901 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use vcpu::cfg::Cfg;
+use vcpu::coverage::{Coverage, Summary};
+use vcpu::dap::DapServer;
+use vcpu::debugger::Debugger;
+use vcpu::lsp::LspServer;
+use vcpu::object;
+use vcpu::profiler::Profiler;
+use vcpu::structures::data_types::GeneralData;
+use vcpu::structures::env_vars::{VmError, DATA_BASE};
+use vcpu::structures::flow_structure::{FlowStructure, OpCode};
+use vcpu::structures::host::{SandboxHost, SystemHost};
+use vcpu::structures::interpreter::{Interpreter, Program};
+use vcpu::structures::registers::Register;
+use vcpu::structures::structures::GeneralStructure;
+use vcpu::testing::TestCase;
+use vcpu::vfs::{FileSystem, HostFs, MemoryFs};
+use vcpu::{formatter, gdb, optimizer, testing, typecheck};
+
+const USAGE: &str = "usage: vcpu [command] [options] <file> [args...]
+
+commands:
+    run       assemble and run a program, the default
+    asm       assemble into an object file
+    disasm    list an assembled program
+    check     report syntax and type errors without running
+    cfg       write the control-flow graph as Graphviz DOT
+    debug     step through a program, or serve gdb with --gdb
+    repl      run instructions as they are typed
+    fmt       format sources: fmt [--check | --write] <files>
+    test      run the test_ labels of programs, or programs as a whole
+    lsp       serve the Language Server Protocol on stdio
+    dap       serve the Debug Adapter Protocol on stdio
+
+options:
+    -q, --quiet        no banner
+    --trace            print each instruction to stderr as it runs
+    --profile          with run, print the busiest instructions and labels
+    --folded <file>    with run, write folded call stacks for flame graphs
+    --coverage <file>  with run or test, write lcov coverage and print a summary
+    --fail-under <n>   with --coverage, fail when under n% of lines ran
+    --max-steps <n>    stop after n instructions, exiting with 124, with
+                       test the budget of each test, 10000000 by default
+    --junit <file>     with test, write the results as JUnit XML
+    --typecheck        with run, refuse programs that fail check
+    -O, --optimize     with run, asm, disasm or cfg, optimise the code first
+    --report           with -O, list what the optimiser changed on stderr
+    --stdin <file>     read program input from a file
+    --sandbox          fixed clock and random
+    --allow-fs         let the program open host files, by default it only
+                       sees files it creates itself, kept in memory
+    --root <dir>       with --allow-fs, confine file access to a directory
+    -o <file>          where asm or cfg write, - for stdout
+    --gdb <port>       with debug, serve gdb on a port, - for stdio
+
+Source and object files are both accepted, - reads stdin. Arguments
+after the program file are passed to it.
+
+A runtime error such as a division by zero is reported as file:line:
+message and exits with 70. Mistakes in the command line exit with 2.";
+
+/// Exit code for a mistake in the command line.
+const USAGE_EXIT: i32 = 2;
+
+/// Exit code after a runtime error, EX_SOFTWARE from sysexits.h.
+const RUNTIME_ERROR_EXIT: i32 = 70;
+
+/// Exit code when --max-steps runs out, as timeout(1) uses.
+const STEP_LIMIT_EXIT: i32 = 124;
+
+/// Instructions each test may run without --max-steps.
+const TEST_BUDGET: u64 = 10_000_000;
+
+#[derive(Default)]
+struct Options {
+    quiet: bool,
+    trace: bool,
+    profile: bool,
+    folded: Option<String>,
+    coverage: Option<String>,
+    junit: Option<String>,
+    fail_under: Option<f64>,
+    max_steps: Option<u64>,
+    typecheck: bool,
+    optimize: bool,
+    report: bool,
+    stdin: Option<String>,
+    sandbox: bool,
+    allow_fs: bool,
+    root: Option<String>,
+    output: Option<String>,
+    gdb: Option<String>,
+    /// Program files, or for `run` and `debug` the program and its
+    /// arguments.
+    files: Vec<String>,
+}
+
+impl Options {
+    /// With `one_program` the first file ends option parsing, the rest
+    /// are the program's arguments.
+    fn parse(args: &[String], one_program: bool) -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-q" | "--quiet" => opts.quiet = true,
+                "--trace" => opts.trace = true,
+                "--profile" => opts.profile = true,
+                "--folded" => opts.folded = Some(value()?),
+                "--coverage" => opts.coverage = Some(value()?),
+                "--junit" => opts.junit = Some(value()?),
+                "--fail-under" => {
+                    let n = value()?;
+                    opts.fail_under =
+                        Some(n.parse().map_err(|_| format!("bad percentage '{}'", n))?);
+                }
+                "--typecheck" => opts.typecheck = true,
+                "-O" | "--optimize" => opts.optimize = true,
+                "--report" => opts.report = true,
+                "--max-steps" => {
+                    let n = value()?;
+                    opts.max_steps =
+                        Some(n.parse().map_err(|_| format!("bad step count '{}'", n))?);
+                }
+                "--stdin" => opts.stdin = Some(value()?),
+                "--sandbox" => opts.sandbox = true,
+                "--allow-fs" => opts.allow_fs = true,
+                "--root" => opts.root = Some(value()?),
+                "-o" => opts.output = Some(value()?),
+                "--gdb" => opts.gdb = Some(value()?),
+                "--" => {
+                    opts.files.extend(args.cloned());
+                    break;
+                }
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(format!("unknown option '{}'", arg))
+                }
+                _ => {
+                    opts.files.push(arg.clone());
+                    if one_program {
+                        opts.files.extend(args.cloned());
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(opts)
+    }
+}
+
+fn print_banner() {
+    println!(
+        "⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⣀⣀⣀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀
+⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⣀⡤⠖⠚⠉⠁⠀⠀⠉⠙⠒⢄⠀⠀⠀⠀⠀⠀
+⠀⠀⠀⠀⠀⠀⠀⢀⠔⠋⠁⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠉⢢⡀⠀⠀⠀
+⠀⠀⠀⠀⠀⠀⡰⠋⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠙⣆⠀⠀
+⠀⠀⠀⠀⠀⢠⠁⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠈⢇⠀
+⠀⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠘⡄
+⠀⠀⠀⠀⢸⠄⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠇
+⠀⠀⠀⠀⠸⡆⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀ ⠇
+⠀⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⡘       _________________________
+⠀⠀⠀⠀⠀⢻⠀⠀⠀⠀⠀⠀⠀⢀⣴⣶⡄⠀⠀⠀⠀⠀⢀⣶⡆⠀⢠⠇      /    They don't know     /
+⠀⠀⠀⠀⠀⠀⣣⠀⠀⠀⠀⠀⠀⠀⠙⠛⠁⠀⠀⠀⠀⠀⠈⠛⠁⡰⠃⠀     /  I made a CPU emulator /
+⠀⠀⠀⠀⢠⠞⠋⢳⢤⡀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⢀⠜⠁------/________________________/
+⠀⠀⠀⣰⠋⠀⠀⠀⢷⠙⠲⢤⣀⡀⠀⠀⠀⠀⠴⠴⣆⠴⠚⠁⠀⠀⠀⠀
+⠀⠀⣰⠃⠀⠀⠀⠀⠘⡇⠀⣀⣀⡉⠙⠒⠒⠒⡎⠉⠀⠀⠀⠀⠀⠀⠀⠀
+⠀⢠⠃⠀⠀⢶⠀⠀⠀⢳⠋⠁⠀⠙⢳⡠⠖⠚⠑⠲⡀⠀⠀⠀⠀⠀⠀⠀
+⠀⡎⠀⠀⠀⠘⣆⠀⠀⠈⢧⣀⣠⠔⡺⣧⠀⡴⡖⠦⠟⢣⠀⠀⠀⠀⠀⠀
+⢸⠀⠀⠀⠀⠀⢈⡷⣄⡀⠀⠀⠀⠀⠉⢹⣾⠁⠁⠀⣠⠎⠀⠀⠀⠀⠀⠀
+⠈⠀⠀⠀⠀⠀⡼⠆⠀⠉⢉⡝⠓⠦⠤⢾⠈⠓⠖⠚⢹⠀⠀⠀⠀⠀⠀⠀
+⢰⡀⠀⠀⠀⠀⠀⠀⠀⠀⢸⠁⠀⠀⠀⢸⠀⠀⠀⠀⡏⠀⠀⠀⠀⠀⠀⠀
+⠀⠳⡀⠀⠀⠀⠀⠀⠀⣀⢾⠀⠀⠀⠀⣾⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀
+⠀⠀⠈⠐⠢⠤⠤⠔⠚⠁⠘⣆⠀⠀⢠⠋⢧⣀⣀⡼⠀⠀⠀⠀⠀⠀⠀⠀
+⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠈⠉⠈⠁⠀⠀⠀⠁⠀⠀⠀⠀"
+    );
+}
+
+fn show_banner(opts: &Options) {
+    if !opts.quiet && io::stdout().is_terminal() {
+        print_banner();
+    }
+}
+
+fn read(path: &str) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut bytes: Vec<u8> = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+    fs::read(path)
+}
+
+/// Assembles a source file or decodes an object file, reporting errors
+/// on stderr.
+fn load(path: &str) -> Option<Program> {
+    let bytes = match read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return None;
+        }
+    };
+    if object::is_object(&bytes) {
+        return object::decode(&bytes)
+            .map_err(|e| eprintln!("{}: {}", path, e))
+            .ok();
+    }
+    let source = String::from_utf8_lossy(&bytes).into_owned();
+    match Interpreter::from_source(source) {
+        Ok(program) => Some(program),
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{}:{}", path, e);
+            }
+            None
+        }
+    }
+}
+
+/// Loads a program, running it through the optimiser under -O.
+fn build(path: &str, opts: &Options) -> Option<Program> {
+    let program = load(path)?;
+    if !opts.optimize {
+        return Some(program);
+    }
+    let (program, report) = optimizer::optimize(program);
+    if opts.report {
+        for change in &report.changes {
+            eprintln!("{}:{}", path, change);
+        }
+        eprintln!(
+            "{}: {} instructions, {} before optimising",
+            path, report.after, report.before
+        );
+    }
+    Some(program)
+}
+
+/// Reports a mistake in the command line, returning the exit code.
+fn usage_error(message: &str) -> i32 {
+    eprintln!("vcpu: {}\nrun 'vcpu help' for usage", message);
+    USAGE_EXIT
+}
+
+/// Reports a runtime error as `path:line: message`, returning the exit code.
+fn runtime_error(path: &str, vm: &GeneralStructure, error: &VmError) -> i32 {
+    let line = vm.flow().get(error.pc).map_or(0, |istr| istr.line);
+    eprintln!("{}:{}: {}", path, line, error);
+    RUNTIME_ERROR_EXIT
+}
+
+/// A VM for `code` and `data` set up as the options ask, `args` being its
+/// argv. Failures are reported and give the exit code.
+fn prepare(
+    code: Vec<FlowStructure>,
+    data: &[u8],
+    opts: &Options,
+    args: &[String],
+) -> Result<GeneralStructure, i32> {
+    let mut vm = GeneralStructure::init(code, data);
+    vm.env_mut().set_args(args).map_err(|e| usage_error(&e))?;
+
+    // Files are kept in memory unless --allow-fs opens the host's, all
+    // of them or those under --root.
+    let fs: Box<dyn FileSystem> = match (opts.allow_fs, &opts.root) {
+        (false, Some(_)) => return Err(usage_error("--root needs --allow-fs")),
+        (false, None) => Box::new(MemoryFs::init()),
+        (true, None) => Box::new(HostFs::init()),
+        (true, Some(root)) => match HostFs::restricted(Path::new(root)) {
+            Ok(fs) => Box::new(fs),
+            Err(e) => {
+                eprintln!("{}: {}", root, e);
+                return Err(1);
+            }
+        },
+    };
+    if opts.sandbox {
+        vm.env_mut().set_host(Box::new(SandboxHost::init(fs)));
+    } else {
+        vm.env_mut().set_host(Box::new(SystemHost::init(fs)));
+    }
+
+    if let Some(path) = &opts.stdin {
+        match File::open(path) {
+            Ok(file) => vm.env_mut().set_input(Box::new(io::BufReader::new(file))),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return Err(1);
+            }
+        }
+    }
+    Ok(vm)
+}
+
+/// Runs to the end or until `max_steps` instructions have run, returning
+/// the exit code. Each instruction goes through `profiler` and `coverage`
+/// when given.
+fn execute(
+    vm: &mut GeneralStructure,
+    opts: &Options,
+    max_steps: Option<u64>,
+    mut profiler: Option<&mut Profiler>,
+    mut coverage: Option<&mut Coverage>,
+) -> Result<i32, VmError> {
+    let mut steps: u64 = 0;
+    while !vm.finished() {
+        if max_steps.is_some_and(|max| steps >= max) {
+            vm.env_mut().flush();
+            return Ok(STEP_LIMIT_EXIT);
+        }
+        if !opts.trace && profiler.is_none() && coverage.is_none() {
+            steps += vm.run_for(max_steps.map_or(u64::MAX, |max| max - steps))?;
+            continue;
+        }
+        if opts.trace {
+            // Keep the trace in order with what the program prints.
+            vm.env_mut().flush();
+            let istr = &vm.flow()[vm.pc()];
+            eprintln!("#{} line {}: {}", vm.pc(), istr.line, istr);
+        }
+        let pc = vm.pc();
+        match profiler.as_deref_mut() {
+            Some(profiler) => profiler.step(vm)?,
+            None => {
+                vm.step()?;
+            }
+        }
+        if let Some(coverage) = coverage.as_deref_mut() {
+            coverage.record(pc, vm);
+        }
+        steps += 1;
+    }
+    Ok(vm.env().exit_code())
+}
+
+fn run(opts: &Options) -> i32 {
+    let program = match build(&opts.files[0], opts) {
+        Some(program) => program,
+        None => return 1,
+    };
+    if opts.typecheck && !typecheck(&opts.files[0], &program) {
+        return 1;
+    }
+    let mut profiler = (opts.profile || opts.folded.is_some()).then(|| Profiler::init(&program));
+    let mut coverage = opts
+        .coverage
+        .is_some()
+        .then(|| Coverage::init(&opts.files[0], &program));
+    let mut vm = match prepare(program.code, &program.data, opts, &opts.files) {
+        Ok(vm) => vm,
+        Err(code) => return code,
+    };
+    show_banner(opts);
+    let code = match execute(
+        &mut vm,
+        opts,
+        opts.max_steps,
+        profiler.as_mut(),
+        coverage.as_mut(),
+    ) {
+        Ok(code) => code,
+        Err(error) => runtime_error(&opts.files[0], &vm, &error),
+    };
+    if let Some(max) = opts
+        .max_steps
+        .filter(|_| code == STEP_LIMIT_EXIT && !vm.finished())
+    {
+        eprintln!("vcpu: stopped after {} steps", max);
+    }
+    if let Some(mut profiler) = profiler {
+        profiler.finish();
+        if opts.profile {
+            eprint!("\n{}", profiler.report());
+        }
+        if let Some(path) = &opts.folded {
+            if let Err(e) = fs::write(path, profiler.folded()) {
+                eprintln!("{}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    if let Some(coverage) = coverage {
+        if !report_coverage(&[coverage], opts) && code == 0 {
+            return 1;
+        }
+    }
+    code
+}
+
+/// Writes the lcov file and prints a summary, returns false when that
+/// fails or coverage is under --fail-under.
+fn report_coverage(coverage: &[Coverage], opts: &Options) -> bool {
+    let mut total = Summary::default();
+    let mut lcov = String::new();
+    for file in coverage {
+        let summary = file.summary();
+        let missed = file.missed();
+        if missed.is_empty() {
+            eprintln!("{}: {}", file.path(), summary);
+        } else {
+            eprintln!("{}: {}, not run: {}", file.path(), summary, missed);
+        }
+        total.add(summary);
+        lcov.push_str(&file.lcov());
+    }
+    if coverage.len() > 1 {
+        eprintln!("total: {}", total);
+    }
+    if let Some(path) = &opts.coverage {
+        let written = if path == "-" {
+            io::stdout().write_all(lcov.as_bytes())
+        } else {
+            fs::write(path, lcov)
+        };
+        if let Err(e) = written {
+            eprintln!("{}: {}", path, e);
+            return false;
+        }
+    }
+    match opts.fail_under {
+        Some(min) if total.lines.percent() < min => {
+            eprintln!(
+                "vcpu: line coverage {:.1}% is under {}%",
+                total.lines.percent(),
+                min
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+fn debug(opts: &Options) -> i32 {
+    let program = match load(&opts.files[0]) {
+        Some(program) => program,
+        None => return 1,
+    };
+    let vm = match prepare(program.code, &program.data, opts, &opts.files) {
+        Ok(vm) => vm,
+        Err(code) => return code,
+    };
+    match &opts.gdb {
+        Some(port) => {
+            if port != "-" {
+                show_banner(opts);
+            }
+            if let Err(e) = gdb::listen(vm, port) {
+                eprintln!("vcpu: {}", e);
+                return 1;
+            }
+        }
+        None => {
+            show_banner(opts);
+            Debugger::init(vm, program.labels).repl();
+        }
+    }
+    0
+}
+
+fn asm(opts: &Options) -> i32 {
+    let path = &opts.files[0];
+    let program = match build(path, opts) {
+        Some(program) => program,
+        None => return 1,
+    };
+    let bytes = object::encode(&program);
+    let output = match &opts.output {
+        Some(output) => output.clone(),
+        None if path == "-" => String::from("-"),
+        None => Path::new(path)
+            .with_extension("vco")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let written = if output == "-" {
+        io::stdout().write_all(&bytes)
+    } else {
+        fs::write(&output, bytes)
+    };
+    match written {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", output, e);
+            1
+        }
+    }
+}
+
+fn cfg(opts: &Options) -> i32 {
+    let program = match build(&opts.files[0], opts) {
+        Some(program) => program,
+        None => return 1,
+    };
+    let dot = Cfg::build(&program).to_dot(&program);
+    let written = match opts.output.as_deref() {
+        Some(output) if output != "-" => fs::write(output, dot),
+        _ => io::stdout().write_all(dot.as_bytes()),
+    };
+    match written {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", opts.output.as_deref().unwrap_or("-"), e);
+            1
+        }
+    }
+}
+
+/// Prints a program as assembly that assembles back to the same code,
+/// with instruction indices and source lines as comments.
+fn disasm(opts: &Options) -> i32 {
+    let program = match build(&opts.files[0], opts) {
+        Some(program) => program,
+        None => return 1,
+    };
+    let mut labels: Vec<(&String, &usize)> = program.labels.iter().collect();
+    labels.sort_by_key(|(name, pos)| (**pos, *name));
+    let names: HashMap<usize, &String> = labels.iter().map(|(name, pos)| (**pos, *name)).collect();
+
+    let mut out = String::new();
+    let mut next = labels.iter().peekable();
+    for (pc, istr) in program.code.iter().enumerate() {
+        while let Some((name, _)) = next.next_if(|(_, pos)| **pos == pc) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        let mut text = istr.to_string();
+        // Jumps name their target when a label marks it.
+        let target = match (istr.op_code, istr.arguments.first()) {
+            (
+                OpCode::JMP | OpCode::JE | OpCode::JNE | OpCode::CALL,
+                Some(GeneralData::Int64(pos)),
+            ) => names.get(&(*pos as usize)),
+            _ => None,
+        };
+        if let Some(name) = target {
+            text = format!("{:?} {}", istr.op_code, name);
+        }
+        out.push_str(&format!("    {:<32} ; #{} line {}\n", text, pc, istr.line));
+    }
+    for (name, _) in next {
+        out.push_str(&format!("{}:\n", name));
+    }
+
+    if !program.data.is_empty() {
+        out.push('\n');
+        for (i, chunk) in program.data.chunks(16).enumerate() {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            out.push_str(&format!(
+                "; {:04x}: {}\n",
+                DATA_BASE + i * 16,
+                bytes.join(" ")
+            ));
+        }
+    }
+    // A closed pipe, as with `| head`, is not an error.
+    let _ = io::stdout().write_all(out.as_bytes());
+    0
+}
+
+/// Reports type errors in `program`, returns false if there were any.
+fn typecheck(path: &str, program: &Program) -> bool {
+    let errors = typecheck::check(program);
+    for e in &errors {
+        eprintln!("{}:{}", path, e);
+    }
+    errors.is_empty()
+}
+
+fn check(opts: &Options) -> i32 {
+    let failed = opts
+        .files
+        .iter()
+        .filter(|path| match load(path) {
+            Some(program) => {
+                for warning in Cfg::build(&program).lint(&program) {
+                    eprintln!("{}:{}", path, warning);
+                }
+                !typecheck(path, &program)
+            }
+            None => true,
+        })
+        .count();
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Runs one test from a fresh VM with its output discarded, from `entry`
+/// as though CALLed or else from the start. It passes when it returns or
+/// exits with 0 within `budget` instructions.
+fn run_test(
+    program: &Program,
+    path: &str,
+    entry: Option<usize>,
+    budget: u64,
+    opts: &Options,
+    coverage: Option<&mut Coverage>,
+) -> Result<(), String> {
+    let args = [path.to_string()];
+    let mut vm = prepare(program.code.clone(), &program.data, opts, &args)
+        .map_err(|_| String::from("could not be set up"))?;
+    vm.env_mut().set_output(Box::new(io::sink()));
+    if opts.stdin.is_none() {
+        vm.env_mut().set_input(Box::new(io::empty()));
+    }
+    if let Some(pc) = entry {
+        vm.call(pc);
+    }
+    // After an exit `pc` has moved past the instruction that stopped the
+    // program, a runtime error leaves it on the failing one.
+    let (pc, message) = match execute(&mut vm, opts, Some(budget), None, coverage) {
+        Ok(0) => return Ok(()),
+        Ok(_) if !vm.finished() => (
+            vm.pc(),
+            format!("ran out of its budget of {} instructions", budget),
+        ),
+        Ok(code) => (vm.pc().saturating_sub(1), format!("exit code {}", code)),
+        Err(error) => (error.pc, error.message),
+    };
+    let line = program
+        .code
+        .get(pc.min(program.code.len().saturating_sub(1)))
+        .map_or(0, |istr| istr.line);
+    Err(format!("{}:{}: {}", path, line, message))
+}
+
+/// Runs the `test_` labels of every program, or programs without any as
+/// a whole, reporting each and writing --junit.
+fn test(opts: &Options) -> i32 {
+    let budget = opts.max_steps.unwrap_or(TEST_BUDGET);
+    let mut cases: Vec<TestCase> = Vec::new();
+    let mut coverage: Vec<Coverage> = Vec::new();
+    for path in &opts.files {
+        let program = match load(path) {
+            Some(program) => program,
+            None => {
+                cases.push(TestCase {
+                    file: path.clone(),
+                    name: path.clone(),
+                    failure: Some(format!("{}: does not assemble", path)),
+                    time: Duration::ZERO,
+                });
+                continue;
+            }
+        };
+        if opts.coverage.is_some() {
+            coverage.push(Coverage::init(path, &program));
+        }
+        let tests = testing::discover(&program);
+        let entries: Vec<(String, Option<usize>)> = if tests.is_empty() {
+            vec![(path.clone(), None)]
+        } else {
+            tests
+                .into_iter()
+                .map(|(name, pc)| (name, Some(pc)))
+                .collect()
+        };
+        for (name, entry) in entries {
+            let start = Instant::now();
+            let outcome = run_test(&program, path, entry, budget, opts, coverage.last_mut());
+            let shown = match entry {
+                Some(_) => format!("{}::{}", path, name),
+                None => name.clone(),
+            };
+            match &outcome {
+                Ok(()) => println!("test {} ... ok", shown),
+                Err(reason) => println!("test {} ... FAILED ({})", shown, reason),
+            }
+            cases.push(TestCase {
+                file: path.clone(),
+                name,
+                failure: outcome.err(),
+                time: start.elapsed(),
+            });
+        }
+    }
+    let failed = cases.iter().filter(|case| case.failure.is_some()).count();
+    let passed = cases.len() - failed;
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        result, passed, failed
+    );
+    if let Some(path) = &opts.junit {
+        if let Err(e) = fs::write(path, testing::junit(&cases)) {
+            eprintln!("{}: {}", path, e);
+            return 1;
+        }
+    }
+    if opts.coverage.is_some() && !report_coverage(&coverage, opts) {
+        return 1;
+    }
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// A label on its own, which starts a block run at the next blank line.
+fn is_label_line(line: &str) -> bool {
+    let line = line.trim();
+    !line.contains(char::is_whitespace) && (line.starts_with(':') || line.ends_with(':'))
+}
+
+fn show_registers(vm: &GeneralStructure) {
+    let env = vm.env();
+    for i in 0..(Register::NIL as usize) {
+        let reg: Register = num_traits::FromPrimitive::from_usize(i).unwrap();
+        let data = env.register(reg);
+        println!("{:?}\t{:?}\t{}", reg, data.data_type(), data);
+    }
+    println!("ZF\t{}", env.flags().zf);
+}
+
+/// Runs each statement as it is entered, on one machine that keeps its
+/// registers and memory. Blocks started by a label run at a blank line,
+/// and HLT or EXIT leave with their status.
+fn repl(opts: &Options) -> i32 {
+    let mut vm = match prepare(Vec::new(), &[], opts, &[String::from("repl")]) {
+        Ok(vm) => vm,
+        Err(code) => return code,
+    };
+    // Program input would fight with the prompt over stdin.
+    if opts.stdin.is_none() {
+        vm.env_mut().set_input(Box::new(io::empty()));
+    }
+
+    let mut buffer = String::new();
+    let mut block = false;
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        io::stdout().flush().expect("Failed to flush to stdout");
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(n) if n > 0 => {}
+            _ => break,
+        }
+        let line = line.trim_end();
+
+        match line.trim() {
+            ":quit" | ":q" => break,
+            ":regs" | ":r" => {
+                show_registers(&vm);
+                continue;
+            }
+            "" if buffer.is_empty() => continue,
+            "" => {}
+            _ => {
+                buffer.push_str(line);
+                buffer.push('\n');
+                block |= is_label_line(line);
+                if block || line.ends_with('\\') {
+                    continue;
+                }
+            }
+        }
+
+        match Interpreter::from_source(std::mem::take(&mut buffer)) {
+            Ok(program) => {
+                vm.load(program.code, &program.data);
+                if let Err(error) = vm.run() {
+                    let line = vm.flow()[error.pc].line;
+                    eprintln!("{}: {}", line, error);
+                }
+                vm.env_mut().flush();
+                if vm.env().halted() {
+                    return vm.env().exit_code();
+                }
+            }
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        block = false;
+    }
+    0
+}
+
+/// Runs the command line, returning the process exit code.
+pub fn main(args: &[String]) -> i32 {
+    let (command, rest) = match args.first().map(String::as_str) {
+        Some(
+            c @ ("run" | "asm" | "disasm" | "check" | "cfg" | "debug" | "repl" | "fmt" | "test"
+            | "lsp" | "dap" | "help"),
+        ) => (c, &args[1..]),
+        Some("--lsp") => ("lsp", &args[1..]),
+        Some("--dap") => ("dap", &args[1..]),
+        // The flags that came before subcommands.
+        Some("--debug") => ("debug", &args[1..]),
+        Some("--gdb") => ("debug", args),
+        Some("-h" | "--help") => ("help", &args[1..]),
+        Some(_) => ("run", args),
+        None => {
+            eprintln!("{}", USAGE);
+            return USAGE_EXIT;
+        }
+    };
+
+    match command {
+        "help" => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        "fmt" => return formatter::run(rest),
+        "lsp" => {
+            let reader = Box::new(io::BufReader::new(io::stdin()));
+            LspServer::init(reader, Box::new(io::stdout())).serve();
+            return 0;
+        }
+        "dap" => {
+            let reader = Box::new(io::BufReader::new(io::stdin()));
+            DapServer::init(reader, Box::new(io::stdout())).serve();
+            return 0;
+        }
+        _ => {}
+    }
+
+    let opts = match Options::parse(rest, matches!(command, "run" | "debug")) {
+        // Only the REPL goes without a file.
+        Ok(opts) if opts.files.is_empty() && command != "repl" => {
+            return usage_error("no program file given");
+        }
+        Ok(opts) => opts,
+        Err(e) => return usage_error(&e),
+    };
+    match command {
+        "run" => run(&opts),
+        "asm" => asm(&opts),
+        "disasm" => disasm(&opts),
+        "check" => check(&opts),
+        "cfg" => cfg(&opts),
+        "debug" => debug(&opts),
+        "repl" => repl(&opts),
+        "test" => test(&opts),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prepare, Options, USAGE_EXIT};
+
+    fn options(args: &[&str]) -> Options {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::parse(&args, true).unwrap()
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let args: Vec<String> = ["-q", "-O", "--max-steps", "10", "a.asm", "--", "-b.asm"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let opts = Options::parse(&args, false).unwrap();
+        assert!(opts.quiet && opts.optimize);
+        assert_eq!(opts.max_steps, Some(10));
+        assert_eq!(opts.files, ["a.asm", "-b.asm"]);
+
+        let error = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            Options::parse(&args, false).err().unwrap()
+        };
+        assert_eq!(error(&["--bogus"]), "unknown option '--bogus'");
+        assert_eq!(error(&["-o"]), "-o needs a value");
+        assert_eq!(error(&["--max-steps", "x"]), "bad step count 'x'");
+        assert_eq!(error(&["--fail-under", "%"]), "bad percentage '%'");
+    }
+
+    #[test]
+    fn host_files_need_allow_fs() {
+        let opts = options(&["--allow-fs", "--root", ".", "prog.asm", "--root"]);
+        assert!(opts.allow_fs);
+        assert_eq!(opts.root.as_deref(), Some("."));
+        assert_eq!(opts.files, ["prog.asm", "--root"]);
+        assert!(prepare(Vec::new(), &[], &opts, &[]).is_ok());
+
+        let opts = options(&["--root", ".", "prog.asm"]);
+        assert!(!opts.allow_fs);
+        assert_eq!(prepare(Vec::new(), &[], &opts, &[]).err(), Some(USAGE_EXIT));
+    }
+
+    #[test]
+    fn oversized_arguments_are_a_usage_error() {
+        let opts = options(&["prog.asm"]);
+        let args = [String::from("prog.asm"), "x".repeat(1 << 16)];
+        assert_eq!(
+            prepare(Vec::new(), &[], &opts, &args).err(),
+            Some(USAGE_EXIT)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::structures::data_types::GeneralData;
+use crate::structures::flow_structure::OpCode;
+use crate::structures::interpreter::Program;
+use crate::structures::structures::GeneralStructure;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Covered out of total, for one kind of item.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Count {
+    pub hit: usize,
+    pub total: usize,
+}
+
+impl Count {
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            100.0 * self.hit as f64 / self.total as f64
+        }
+    }
+
+    fn add(&mut self, other: Count) {
+        self.hit += other.hit;
+        self.total += other.total;
+    }
+}
+
+impl Display for Count {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} ({:.1}%)", self.hit, self.total, self.percent())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub instructions: Count,
+    pub lines: Count,
+    /// Each direction of each conditional jump.
+    pub branches: Count,
+}
+
+impl Summary {
+    pub fn add(&mut self, other: Summary) {
+        self.instructions.add(other.instructions);
+        self.lines.add(other.lines);
+        self.branches.add(other.branches);
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "lines {}, instructions {}, branches {}",
+            self.lines, self.instructions, self.branches
+        )
+    }
+}
+
+/// Which instructions of one program ran, and which way its JE and JNE
+/// went.
+pub struct Coverage {
+    path: String,
+    lines: Vec<usize>,
+    ops: Vec<OpCode>,
+    /// Labels that are CALLed, reported as functions, with their index.
+    functions: Vec<(String, usize)>,
+    hits: Vec<u64>,
+    taken: Vec<u64>,
+    not_taken: Vec<u64>,
+}
+
+impl Coverage {
+    /// `path` names the source in the lcov records.
+    pub fn init(path: &str, program: &Program) -> Self {
+        let len = program.code.len();
+        let called: Vec<i64> = program
+            .code
+            .iter()
+            .filter(|istr| istr.op_code == OpCode::CALL)
+            .filter_map(|istr| match istr.arguments.first() {
+                Some(GeneralData::Int64(target)) => Some(*target),
+                _ => None,
+            })
+            .collect();
+        let mut functions: Vec<(String, usize)> = program
+            .labels
+            .iter()
+            .filter(|(_, pos)| **pos < len && called.contains(&(**pos as i64)))
+            .map(|(name, pos)| (name.clone(), *pos))
+            .collect();
+        functions.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        Coverage {
+            path: path.to_string(),
+            lines: program.code.iter().map(|istr| istr.line).collect(),
+            ops: program.code.iter().map(|istr| istr.op_code).collect(),
+            functions,
+            hits: vec![0; len],
+            taken: vec![0; len],
+            not_taken: vec![0; len],
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Records the instruction at `pc`, which `vm` has just executed.
+    pub fn record(&mut self, pc: usize, vm: &GeneralStructure) {
+        self.hits[pc] += 1;
+        let zf = vm.env().flags().zf;
+        let taken = match self.ops[pc] {
+            OpCode::JE => zf,
+            OpCode::JNE => !zf,
+            _ => return,
+        };
+        if taken {
+            self.taken[pc] += 1;
+        } else {
+            self.not_taken[pc] += 1;
+        }
+    }
+
+    fn is_branch(&self, pc: usize) -> bool {
+        matches!(self.ops[pc], OpCode::JE | OpCode::JNE)
+    }
+
+    /// Times each source line ran, the most any of its instructions did.
+    fn line_hits(&self) -> BTreeMap<usize, u64> {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        for (line, hits) in self.lines.iter().zip(&self.hits) {
+            let entry = lines.entry(*line).or_default();
+            *entry = (*entry).max(*hits);
+        }
+        lines
+    }
+
+    pub fn summary(&self) -> Summary {
+        let lines = self.line_hits();
+        let branches: Vec<usize> = (0..self.ops.len())
+            .filter(|pc| self.is_branch(*pc))
+            .collect();
+        Summary {
+            instructions: Count {
+                hit: self.hits.iter().filter(|hits| **hits > 0).count(),
+                total: self.hits.len(),
+            },
+            lines: Count {
+                hit: lines.values().filter(|hits| **hits > 0).count(),
+                total: lines.len(),
+            },
+            branches: Count {
+                hit: branches
+                    .iter()
+                    .map(|pc| (self.taken[*pc] > 0) as usize + (self.not_taken[*pc] > 0) as usize)
+                    .sum(),
+                total: branches.len() * 2,
+            },
+        }
+    }
+
+    /// Source lines that never ran, with runs of them as `a-b`.
+    pub fn missed(&self) -> String {
+        let missed: Vec<usize> = self
+            .line_hits()
+            .into_iter()
+            .filter(|(_, hits)| *hits == 0)
+            .map(|(line, _)| line)
+            .collect();
+        let mut ranges: Vec<String> = Vec::new();
+        let mut i = 0;
+        while i < missed.len() {
+            let start = missed[i];
+            while i + 1 < missed.len() && missed[i + 1] == missed[i] + 1 {
+                i += 1;
+            }
+            ranges.push(if missed[i] == start {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, missed[i])
+            });
+            i += 1;
+        }
+        ranges.join(", ")
+    }
+
+    /// One lcov tracefile record, as genhtml and coverage services read.
+    pub fn lcov(&self) -> String {
+        let summary = self.summary();
+        let mut out = format!("TN:\nSF:{}\n", self.path);
+        for (name, pos) in &self.functions {
+            out.push_str(&format!("FN:{},{}\n", self.lines[*pos], name));
+        }
+        for (name, pos) in &self.functions {
+            out.push_str(&format!("FNDA:{},{}\n", self.hits[*pos], name));
+        }
+        out.push_str(&format!("FNF:{}\n", self.functions.len()));
+        let called = self
+            .functions
+            .iter()
+            .filter(|(_, pos)| self.hits[*pos] > 0)
+            .count();
+        out.push_str(&format!("FNH:{}\n", called));
+
+        // Branch 0 is the jump taken, branch 1 falling through.
+        for pc in (0..self.ops.len()).filter(|pc| self.is_branch(*pc)) {
+            for (branch, count) in [self.taken[pc], self.not_taken[pc]].iter().enumerate() {
+                let count = if self.hits[pc] == 0 {
+                    String::from("-")
+                } else {
+                    count.to_string()
+                };
+                out.push_str(&format!(
+                    "BRDA:{},{},{},{}\n",
+                    self.lines[pc], pc, branch, count
+                ));
+            }
+        }
+        out.push_str(&format!(
+            "BRF:{}\nBRH:{}\n",
+            summary.branches.total, summary.branches.hit
+        ));
+
+        for (line, hits) in self.line_hits() {
+            out.push_str(&format!("DA:{},{}\n", line, hits));
+        }
+        out.push_str(&format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            summary.lines.total, summary.lines.hit
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Count, Coverage};
+    use crate::structures::structures::GeneralStructure;
+    use crate::test_support::assemble;
+
+    fn cover(source: &str) -> Coverage {
+        let program = assemble(source);
+        let mut coverage = Coverage::init("prog.asm", &program);
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        while !vm.finished() {
+            let pc = vm.pc();
+            vm.step().unwrap();
+            coverage.record(pc, &vm);
+        }
+        coverage
+    }
+
+    const PROGRAM: &str = "mov rax, 0\ncall f\ncmp rax, 1\nje done\nnop\ndone: hlt\n\
+                           f: add rax, 1\nret\ng: nop\ncall f\nret\n";
+
+    #[test]
+    fn summary_and_missed_lines() {
+        let coverage = cover(PROGRAM);
+        let summary = coverage.summary();
+        assert_eq!(summary.lines, Count { hit: 7, total: 11 });
+        assert_eq!(summary.branches, Count { hit: 1, total: 2 });
+        assert_eq!(summary.instructions.to_string(), "7/11 (63.6%)");
+        assert_eq!(coverage.missed(), "5, 9-11");
+        assert_eq!(Count::default().percent(), 100.0);
+    }
+
+    #[test]
+    fn lcov_record() {
+        assert_eq!(
+            cover(PROGRAM).lcov(),
+            "TN:\nSF:prog.asm\nFN:7,f\nFNDA:1,f\nFNF:1\nFNH:1\n\
+             BRDA:4,3,0,1\nBRDA:4,3,1,0\nBRF:2\nBRH:1\n\
+             DA:1,1\nDA:2,1\nDA:3,1\nDA:4,1\nDA:5,0\nDA:6,1\nDA:7,1\nDA:8,1\n\
+             DA:9,0\nDA:10,0\nDA:11,0\nLF:11\nLH:7\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use crate::protocol::{read_message, write_message, ReadError, SharedBuffer};
+use crate::structures::env_vars::VmError;
+use crate::structures::flow_structure::OpCode;
+use crate::structures::interpreter::Interpreter;
+use crate::structures::registers::Register;
+use crate::structures::structures::GeneralStructure;
+use num_traits::FromPrimitive;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::{fs, io};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const FLAGS_REF: i64 = 2;
+
+/// Why execution stopped, as reported to the client.
+enum Stop {
+    Reason(&'static str),
+    /// The program raised a runtime error, with its message.
+    Exception(String),
+}
+
+struct Session {
+    vm: GeneralStructure,
+    path: String,
+    labels: HashMap<String, usize>,
+    breakpoints: BTreeSet<usize>,
+    stop_on_entry: bool,
+}
+
+/// Debug Adapter Protocol server speaking over stdin/stdout.
+pub struct DapServer {
+    session: Option<Session>,
+    /// Collects what the program prints so it can be forwarded as `output` events.
+    output: SharedBuffer,
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+    seq: i64,
+}
+
+/// How far the call depth moves when `op` executes.
+fn depth_change(op: OpCode) -> i64 {
+    match op {
+        OpCode::CALL => 1,
+        OpCode::RET => -1,
+        _ => 0,
+    }
+}
+
+impl Session {
+    fn current_op(&self) -> Option<OpCode> {
+        if self.vm.finished() {
+            None
+        } else {
+            Some(self.vm.flow()[self.vm.pc()].op_code)
+        }
+    }
+
+    /// Steps until the call depth drops below `target` or a breakpoint is
+    /// reached, `target` of `None` only stops on breakpoints.
+    fn run(&mut self, target: Option<i64>) -> Result<&'static str, VmError> {
+        let mut depth: i64 = 0;
+        while let Some(op) = self.current_op() {
+            depth += depth_change(op);
+            self.vm.step()?;
+            if target.is_some_and(|t| depth < t) {
+                return Ok("step");
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return Ok("breakpoint");
+            }
+        }
+        Ok("exit")
+    }
+
+    fn step_over(&mut self) -> Result<&'static str, VmError> {
+        match self.current_op() {
+            Some(OpCode::CALL) => self.run(Some(1)),
+            Some(_) => self.vm.step().map(|_| "step"),
+            None => Ok("exit"),
+        }
+    }
+
+    fn reverse_continue(&mut self) -> &'static str {
+        while self.vm.reverse_step() {
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return "breakpoint";
+            }
+        }
+        "entry"
+    }
+
+    fn line(&self) -> usize {
+        let flow = self.vm.flow();
+        match flow.get(self.vm.pc()).or(flow.last()) {
+            Some(istr) => istr.line,
+            None => 0,
+        }
+    }
+
+    /// Name of the innermost label at or before `pc`.
+    fn frame_name(&self) -> String {
+        let pc = self.vm.pc();
+        self.labels
+            .iter()
+            .filter(|(_, pos)| **pos <= pc)
+            .max_by_key(|(_, pos)| **pos)
+            .map_or(String::from("main"), |(name, _)| name.clone())
+    }
+
+    fn set_breakpoints(&mut self, lines: &[i64]) -> Vec<Value> {
+        self.breakpoints.clear();
+        let flow = self.vm.flow();
+
+        lines
+            .iter()
+            .map(
+                |line| match flow.iter().position(|istr| istr.line as i64 >= *line) {
+                    Some(pos) => {
+                        self.breakpoints.insert(pos);
+                        json!({"verified": true, "line": flow[pos].line})
+                    }
+                    None => json!({"verified": false, "line": line}),
+                },
+            )
+            .collect()
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let env = self.vm.env();
+        match reference {
+            REGISTERS_REF => (0..(Register::NIL as usize))
+                .map(|i| {
+                    let reg: Register = FromPrimitive::from_usize(i).unwrap();
+                    let data = env.register(reg);
+                    json!({
+                        "name": format!("{:?}", reg),
+                        "value": format!("{}", data),
+                        "type": format!("{:?}", data.data_type()),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            FLAGS_REF => vec![json!({
+                "name": "ZF",
+                "value": format!("{}", env.flags().zf),
+                "variablesReference": 0,
+            })],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl DapServer {
+    pub fn init(reader: Box<dyn BufRead>, writer: Box<dyn Write>) -> Self {
+        DapServer {
+            session: None,
+            output: SharedBuffer::default(),
+            reader,
+            writer,
+            seq: 0,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn flush_output(&mut self) {
+        let text = self.output.text();
+        self.output.0.borrow_mut().clear();
+        if !text.is_empty() {
+            self.event("output", json!({"category": "stdout", "output": text}));
+        }
+    }
+
+    /// Reports where execution ended up after a resume request.
+    fn stopped(&mut self, stop: Stop) {
+        self.flush_output();
+        match stop {
+            Stop::Reason("exit") => {
+                self.event("terminated", json!({}));
+                let code = self.session.as_ref().map_or(0, |s| s.vm.env().exit_code());
+                self.event("exited", json!({"exitCode": code}));
+            }
+            Stop::Reason(reason) => self.event(
+                "stopped",
+                json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}),
+            ),
+            Stop::Exception(message) => self.event(
+                "stopped",
+                json!({
+                    "reason": "exception",
+                    "description": "the program raised a runtime error",
+                    "text": message,
+                    "threadId": THREAD_ID,
+                    "allThreadsStopped": true,
+                }),
+            ),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("launch needs a 'program' path")?;
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let program = Interpreter::from_source(source).map_err(|errors| {
+            let messages: Vec<String> = errors.iter().map(|e| format!("{}:{}", path, e)).collect();
+            messages.join("\n")
+        })?;
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        let mut argv = vec![String::from(path)];
+        if let Some(args) = args["args"].as_array() {
+            argv.extend(args.iter().filter_map(|a| a.as_str()).map(String::from));
+        }
+        vm.env_mut().set_args(&argv)?;
+        vm.env_mut().set_recording(true);
+        vm.env_mut().set_output(Box::new(self.output.clone()));
+        vm.env_mut().set_input(Box::new(io::empty()));
+
+        self.session = Some(Session {
+            vm,
+            path: String::from(path),
+            labels: program.labels,
+            breakpoints: BTreeSet::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        Ok(json!({}))
+    }
+
+    /// Runs a request that needs a launched program, answering it before
+    /// any resulting `stopped` event.
+    fn with_session(
+        &mut self,
+        request: &Value,
+        f: impl FnOnce(&mut Session, &Value) -> (Value, Option<&'static str>),
+    ) {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return self.respond(request, Err(String::from("no program launched"))),
+        };
+        let (body, stop) = f(session, &request["arguments"]);
+        self.respond(request, Ok(body));
+        if let Some(reason) = stop {
+            self.stopped(Stop::Reason(reason));
+        }
+    }
+
+    /// Runs a request that executes the program. A runtime error stops
+    /// it with an exception at the failing instruction, and finishing the
+    /// program ends the session.
+    fn resume(
+        &mut self,
+        request: &Value,
+        body: Value,
+        f: impl FnOnce(&mut Session) -> Result<&'static str, VmError>,
+    ) {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return self.respond(request, Err(String::from("no program launched"))),
+        };
+
+        let stop = match f(session) {
+            Ok(_) if session.vm.finished() => Stop::Reason("exit"),
+            Ok(reason) => Stop::Reason(reason),
+            Err(error) => {
+                // Undoing what the failing instruction did before it failed.
+                session.vm.reverse_step();
+                Stop::Exception(error.message)
+            }
+        };
+        self.respond(request, Ok(body));
+        self.stopped(stop);
+    }
+
+    /// Handles one request, returns false once the client disconnects.
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or("");
+        match command {
+            "initialize" => self.respond(
+                request,
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsStepBack": true,
+                })),
+            ),
+            "launch" => {
+                let result = self.launch(&request["arguments"]);
+                let ok = result.is_ok();
+                self.respond(request, result);
+                if ok {
+                    self.event("initialized", json!({}));
+                }
+            }
+            "setBreakpoints" => self.with_session(request, |s, args| {
+                let lines: Vec<i64> = args["breakpoints"]
+                    .as_array()
+                    .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_i64()).collect())
+                    .unwrap_or_default();
+                (json!({"breakpoints": s.set_breakpoints(&lines)}), None)
+            }),
+            "configurationDone" => self.resume(request, json!({}), |s| {
+                if s.stop_on_entry {
+                    Ok("entry")
+                } else {
+                    s.run(None)
+                }
+            }),
+            "threads" => self.respond(
+                request,
+                Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            ),
+            "stackTrace" => self.with_session(request, |s, _| {
+                let frame = json!({
+                    "id": 1,
+                    "name": s.frame_name(),
+                    "source": {"path": s.path},
+                    "line": s.line(),
+                    "column": 1,
+                });
+                (json!({"stackFrames": [frame], "totalFrames": 1}), None)
+            }),
+            "scopes" => self.respond(
+                request,
+                Ok(json!({"scopes": [
+                    {"name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false},
+                    {"name": "Flags", "variablesReference": FLAGS_REF, "expensive": false},
+                ]})),
+            ),
+            "variables" => self.with_session(request, |s, args| {
+                let reference = args["variablesReference"].as_i64().unwrap_or(0);
+                (json!({"variables": s.variables(reference)}), None)
+            }),
+            "continue" => self.resume(request, json!({"allThreadsContinued": true}), |s| {
+                s.run(None)
+            }),
+            "next" => self.resume(request, json!({}), |s| s.step_over()),
+            "stepIn" => self.resume(request, json!({}), |s| s.vm.step().map(|_| "step")),
+            "stepOut" => self.resume(request, json!({}), |s| s.run(Some(0))),
+            "stepBack" => self.with_session(request, |s, _| {
+                let stop = if s.vm.reverse_step() { "step" } else { "entry" };
+                (json!({}), Some(stop))
+            }),
+            "reverseContinue" => {
+                self.with_session(request, |s, _| (json!({}), Some(s.reverse_continue())))
+            }
+            "disconnect" => {
+                self.respond(request, Ok(json!({})));
+                return false;
+            }
+            _ => self.respond(request, Err(format!("unsupported request '{}'", command))),
+        }
+        true
+    }
+
+    /// Answers requests until the client disconnects. A request that is
+    /// not JSON gets a failed response and the session goes on.
+    pub fn serve(&mut self) {
+        loop {
+            let request = match read_message(&mut self.reader) {
+                Ok(request) => request,
+                Err(ReadError::Parse(e)) => {
+                    self.respond(&Value::Null, Err(format!("malformed request: {}", e)));
+                    continue;
+                }
+                Err(ReadError::Closed) => break,
+            };
+            if !self.handle(&request) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `requests` against `source` and returns everything sent back.
+    fn exchange(name: &str, source: &str, stop_on_entry: bool, requests: &[Value]) -> Vec<Value> {
+        let path =
+            std::env::temp_dir().join(format!("vcpu-dap-{}-{}.asm", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let mut input: Vec<u8> = Vec::new();
+        let launch = json!({
+            "command": "launch",
+            "arguments": {"program": path, "stopOnEntry": stop_on_entry},
+        });
+        for (seq, request) in std::iter::once(&launch).chain(requests).enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            write_message(&mut input, &request);
+        }
+
+        let sent = SharedBuffer::default();
+        let mut server = DapServer::init(Box::new(io::Cursor::new(input)), Box::new(sent.clone()));
+        server.serve();
+        fs::remove_file(&path).unwrap();
+
+        let bytes = sent.0.borrow().clone();
+        let mut reader = io::Cursor::new(bytes);
+        std::iter::from_fn(|| read_message(&mut reader).ok()).collect()
+    }
+
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|m| m["type"] == "event")
+            .map(|m| match m["body"]["reason"].as_str() {
+                Some(reason) => format!("{}:{}", m["event"].as_str().unwrap(), reason),
+                None => m["event"].as_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn breakpoints_map_lines_to_instructions() {
+        let messages = exchange(
+            "breakpoints",
+            "mov rax, 1\n\nmov rbx, 2\nmov rcx, 3\n",
+            false,
+            &[
+                json!({"command": "setBreakpoints", "arguments": {"breakpoints": [{"line": 2}, {"line": 9}]}}),
+                json!({"command": "configurationDone"}),
+                json!({"command": "stackTrace"}),
+                json!({"command": "continue"}),
+            ],
+        );
+        let breakpoints = &messages
+            .iter()
+            .find(|m| m["command"] == "setBreakpoints")
+            .unwrap()["body"]["breakpoints"];
+        assert_eq!(breakpoints[0], json!({"verified": true, "line": 3}));
+        assert_eq!(breakpoints[1]["verified"], false);
+        let trace = messages
+            .iter()
+            .find(|m| m["command"] == "stackTrace")
+            .unwrap();
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 3);
+        assert_eq!(
+            events(&messages),
+            ["initialized", "stopped:breakpoint", "terminated", "exited"]
+        );
+    }
+
+    #[test]
+    fn stepping_past_the_end_terminates() {
+        let messages = exchange(
+            "steps",
+            "mov rax, 1\nmov rbx, 2\n",
+            true,
+            &[
+                json!({"command": "configurationDone"}),
+                json!({"command": "stepIn"}),
+                json!({"command": "next"}),
+            ],
+        );
+        assert_eq!(
+            events(&messages),
+            [
+                "initialized",
+                "stopped:entry",
+                "stopped:step",
+                "terminated",
+                "exited"
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_stop_with_an_exception() {
+        let messages = exchange(
+            "error",
+            "mov rax, 1\nmov rbx, \"x\"\ncmp rax, rbx\nmov rcx, 2\n",
+            true,
+            &[
+                json!({"command": "continue"}),
+                json!({"command": "stackTrace"}),
+                json!({"command": "variables", "arguments": {"variablesReference": REGISTERS_REF}}),
+            ],
+        );
+        assert_eq!(events(&messages), ["initialized", "stopped:exception"]);
+        let stopped = messages.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert!(stopped["body"]["text"].as_str().unwrap().contains("mixes"));
+        let trace = messages
+            .iter()
+            .find(|m| m["command"] == "stackTrace")
+            .unwrap();
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 3);
+        let variables = messages
+            .iter()
+            .find(|m| m["command"] == "variables")
+            .unwrap();
+        assert_eq!(variables["body"]["variables"][0]["value"], "1");
+    }
+
+    #[test]
+    fn requests_before_launch_fail() {
+        let mut input: Vec<u8> = Vec::new();
+        write_message(&mut input, &json!({"seq": 1, "command": "next"}));
+        let sent = SharedBuffer::default();
+        DapServer::init(Box::new(io::Cursor::new(input)), Box::new(sent.clone())).serve();
+        let bytes = sent.0.borrow().clone();
+        let reply = read_message(&mut io::Cursor::new(bytes)).unwrap();
+        assert_eq!(reply["success"], false);
+        assert_eq!(reply["message"], "no program launched");
+    }
+
+    #[test]
+    fn malformed_requests_fail_without_ending_the_session() {
+        let mut input: Vec<u8> = b"Content-Length: 3\r\n\r\n{]}".to_vec();
+        write_message(&mut input, &json!({"seq": 1, "command": "next"}));
+        let sent = SharedBuffer::default();
+        DapServer::init(Box::new(io::Cursor::new(input)), Box::new(sent.clone())).serve();
+        let bytes = sent.0.borrow().clone();
+        let mut reader = io::Cursor::new(bytes);
+        let replies: Vec<Value> = std::iter::from_fn(|| read_message(&mut reader).ok()).collect();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["success"], false);
+        assert!(replies[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("malformed request"));
+        assert_eq!(replies[1]["message"], "no program launched");
+    }
+}
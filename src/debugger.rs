@@ -0,0 +1,233 @@
+use crate::structures::registers::Register;
+use crate::structures::structures::GeneralStructure;
+use num_traits::FromPrimitive;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::Write;
+
+/// Interactive debugger with reverse execution, driven by commands on stdin.
+pub struct Debugger {
+    vm: GeneralStructure,
+    labels: HashMap<String, usize>,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn init(mut vm: GeneralStructure, labels: HashMap<String, usize>) -> Self {
+        vm.env_mut().set_recording(true);
+        Debugger {
+            vm,
+            labels,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    fn resolve(&self, target: &str) -> Option<usize> {
+        match self.labels.get(target.trim_start_matches(':')) {
+            Some(pos) => Some(*pos),
+            None => target.parse().ok(),
+        }
+    }
+
+    fn show_position(&self) {
+        if self.vm.finished() {
+            println!("program finished");
+        } else {
+            let pc = self.vm.pc();
+            println!("#{} {}", pc, self.vm.flow()[pc]);
+        }
+    }
+
+    fn show_registers(&self) {
+        let env = self.vm.env();
+        for i in 0..(Register::NIL as usize) {
+            let reg: Register = FromPrimitive::from_usize(i).unwrap();
+            let data = env.register(reg);
+            println!("{:?}\t{:?}\t{}", reg, data.data_type(), data);
+        }
+        println!("ZF\t{}", env.flags().zf);
+    }
+
+    fn show_last_write(&self, name: &str) {
+        let reg = match Register::from_string(name) {
+            Some(reg) => reg,
+            None => return println!("unknown register {}", name),
+        };
+        match self.vm.env().last_write(reg) {
+            Some((step, pc)) => println!(
+                "{:?} last written at step {} by #{} {}",
+                reg,
+                step + 1,
+                pc,
+                self.vm.flow()[pc as usize]
+            ),
+            None => println!("{:?} not written since start", reg),
+        }
+    }
+
+    /// Steps once, reporting a runtime error, and returns whether to go on.
+    fn step(&mut self) -> bool {
+        match self.vm.step() {
+            Ok(stepped) => stepped,
+            Err(error) => {
+                self.vm.env_mut().flush();
+                println!("error at #{}: {}", error.pc, error);
+                false
+            }
+        }
+    }
+
+    fn continue_forward(&mut self) {
+        while self.step() {
+            if self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+        }
+    }
+
+    fn continue_backward(&mut self) {
+        while self.vm.reverse_step() {
+            if self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+        }
+    }
+
+    /// Runs one command, returns false when the session should end.
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let arg = words.next();
+
+        match (cmd, arg) {
+            ("break" | "b", Some(target)) => match self.resolve(target) {
+                Some(pos) => {
+                    self.breakpoints.insert(pos);
+                    println!("breakpoint at #{}", pos);
+                }
+                None => println!("unknown location {}", target),
+            },
+            ("delete" | "d", Some(target)) => match self.resolve(target) {
+                Some(pos) => {
+                    self.breakpoints.remove(&pos);
+                }
+                None => println!("unknown location {}", target),
+            },
+            ("step" | "s", _) => {
+                self.step();
+                self.show_position();
+            }
+            ("continue" | "c", _) => {
+                self.continue_forward();
+                self.show_position();
+            }
+            ("reverse-step" | "rs", _) => {
+                if !self.vm.reverse_step() {
+                    println!("at start of recording");
+                }
+                self.show_position();
+            }
+            ("reverse-continue" | "rc", _) => {
+                self.continue_backward();
+                self.show_position();
+            }
+            ("regs" | "r", _) => self.show_registers(),
+            ("last-write" | "lw", Some(reg)) => self.show_last_write(reg),
+            ("quit" | "q", _) => return false,
+            ("", _) => {}
+            _ => println!(
+                "commands: break <loc>, delete <loc>, step, continue, reverse-step, \
+                 reverse-continue, regs, last-write <reg>, quit"
+            ),
+        }
+
+        true
+    }
+
+    pub fn repl(&mut self) {
+        self.show_position();
+        loop {
+            print!("(vdb) ");
+            io::stdout().flush().expect("Failed to flush to stdout");
+            // Not holding the stdin lock, the program may read from it too.
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(n) if n > 0 => {}
+                _ => break,
+            }
+            let running = self.command(line.trim());
+            self.vm.env_mut().flush();
+            if !running {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::registers::Register;
+    use crate::structures::structures::GeneralStructure;
+    use crate::test_support::assemble;
+
+    fn debugger(source: &str) -> Debugger {
+        let program = assemble(source);
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        vm.env_mut().set_output(Box::new(std::io::sink()));
+        Debugger::init(vm, program.labels)
+    }
+
+    fn rax(debugger: &Debugger) -> GeneralData {
+        debugger.vm.env().register(Register::RAX).clone()
+    }
+
+    const LOOP: &str = "mov rax, 0\nloop: add rax, 1\ncmp rax, 3\njne loop\nhlt\n";
+
+    #[test]
+    fn breakpoints_stop_both_ways() {
+        let mut debugger = debugger(LOOP);
+        assert!(debugger.command("break :loop"));
+        debugger.command("c");
+        assert_eq!(debugger.vm.pc(), 1);
+        debugger.command("continue");
+        assert_eq!(
+            (debugger.vm.pc(), rax(&debugger)),
+            (1, GeneralData::Int64(1))
+        );
+        debugger.command("s");
+        assert_eq!(rax(&debugger), GeneralData::Int64(2));
+
+        debugger.command("rc");
+        assert_eq!(
+            (debugger.vm.pc(), rax(&debugger)),
+            (1, GeneralData::Int64(1))
+        );
+        debugger.command("rs");
+        assert_eq!(debugger.vm.pc(), 3);
+
+        debugger.command("delete loop");
+        debugger.command("c");
+        assert!(debugger.vm.finished());
+        assert_eq!(rax(&debugger), GeneralData::Int64(3));
+        debugger.command("reverse-continue");
+        assert_eq!(
+            (debugger.vm.pc(), rax(&debugger)),
+            (0, GeneralData::Int32(0))
+        );
+    }
+
+    #[test]
+    fn locations_and_commands() {
+        let mut debugger = debugger(LOOP);
+        assert_eq!(debugger.resolve("loop"), Some(1));
+        assert_eq!(debugger.resolve("3"), Some(3));
+        assert_eq!(debugger.resolve("nowhere"), None);
+        debugger.command("b 4");
+        debugger.command("b nowhere");
+        assert_eq!(debugger.breakpoints.iter().collect::<Vec<_>>(), [&4]);
+        assert!(debugger.command("help"));
+        assert!(!debugger.command("q"));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::structures::parser::Parser;
+use crate::structures::registers::Register;
+use crate::structures::syntax::{Statement, SyntaxTree};
+use std::fs;
+use std::io::{self, Read};
+
+const INDENT: &str = "    ";
+
+/// One output line before comments are aligned.
+struct Row {
+    code: String,
+    comment: Option<String>,
+    /// Standalone comments keep their own indentation instead of aligning.
+    standalone: bool,
+}
+
+fn operand(raw: &str) -> String {
+    if Register::is_reg(raw) {
+        return raw.to_lowercase();
+    }
+    // The base register of `[base+disp]`.
+    if let Some(inner) = raw.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        let split = inner.find(['+', '-']).unwrap_or(inner.len());
+        let (base, disp) = inner.split_at(split);
+        if Register::is_reg(base.trim()) {
+            return format!("[{}{}]", base.to_lowercase(), disp);
+        }
+    }
+    String::from(raw)
+}
+
+/// `continuation` is set when the line above ended in `\`, its operands
+/// are then lined up under the ones above.
+fn rows(statement: &Statement, mnemonic_width: usize, continuation: bool) -> Vec<Row> {
+    // Data and constants keep their labels on the same line.
+    if let (Some(mnemonic), Some(_)) = (&statement.mnemonic, statement.directive) {
+        let operands: Vec<String> = statement.operands.iter().map(|o| operand(o)).collect();
+        let labels: String = statement
+            .labels
+            .iter()
+            .map(|l| format!("{}: ", l))
+            .collect();
+        let indent = if labels.is_empty() { INDENT } else { "" };
+        let mut code = format!(
+            "{}{}{} {}",
+            indent,
+            labels,
+            mnemonic.to_lowercase(),
+            operands.join(", ")
+        );
+        code.truncate(code.trim_end().len());
+        if statement.continued {
+            if !operands.is_empty() {
+                code.push(',');
+            }
+            code.push_str(" \\");
+        }
+        return vec![Row {
+            code,
+            comment: statement.comment.clone(),
+            standalone: false,
+        }];
+    }
+
+    let mut rows: Vec<Row> = statement
+        .labels
+        .iter()
+        .map(|label| Row {
+            code: format!("{}:", label),
+            comment: None,
+            standalone: false,
+        })
+        .collect();
+
+    if statement.mnemonic.is_some() || !statement.operands.is_empty() || statement.continued {
+        let operands: Vec<String> = statement.operands.iter().map(|o| operand(o)).collect();
+        let mut code = match &statement.mnemonic {
+            Some(mnemonic) => format!(
+                "{}{:<width$} {}",
+                INDENT,
+                mnemonic.to_lowercase(),
+                operands.join(", "),
+                width = mnemonic_width
+            ),
+            None if continuation => format!(
+                "{}{:width$} {}",
+                INDENT,
+                "",
+                operands.join(", "),
+                width = mnemonic_width
+            ),
+            None => format!("{}{}", INDENT, operands.join(", ")),
+        };
+        code.truncate(code.trim_end().len());
+        if statement.continued {
+            if !operands.is_empty() {
+                code.push(',');
+            }
+            code.push_str(" \\");
+        }
+        rows.push(Row {
+            code,
+            comment: None,
+            standalone: false,
+        });
+    }
+
+    match rows.last_mut() {
+        Some(row) => row.comment = statement.comment.clone(),
+        None => rows.push(Row {
+            code: String::from(if statement.comment_column == 0 {
+                ""
+            } else {
+                INDENT
+            }),
+            comment: statement.comment.clone(),
+            standalone: true,
+        }),
+    }
+    rows
+}
+
+/// Re-emits a program in canonical layout: `name:` labels on their own line
+/// in column 0, indented lowercase mnemonics padded so operands line up,
+/// lowercase registers and trailing comments aligned to one column.
+pub fn format(tree: &SyntaxTree) -> String {
+    let mnemonic_width = tree
+        .statements
+        .iter()
+        .filter(|s| s.op_code.is_some())
+        .filter_map(|s| s.mnemonic.as_ref().map(|m| m.chars().count()))
+        .max()
+        .unwrap_or(0);
+
+    let mut all: Vec<Row> = Vec::new();
+    let mut blank = true;
+    let mut continuation = false;
+    for statement in &tree.statements {
+        if statement.is_blank() {
+            if !blank {
+                all.push(Row {
+                    code: String::new(),
+                    comment: None,
+                    standalone: false,
+                });
+            }
+            blank = true;
+            continue;
+        }
+        blank = false;
+        all.extend(rows(statement, mnemonic_width, continuation));
+        continuation = statement.continued;
+    }
+    while all
+        .last()
+        .is_some_and(|row| row.code.is_empty() && row.comment.is_none())
+    {
+        all.pop();
+    }
+
+    let comment_column = all
+        .iter()
+        .filter(|row| row.comment.is_some() && !row.standalone)
+        .map(|row| row.code.chars().count() + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for row in all {
+        out.push_str(&row.code);
+        if let Some(comment) = row.comment {
+            if !row.standalone {
+                let pad = comment_column - row.code.chars().count();
+                out.push_str(&" ".repeat(pad));
+            }
+            out.push_str(comment.trim_end());
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub fn format_source(source: String) -> String {
+    let mut parse = Parser::init(source);
+    parse.parse();
+    format(&SyntaxTree::from_parser(&parse))
+}
+
+/// `fmt [--check | --write] <files>`: prints formatted sources, rewrites
+/// them in place, or only reports which files would change. `-` is
+/// standard input, which `--write` prints instead. Returns the process
+/// exit code.
+pub fn run(args: &[String]) -> i32 {
+    let check = args.iter().any(|a| a == "--check");
+    let write = args.iter().any(|a| a == "--write");
+    let mut code = 0;
+
+    for path in args.iter().filter(|a| !a.starts_with("--")) {
+        let read = if path == "-" {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source).map(|_| source)
+        } else {
+            fs::read_to_string(path)
+        };
+        let source = match read {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                code = 2;
+                continue;
+            }
+        };
+        let formatted = format_source(source.clone());
+
+        if check {
+            if formatted != source {
+                println!("{}", path);
+                code = 1;
+            }
+        } else if write && path != "-" {
+            if formatted != source {
+                if let Err(e) = fs::write(path, formatted) {
+                    eprintln!("{}: {}", path, e);
+                    code = 2;
+                }
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(source: &str) -> String {
+        format_source(String::from(source))
+    }
+
+    #[test]
+    fn labels_mnemonics_and_registers_are_canonical() {
+        assert_eq!(
+            fmt(":start MOV RAX, 1\n  PNL   Rax\nJMP start\n"),
+            "start:\n    mov rax, 1\n    pnl rax\n    jmp start\n"
+        );
+    }
+
+    #[test]
+    fn memory_operands_lowercase_their_base() {
+        assert_eq!(
+            fmt("mov RAX, [RBX+8]\nlea rcx, [RSP]\nmov [Data-8], rax\n"),
+            "    mov rax, [rbx+8]\n    lea rcx, [rsp]\n    mov [Data-8], rax\n"
+        );
+    }
+
+    #[test]
+    fn comments_align_and_standalone_ones_stay() {
+        assert_eq!(
+            fmt("; header\nmov rax, 1 ; one\npush rax   ; two\n\n\n\nhlt\n"),
+            "; header\n    mov  rax, 1 ; one\n    push rax    ; two\n\n    hlt\n"
+        );
+    }
+
+    #[test]
+    fn data_keeps_its_label_and_continuations_line_up() {
+        assert_eq!(
+            fmt("msg:   DB \"hi\",10\nmov rax, \\\n  1\n"),
+            "msg: db \"hi\", 10\n    mov rax, \\\n        1\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let source = "a: db 1\n:main\nMOV RAX,[RBX]   ; load\n\n  ; note\ncmp rax, 0\nje main\n";
+        let once = fmt(source);
+        assert_eq!(fmt(&once), once);
+    }
+}
@@ -0,0 +1,506 @@
+use crate::structures::data_types::GeneralData;
+use crate::structures::registers::Register;
+use crate::structures::structures::GeneralStructure;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+/// GDB's x86-64 general register order, mapped onto ours. `None` entries
+/// (r8-r15) have no counterpart and always read as zero.
+const GDB_GPRS: [Option<Register>; 16] = [
+    Some(Register::RAX),
+    Some(Register::RBX),
+    Some(Register::RCX),
+    Some(Register::RDX),
+    Some(Register::RSI),
+    Some(Register::RDI),
+    Some(Register::RBP),
+    Some(Register::RSP),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+const GDB_RIP: usize = 16;
+const GDB_EFLAGS: usize = 17;
+/// rip and the 16 GPRs are 8 bytes wide, eflags and the 6 segment registers 4.
+const GDB_REG_COUNT: usize = 24;
+const EFLAGS_ZF: u32 = 1 << 6;
+
+/// GDB Remote Serial Protocol server. The program counter is exposed as
+/// `rip` holding the instruction index, and breakpoints are set on those
+/// indices.
+pub struct GdbStub {
+    vm: GeneralStructure,
+    breakpoints: BTreeSet<usize>,
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+}
+
+/// Builds a register value from raw bits, keeping the type the register
+/// already holds where that type can represent them.
+fn from_bits(old: &GeneralData, bits: u64) -> GeneralData {
+    match old {
+        GeneralData::Uint32(_) => GeneralData::Uint32(bits as u32),
+        GeneralData::Uint64(_) => GeneralData::Uint64(bits),
+        GeneralData::Int32(_) => GeneralData::Int32(bits as i32),
+        GeneralData::Float(_) => GeneralData::Float(f32::from_bits(bits as u32)),
+        GeneralData::Double(_) => GeneralData::Double(f64::from_bits(bits)),
+        _ => GeneralData::Int64(bits as i64),
+    }
+}
+
+fn hex_le(value: u64, width: usize) -> String {
+    value.to_le_bytes()[..width]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_le(hex: &str) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate().take(hex.len() / 2) {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Undoes the `}` escaping of binary packet data.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => out.extend(bytes.next().map(|b| b ^ 0x20)),
+            _ => out.push(*byte),
+        }
+    }
+    out
+}
+
+fn reg_width(n: usize) -> usize {
+    if n <= GDB_RIP {
+        8
+    } else {
+        4
+    }
+}
+
+impl GdbStub {
+    pub fn init(
+        mut vm: GeneralStructure,
+        reader: Box<dyn BufRead>,
+        writer: Box<dyn Write>,
+    ) -> Self {
+        vm.env_mut().set_recording(true);
+        GdbStub {
+            vm,
+            breakpoints: BTreeSet::new(),
+            reader,
+            writer,
+        }
+    }
+
+    fn read_reg(&self, n: usize) -> u64 {
+        let env = self.vm.env();
+        match n {
+            0..=15 => GDB_GPRS[n].map_or(0, |reg| env.register(reg).to_bits()),
+            GDB_RIP => self.vm.pc() as u64,
+            GDB_EFLAGS if env.flags().zf => EFLAGS_ZF as u64,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, n: usize, bits: u64) {
+        let env = self.vm.env_mut();
+        match n {
+            0..=15 => {
+                if let Some(reg) = GDB_GPRS[n] {
+                    let data = from_bits(env.register(reg), bits);
+                    env.set_register(reg, data);
+                }
+            }
+            GDB_RIP => env.pc = bits as i64,
+            GDB_EFLAGS => {
+                let mut flags = env.flags();
+                flags.zf = bits as u32 & EFLAGS_ZF != 0;
+                env.set_flags(flags);
+            }
+            _ => {}
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        if self.vm.finished() {
+            format!("W{:02x}", self.vm.env().exit_code() as u8)
+        } else {
+            String::from("S05")
+        }
+    }
+
+    /// Runs until a breakpoint, the end or after one instruction. A
+    /// runtime error stops at the failing instruction, its message goes to
+    /// the gdb console and along with the stop as `fault`.
+    fn resume(&mut self, single: bool) -> String {
+        loop {
+            match self.vm.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) => {
+                    let message = format!("error at #{}: {}", error.pc, error);
+                    self.send(&format!("O{}", to_hex(format!("{}\n", message).as_bytes())));
+                    return format!("T05fault:{};", to_hex(message.as_bytes()));
+                }
+            }
+            if single || self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+        }
+        self.vm.env_mut().flush();
+        self.stop_reply()
+    }
+
+    fn resume_backward(&mut self, single: bool) -> String {
+        loop {
+            if !self.vm.reverse_step() {
+                return String::from("T05replaylog:begin;");
+            }
+            if single || self.breakpoints.contains(&self.vm.pc()) {
+                return String::from("S05");
+            }
+        }
+    }
+
+    /// Parses `addr,len` into a range of memory, `None` when it is
+    /// malformed or leaves memory.
+    fn memory_range(&self, spec: &str) -> Option<std::ops::Range<usize>> {
+        let (addr, len) = spec.split_once(',')?;
+        let addr = usize::from_str_radix(addr, 16).ok()?;
+        let end = addr.checked_add(usize::from_str_radix(len, 16).ok()?)?;
+        (end <= self.vm.env().memory().len()).then_some(addr..end)
+    }
+
+    fn read_memory(&self, spec: &str) -> String {
+        match self.memory_range(spec) {
+            Some(range) => to_hex(&self.vm.env().memory()[range]),
+            None => String::from("E01"),
+        }
+    }
+
+    fn write_memory(&mut self, spec: &str, bytes: Option<Vec<u8>>) -> String {
+        match (self.memory_range(spec), bytes) {
+            (Some(range), Some(bytes)) if bytes.len() == range.len() => {
+                self.vm.env_mut().set_memory(range.start, &bytes);
+                String::from("OK")
+            }
+            _ => String::from("E01"),
+        }
+    }
+
+    fn set_pc(&mut self, addr: &str) {
+        if let Ok(pc) = i64::from_str_radix(addr, 16) {
+            self.vm.env_mut().pc = pc;
+        }
+    }
+
+    fn breakpoint(&mut self, packet: &str, insert: bool) -> String {
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next();
+        let addr = fields
+            .next()
+            .and_then(|a| usize::from_str_radix(a, 16).ok());
+
+        match (kind, addr) {
+            (Some("0") | Some("1"), Some(addr)) => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                String::from("OK")
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Answers one packet, `None` ends the session.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.chars().next() {
+            Some('?') => self.stop_reply(),
+            Some('g') => (0..GDB_REG_COUNT)
+                .map(|n| hex_le(self.read_reg(n), reg_width(n)))
+                .collect(),
+            Some('G') => {
+                let mut pos = 1;
+                for n in 0..GDB_REG_COUNT {
+                    let end = pos + reg_width(n) * 2;
+                    if end > packet.len() {
+                        break;
+                    }
+                    if let Some(bits) = parse_le(&packet[pos..end]) {
+                        self.write_reg(n, bits);
+                    }
+                    pos = end;
+                }
+                String::from("OK")
+            }
+            Some('p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(n) if n < GDB_REG_COUNT => hex_le(self.read_reg(n), reg_width(n)),
+                _ => String::from("E00"),
+            },
+            Some('P') => {
+                let (n, value) = packet[1..].split_once('=').unwrap_or(("", ""));
+                match (usize::from_str_radix(n, 16), parse_le(value)) {
+                    (Ok(n), Some(bits)) if n < GDB_REG_COUNT => {
+                        self.write_reg(n, bits);
+                        String::from("OK")
+                    }
+                    _ => String::from("E00"),
+                }
+            }
+            Some('m') => self.read_memory(&packet[1..]),
+            Some('M') => {
+                let (spec, hex) = packet[1..].split_once(':').unwrap_or(("", ""));
+                self.write_memory(spec, parse_hex(hex))
+            }
+            Some('c') => {
+                self.set_pc(&packet[1..]);
+                self.resume(false)
+            }
+            Some('s') => {
+                self.set_pc(&packet[1..]);
+                self.resume(true)
+            }
+            Some('b') if packet == "bc" => self.resume_backward(false),
+            Some('b') if packet == "bs" => self.resume_backward(true),
+            Some('Z') => self.breakpoint(packet, true),
+            Some('z') => self.breakpoint(packet, false),
+            Some('H') | Some('T') => String::from("OK"),
+            Some('D') => {
+                self.send("OK");
+                return None;
+            }
+            Some('k') => return None,
+            _ if packet.starts_with("qSupported") => {
+                String::from("PacketSize=4000;ReverseStep+;ReverseContinue+")
+            }
+            _ if packet == "qAttached" => String::from("1"),
+            _ if packet == "qC" => String::from("QC1"),
+            _ if packet == "qfThreadInfo" => String::from("m1"),
+            _ if packet == "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    fn send(&mut self, data: &str) {
+        let sum = checksum(data.as_bytes());
+        write!(self.writer, "${}#{:02x}", data, sum).expect("Failed to write to gdb");
+        self.writer.flush().expect("Failed to flush to gdb");
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        match self.reader.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    /// Reads the next `$packet#xx`, acknowledging it. A packet whose
+    /// checksum does not match is answered with `-` so gdb sends it again.
+    /// Returns `None` on disconnect.
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            while self.read_byte()? != b'$' {}
+            let mut data: Vec<u8> = Vec::new();
+            self.reader.read_until(b'#', &mut data).ok()?;
+            data.pop();
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let sum = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if sum == Some(checksum(&data)) {
+                self.writer.write_all(b"+").ok()?;
+                return Some(data);
+            }
+            self.writer.write_all(b"-").ok()?;
+        }
+    }
+
+    pub fn serve(&mut self) {
+        while let Some(packet) = self.receive() {
+            // X carries raw bytes after the colon, everything else is text.
+            let reply = match packet.split_first() {
+                Some((b'X', rest)) => {
+                    let colon = rest.iter().position(|b| *b == b':').unwrap_or(rest.len());
+                    let spec = String::from_utf8_lossy(&rest[..colon]);
+                    let bytes = rest.get(colon + 1..).map(unescape);
+                    Some(self.write_memory(&spec, bytes))
+                }
+                _ => self.handle(&String::from_utf8_lossy(&packet)),
+            };
+            match reply {
+                Some(reply) => self.send(&reply),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Serves one gdb session on `127.0.0.1:<port>`, or on stdin/stdout when
+/// `port` is `-`. Program output goes to stderr in the latter case.
+pub fn listen(mut vm: GeneralStructure, port: &str) -> io::Result<()> {
+    if port == "-" {
+        vm.env_mut().set_output(Box::new(io::stderr()));
+        vm.env_mut().set_input(Box::new(io::empty()));
+        let reader = Box::new(io::BufReader::new(io::stdin()));
+        GdbStub::init(vm, reader, Box::new(io::stdout())).serve();
+        return Ok(());
+    }
+
+    let listener = std::net::TcpListener::bind(format!("127.0.0.1:{}", port))?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    let reader = Box::new(io::BufReader::new(stream.try_clone()?));
+    GdbStub::init(vm, reader, Box::new(stream)).serve();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::env_vars::DATA_BASE;
+    use crate::test_support::{assemble, SharedBuffer};
+
+    fn stub(source: &str, input: &[u8]) -> (GdbStub, SharedBuffer) {
+        let program = assemble(source);
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        vm.env_mut().set_output(Box::new(io::sink()));
+        let sent = SharedBuffer::default();
+        let reader = Box::new(io::Cursor::new(input.to_vec()));
+        (GdbStub::init(vm, reader, Box::new(sent.clone())), sent)
+    }
+
+    #[test]
+    fn packets_are_acknowledged_and_checksummed() {
+        let (mut stub, sent) = stub("nop\n", b"$?#3f$k#6b");
+        stub.serve();
+        assert_eq!(sent.text(), "+$S05#b8+");
+    }
+
+    #[test]
+    fn bad_checksums_are_refused() {
+        let (mut stub, sent) = stub("nop\n", b"$?#00$?#3f$k#6b");
+        stub.serve();
+        assert_eq!(sent.text(), "-+$S05#b8+");
+    }
+
+    #[test]
+    fn runtime_errors_stop_at_the_failing_instruction() {
+        let (mut stub, sent) = stub("mov rax, 1\ndiv rax, 0\nhlt\n", b"");
+        let message = "error at #1: DIV by zero";
+        assert_eq!(
+            stub.handle("c").unwrap(),
+            format!("T05fault:{};", to_hex(message.as_bytes()))
+        );
+        assert_eq!(stub.vm.pc(), 1);
+        let console = format!("O{}", to_hex(format!("{}\n", message).as_bytes()));
+        assert!(sent.text().starts_with(&format!("${}#", console)));
+    }
+
+    #[test]
+    fn registers_read_and_write_little_endian() {
+        let (mut stub, _) = stub("mov rax, 0x102\n", b"");
+        assert_eq!(stub.handle("s").unwrap(), "W00");
+        assert_eq!(stub.handle("p0").unwrap(), "0201000000000000");
+        assert_eq!(stub.handle("P1=0500000000000000").unwrap(), "OK");
+        assert_eq!(
+            stub.vm.env().register(Register::RBX),
+            &GeneralData::Int32(5)
+        );
+        assert_eq!(stub.handle("p10").unwrap(), "0100000000000000");
+        assert_eq!(stub.handle("p99").unwrap(), "E00");
+    }
+
+    #[test]
+    fn memory_reads_and_writes_are_bounds_checked() {
+        let (mut stub, _) = stub("data: db 1, 2, 3\nnop\n", b"");
+        let at = format!("{:x}", DATA_BASE);
+        assert_eq!(stub.handle(&format!("m{},3", at)).unwrap(), "010203");
+        assert_eq!(stub.handle(&format!("M{},2:ff7f", at)).unwrap(), "OK");
+        assert_eq!(stub.handle(&format!("m{},3", at)).unwrap(), "ff7f03");
+        assert_eq!(stub.handle("m10000,1").unwrap(), "E01");
+        assert_eq!(stub.handle("mffff,2").unwrap(), "E01");
+        assert_eq!(stub.handle(&format!("M{},2:ff", at)).unwrap(), "E01");
+    }
+
+    #[test]
+    fn binary_writes_are_unescaped() {
+        let data = format!("X{:x},2:}}\x03a", DATA_BASE);
+        let packet = format!("${}#{:02x}$k#6b", data, checksum(data.as_bytes()));
+        let (mut stub, sent) = stub("nop\n", packet.as_bytes());
+        stub.serve();
+        assert!(sent.text().starts_with("+$OK#"));
+        assert_eq!(&stub.vm.env().memory()[DATA_BASE..DATA_BASE + 2], b"#a");
+    }
+
+    #[test]
+    fn memory_writes_are_undone_with_the_last_instruction() {
+        let (mut stub, _) = stub("nop\nnop\n", b"");
+        stub.handle("s").unwrap();
+        stub.handle("M0,1:2a").unwrap();
+        assert_eq!(stub.vm.env().memory()[0], 42);
+        assert_eq!(stub.handle("bs").unwrap(), "S05");
+        assert_eq!(stub.vm.env().memory()[0], 0);
+    }
+
+    #[test]
+    fn breakpoints_stop_continue() {
+        let (mut stub, _) = stub("nop\nnop\nnop\n", b"");
+        assert_eq!(stub.handle("Z0,2,1").unwrap(), "OK");
+        assert_eq!(stub.handle("c").unwrap(), "S05");
+        assert_eq!(stub.vm.pc(), 2);
+        assert_eq!(stub.handle("z0,2,1").unwrap(), "OK");
+        assert_eq!(stub.handle("c").unwrap(), "W00");
+        assert_eq!(stub.handle("bc").unwrap(), "T05replaylog:begin;");
+        assert_eq!(stub.vm.pc(), 0);
+    }
+
+    #[test]
+    fn hex_helpers_round_trip() {
+        assert_eq!(hex_le(0x0102, 4), "02010000");
+        assert_eq!(parse_le("0201"), Some(0x0102));
+        assert_eq!(parse_le("021"), None);
+        assert_eq!(parse_hex("0aff"), Some(vec![10, 255]));
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(unescape(b"a}]b"), b"a}b");
+    }
+}
@@ -0,0 +1,19 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod cfg;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod formatter;
+pub mod gdb;
+pub mod lsp;
+pub mod object;
+pub mod optimizer;
+pub mod profiler;
+pub mod protocol;
+pub mod structures;
+#[cfg(test)]
+mod test_support;
+pub mod testing;
+pub mod typecheck;
+pub mod vfs;
@@ -0,0 +1,546 @@
+use crate::protocol::{read_message, write_message, ReadError};
+use crate::structures::data_types::{DataType, GeneralData};
+use crate::structures::flow_structure::{Directive, OpCode};
+use crate::structures::interpreter::Interpreter;
+use crate::structures::parser::Parser;
+use crate::structures::registers::Register;
+use crate::structures::tokenizer::Tokenizer;
+use crate::structures::tokens::Tokens;
+use num_traits::FromPrimitive;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+const SEVERITY_ERROR: i64 = 1;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_REFERENCE: i64 = 18;
+/// JSON-RPC's code for a message that is not JSON.
+const PARSE_ERROR: i64 = -32700;
+
+#[derive(Debug, PartialEq)]
+enum SymbolKind {
+    Mnemonic(OpCode),
+    Directive(Directive),
+    Register,
+    /// `:name` or `name:`, holding the name without the colon.
+    Label(String),
+    /// A bare word naming a defined label.
+    Reference(String),
+    Literal(DataType),
+    /// An integer expression such as `end-start`.
+    Expression,
+    /// A `[base+disp]` operand.
+    Memory,
+    Comment,
+    /// A bare word that is neither a mnemonic, register nor known label.
+    Unknown,
+}
+
+struct Symbol {
+    text: String,
+    /// 0-based, as LSP counts.
+    line: usize,
+    column: usize,
+    len: usize,
+    kind: SymbolKind,
+}
+
+impl Symbol {
+    fn range(&self) -> Value {
+        json!({
+            "start": {"line": self.line, "character": self.column},
+            "end": {"line": self.line, "character": self.column + self.len},
+        })
+    }
+
+    fn contains(&self, line: usize, column: usize) -> bool {
+        self.line == line && column >= self.column && column <= self.column + self.len
+    }
+
+    /// The label this symbol defines or refers to.
+    fn label(&self) -> Option<&str> {
+        match &self.kind {
+            SymbolKind::Label(name) | SymbolKind::Reference(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Everything the server knows about one document, rebuilt on every change.
+struct Analysis {
+    symbols: Vec<Symbol>,
+    labels: HashMap<String, usize>,
+    diagnostics: Vec<Value>,
+}
+
+impl Analysis {
+    fn init(text: &str) -> Self {
+        let mut parse = Parser::init(String::from(text));
+        parse.parse();
+        let texts = parse.tokens.clone();
+        let raws = parse.raw_tokens.clone();
+        let lines = parse.lines.clone();
+        let columns = parse.columns.clone();
+        let mut tokenizer = Tokenizer::init(parse);
+        let tokens = tokenizer.tokenize();
+
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        for (i, token) in tokens.iter().enumerate() {
+            if let Tokens::CHECKPOINT(name) = token {
+                labels.insert(String::from(&name[1..]), i);
+            }
+        }
+
+        let mut symbols: Vec<Symbol> = Vec::with_capacity(tokens.len());
+        for (i, token) in tokens.iter().enumerate() {
+            let (line, column) = (lines[i] - 1, columns[i]);
+            let quote = raws[i].starts_with(['"', '\'']);
+            let kind = match token {
+                Tokens::INSTRUCTION(op) => SymbolKind::Mnemonic(*op),
+                Tokens::DIRECTIVE(directive) => SymbolKind::Directive(*directive),
+                Tokens::REGISTER(_) => SymbolKind::Register,
+                Tokens::CHECKPOINT(name) => SymbolKind::Label(String::from(&name[1..])),
+                Tokens::COMMENT(_) => SymbolKind::Comment,
+                Tokens::GOTO(name) => SymbolKind::Reference(name.clone()),
+                Tokens::EXPR(_) => SymbolKind::Expression,
+                Tokens::MEMORY(_, _) => SymbolKind::Memory,
+                Tokens::DATA(GeneralData::String(_)) if quote => {
+                    SymbolKind::Literal(DataType::String)
+                }
+                Tokens::DATA(GeneralData::String(_)) => SymbolKind::Unknown,
+                Tokens::DATA(d) => SymbolKind::Literal(d.data_type()),
+            };
+            let len = raws[i].chars().count();
+            symbols.push(Symbol {
+                text: texts[i].clone(),
+                line,
+                column,
+                len,
+                kind,
+            });
+        }
+
+        let mut this = Analysis {
+            symbols,
+            labels,
+            diagnostics: Vec::new(),
+        };
+        let mut errors = tokenizer.errors.clone();
+        if errors.is_empty() {
+            if let Err(assembly) = Interpreter::interpret(tokenizer.tokens(), tokenizer.parser()) {
+                errors = assembly;
+            }
+        }
+        for e in &errors {
+            let (line, column) = (e.line - 1, e.column);
+            let end = this
+                .symbols
+                .iter()
+                .find(|s| s.line == line && s.column == column)
+                .map_or(column, |s| column + s.len);
+            this.diagnostics.push(json!({
+                "range": {
+                    "start": {"line": line, "character": column},
+                    "end": {"line": line, "character": end},
+                },
+                "severity": SEVERITY_ERROR,
+                "source": "vcpu",
+                "message": e.message,
+            }));
+        }
+        this
+    }
+
+    fn at(&self, line: usize, column: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.contains(line, column))
+    }
+
+    /// Definition and references of `name`, definition first.
+    fn occurrences(&self, name: &str) -> Vec<&Symbol> {
+        let mut found: Vec<&Symbol> = self
+            .symbols
+            .iter()
+            .filter(|s| s.label() == Some(name))
+            .collect();
+        found.sort_by_key(|s| !matches!(s.kind, SymbolKind::Label(_)));
+        found
+    }
+
+    fn hover(&self, symbol: &Symbol) -> Option<String> {
+        match &symbol.kind {
+            SymbolKind::Mnemonic(op) => Some(String::from(op.doc())),
+            SymbolKind::Directive(directive) => Some(String::from(directive.doc())),
+            SymbolKind::Register => {
+                Register::from_string(&symbol.text).map(|reg| format!("register {:?}", reg))
+            }
+            SymbolKind::Label(name) | SymbolKind::Reference(name) => {
+                let def = &self.symbols[*self.labels.get(name)?];
+                Some(format!(
+                    "label `{}`, defined on line {}",
+                    name,
+                    def.line + 1
+                ))
+            }
+            SymbolKind::Literal(t) => Some(format!("{:?} literal", t)),
+            SymbolKind::Expression => Some(String::from("expression, evaluated at assembly time")),
+            SymbolKind::Memory => Some(String::from("memory operand, 8 bytes")),
+            _ => None,
+        }
+    }
+}
+
+/// Language server for the assembly dialect, speaking LSP over stdin/stdout.
+pub struct LspServer {
+    documents: HashMap<String, Analysis>,
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+}
+
+fn completions(analysis: Option<&Analysis>) -> Vec<Value> {
+    let mut items: Vec<Value> = Vec::new();
+    for i in 0..(OpCode::COUNT as usize) {
+        let op: OpCode = FromPrimitive::from_usize(i).unwrap();
+        items.push(json!({
+            "label": format!("{:?}", op).to_lowercase(),
+            "kind": COMPLETION_KEYWORD,
+            "documentation": op.doc(),
+        }));
+    }
+    for directive in [Directive::DB, Directive::DQ, Directive::EQU] {
+        items.push(json!({
+            "label": format!("{:?}", directive).to_lowercase(),
+            "kind": COMPLETION_KEYWORD,
+            "documentation": directive.doc(),
+        }));
+    }
+    for i in 0..(Register::NIL as usize) {
+        let reg: Register = FromPrimitive::from_usize(i).unwrap();
+        items.push(json!({
+            "label": format!("{:?}", reg).to_lowercase(),
+            "kind": COMPLETION_VARIABLE,
+        }));
+    }
+    if let Some(analysis) = analysis {
+        for name in analysis.labels.keys() {
+            items.push(json!({"label": name, "kind": COMPLETION_REFERENCE}));
+        }
+    }
+    items
+}
+
+impl LspServer {
+    pub fn init(reader: Box<dyn BufRead>, writer: Box<dyn Write>) -> Self {
+        LspServer {
+            documents: HashMap::new(),
+            reader,
+            writer,
+        }
+    }
+
+    fn respond(&mut self, request: &Value, result: Value) {
+        let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+        write_message(&mut self.writer, &response);
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        let notification = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        write_message(&mut self.writer, &notification);
+    }
+
+    fn update(&mut self, uri: &str, text: &str) {
+        let analysis = Analysis::init(text);
+        let diagnostics = analysis.diagnostics.clone();
+        self.documents.insert(String::from(uri), analysis);
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({"uri": uri, "diagnostics": diagnostics}),
+        );
+    }
+
+    /// Finds the document and symbol under a `TextDocumentPositionParams`.
+    fn lookup<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a Analysis, &'a Symbol)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let analysis = self.documents.get(uri)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let column = params["position"]["character"].as_u64()? as usize;
+        Some((uri, analysis, analysis.at(line, column)?))
+    }
+
+    fn locations(&self, params: &Value) -> Vec<Value> {
+        match self.lookup(params) {
+            Some((uri, analysis, symbol)) => match symbol.label() {
+                Some(name) => analysis
+                    .occurrences(name)
+                    .iter()
+                    .map(|s| json!({"uri": uri, "range": s.range()}))
+                    .collect(),
+                None => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+
+    fn rename(&self, params: &Value) -> Value {
+        let new_name = params["newName"].as_str().unwrap_or("");
+        let (uri, analysis, symbol) = match self.lookup(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let name = match symbol.label() {
+            Some(name) if !new_name.is_empty() && !new_name.contains(char::is_whitespace) => name,
+            _ => return Value::Null,
+        };
+        let new_name = new_name.trim_matches(':');
+        let edits: Vec<Value> = analysis
+            .occurrences(name)
+            .iter()
+            .map(|s| {
+                let text = match s.kind {
+                    SymbolKind::Label(_) if s.text.ends_with(':') => format!("{}:", new_name),
+                    SymbolKind::Label(_) => format!(":{}", new_name),
+                    _ => String::from(new_name),
+                };
+                json!({"range": s.range(), "newText": text})
+            })
+            .collect();
+
+        json!({"changes": {uri: edits}})
+    }
+
+    /// Handles one message, returns false on `exit`.
+    fn handle(&mut self, message: &Value) -> bool {
+        let params = &message["params"];
+        match message["method"].as_str().unwrap_or("") {
+            "initialize" => self.respond(
+                message,
+                json!({"capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "renameProvider": true,
+                    "completionProvider": {},
+                }}),
+            ),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let uri = document["uri"].as_str().unwrap_or("");
+                self.update(uri, document["text"].as_str().unwrap_or(""));
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.update(uri, text);
+                }
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                self.documents.remove(uri);
+            }
+            "textDocument/hover" => {
+                let hover = self.lookup(params).and_then(|(_, analysis, symbol)| {
+                    Some((analysis.hover(symbol)?, symbol.range()))
+                });
+                let result = match hover {
+                    Some((text, range)) => {
+                        json!({"contents": {"kind": "markdown", "value": text}, "range": range})
+                    }
+                    None => Value::Null,
+                };
+                self.respond(message, result);
+            }
+            "textDocument/definition" => {
+                let result = self
+                    .locations(params)
+                    .into_iter()
+                    .next()
+                    .unwrap_or(Value::Null);
+                self.respond(message, result);
+            }
+            "textDocument/references" => {
+                let mut result = self.locations(params);
+                if params["context"]["includeDeclaration"] == json!(false) && !result.is_empty() {
+                    result.remove(0);
+                }
+                self.respond(message, json!(result));
+            }
+            "textDocument/completion" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                let items = completions(self.documents.get(uri));
+                self.respond(message, json!(items));
+            }
+            "textDocument/rename" => {
+                let result = self.rename(params);
+                self.respond(message, result);
+            }
+            "shutdown" => self.respond(message, Value::Null),
+            "exit" => return false,
+            _ => {
+                if !message["id"].is_null() {
+                    self.respond(message, Value::Null);
+                }
+            }
+        }
+        true
+    }
+
+    /// Handles messages until `exit` or the end of input. A message that
+    /// is not JSON is answered with a parse error and skipped.
+    pub fn serve(&mut self) {
+        loop {
+            let message = match read_message(&mut self.reader) {
+                Ok(message) => message,
+                Err(ReadError::Parse(e)) => {
+                    let error = json!({"code": PARSE_ERROR, "message": e});
+                    let response = json!({"jsonrpc": "2.0", "id": null, "error": error});
+                    write_message(&mut self.writer, &response);
+                    continue;
+                }
+                Err(ReadError::Closed) => break,
+            };
+            if !self.handle(&message) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::SharedBuffer;
+    use std::io;
+
+    const URI: &str = "file:///test.asm";
+
+    /// Opens `text` and sends `requests`, returning every message sent
+    /// back.
+    fn exchange(text: &str, requests: &[Value]) -> Vec<Value> {
+        let mut input: Vec<u8> = Vec::new();
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": URI, "text": text}},
+        });
+        for message in std::iter::once(&open).chain(requests) {
+            write_message(&mut input, message);
+        }
+        replies(input)
+    }
+
+    /// Serves `input` as it is, returning every message sent back.
+    fn replies(input: Vec<u8>) -> Vec<Value> {
+        let sent = SharedBuffer::default();
+        LspServer::init(Box::new(io::Cursor::new(input)), Box::new(sent.clone())).serve();
+        let bytes = sent.0.borrow().clone();
+        let mut reader = io::Cursor::new(bytes);
+        std::iter::from_fn(|| read_message(&mut reader).ok()).collect()
+    }
+
+    fn at(id: i64, method: &str, line: usize, character: usize) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+                "newName": "again",
+            },
+        })
+    }
+
+    fn messages(analysis: &Analysis) -> Vec<&str> {
+        analysis
+            .diagnostics
+            .iter()
+            .map(|d| d["message"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn malformed_messages_get_a_parse_error() {
+        let mut input: Vec<u8> = b"Content-Length: 3\r\n\r\n{]}".to_vec();
+        write_message(
+            &mut input,
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"}),
+        );
+        let sent = replies(input);
+        assert_eq!(sent[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(sent[0]["id"], Value::Null);
+        assert_eq!(sent[1]["id"], 1);
+    }
+
+    #[test]
+    fn diagnostics_match_the_assembler() {
+        let analysis = Analysis::init("mov 5, rax\njmp nowhere\nx: nop\nx: nop\n");
+        assert_eq!(
+            messages(&analysis),
+            [
+                "MOV expects a register or memory operand",
+                "undefined symbol 'nowhere'",
+                "label 'x' defined twice",
+            ]
+        );
+        let first = &analysis.diagnostics[0]["range"];
+        assert_eq!(first["start"], json!({"line": 0, "character": 4}));
+        assert_eq!(first["end"], json!({"line": 0, "character": 5}));
+        assert!(messages(&Analysis::init("loop: jmp loop\n")).is_empty());
+    }
+
+    #[test]
+    fn diagnostics_are_published_on_open() {
+        let sent = exchange("bogus rax\n", &[]);
+        assert_eq!(sent[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(sent[0]["params"]["uri"], URI);
+        assert_eq!(
+            sent[0]["params"]["diagnostics"][0]["message"],
+            "unknown mnemonic 'bogus'"
+        );
+    }
+
+    #[test]
+    fn hover_describes_mnemonics_and_labels() {
+        let sent = exchange(
+            "start:\n    malloc rax, 8\n    jmp start\n",
+            &[
+                at(1, "textDocument/hover", 1, 5),
+                at(2, "textDocument/hover", 2, 9),
+            ],
+        );
+        let malloc = sent[1]["result"]["contents"]["value"].as_str().unwrap();
+        assert!(malloc.contains("Not implemented yet"));
+        assert_eq!(
+            sent[2]["result"]["contents"]["value"],
+            "label `start`, defined on line 1"
+        );
+    }
+
+    #[test]
+    fn definition_references_and_rename_follow_labels() {
+        let sent = exchange(
+            "loop:\n    nop\n    jmp loop\n    jne loop\n",
+            &[
+                at(1, "textDocument/definition", 2, 9),
+                at(2, "textDocument/references", 0, 1),
+                at(3, "textDocument/rename", 3, 8),
+            ],
+        );
+        assert_eq!(
+            sent[1]["result"]["range"]["start"],
+            json!({"line": 0, "character": 0})
+        );
+        assert_eq!(sent[2]["result"].as_array().unwrap().len(), 3);
+        let edits = sent[3]["result"]["changes"][URI].as_array().unwrap();
+        let texts: Vec<&str> = edits
+            .iter()
+            .map(|e| e["newText"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, ["again:", "again", "again"]);
+    }
+}
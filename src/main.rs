@@ -1,82 +1,8 @@
-use crate::structures::interpreter::Interpreter;
-use crate::structures::parser::Parser;
-use crate::structures::structures::GeneralStructure;
-use crate::structures::tokenizer::Tokenizer;
-use std::io::Write;
 use std::process::exit;
-use std::{fs, io};
 
-pub mod structures;
-
-fn print_banner() {
-    println!(
-        "⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⣀⣀⣀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀
-⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⣀⡤⠖⠚⠉⠁⠀⠀⠉⠙⠒⢄⠀⠀⠀⠀⠀⠀
-⠀⠀⠀⠀⠀⠀⠀⢀⠔⠋⠁⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠉⢢⡀⠀⠀⠀
-⠀⠀⠀⠀⠀⠀⡰⠋⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠙⣆⠀⠀
-⠀⠀⠀⠀⠀⢠⠁⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠈⢇⠀
-⠀⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠘⡄
-⠀⠀⠀⠀⢸⠄⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠇
-⠀⠀⠀⠀⠸⡆⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀ ⠇
-⠀⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⡘       _________________________
-⠀⠀⠀⠀⠀⢻⠀⠀⠀⠀⠀⠀⠀⢀⣴⣶⡄⠀⠀⠀⠀⠀⢀⣶⡆⠀⢠⠇      /    They don't know     /
-⠀⠀⠀⠀⠀⠀⣣⠀⠀⠀⠀⠀⠀⠀⠙⠛⠁⠀⠀⠀⠀⠀⠈⠛⠁⡰⠃⠀     /  I made a CPU emulator /
-⠀⠀⠀⠀⢠⠞⠋⢳⢤⡀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⢀⠜⠁------/________________________/
-⠀⠀⠀⣰⠋⠀⠀⠀⢷⠙⠲⢤⣀⡀⠀⠀⠀⠀⠴⠴⣆⠴⠚⠁⠀⠀⠀⠀
-⠀⠀⣰⠃⠀⠀⠀⠀⠘⡇⠀⣀⣀⡉⠙⠒⠒⠒⡎⠉⠀⠀⠀⠀⠀⠀⠀⠀
-⠀⢠⠃⠀⠀⢶⠀⠀⠀⢳⠋⠁⠀⠙⢳⡠⠖⠚⠑⠲⡀⠀⠀⠀⠀⠀⠀⠀
-⠀⡎⠀⠀⠀⠘⣆⠀⠀⠈⢧⣀⣠⠔⡺⣧⠀⡴⡖⠦⠟⢣⠀⠀⠀⠀⠀⠀
-⢸⠀⠀⠀⠀⠀⢈⡷⣄⡀⠀⠀⠀⠀⠉⢹⣾⠁⠁⠀⣠⠎⠀⠀⠀⠀⠀⠀
-⠈⠀⠀⠀⠀⠀⡼⠆⠀⠉⢉⡝⠓⠦⠤⢾⠈⠓⠖⠚⢹⠀⠀⠀⠀⠀⠀⠀
-⢰⡀⠀⠀⠀⠀⠀⠀⠀⠀⢸⠁⠀⠀⠀⢸⠀⠀⠀⠀⡏⠀⠀⠀⠀⠀⠀⠀
-⠀⠳⡀⠀⠀⠀⠀⠀⠀⣀⢾⠀⠀⠀⠀⣾⠀⠀⠀⠀⡇⠀⠀⠀⠀⠀⠀⠀
-⠀⠀⠈⠐⠢⠤⠤⠔⠚⠁⠘⣆⠀⠀⢠⠋⢧⣀⣀⡼⠀⠀⠀⠀⠀⠀⠀⠀
-⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠀⠈⠉⠈⠁⠀⠀⠀⠁⠀⠀⠀⠀"
-    );
-}
-
-// fn interpreter() {
-//     let mut _buffer = String::new();
-//     let mut inter: GeneralStructure = GeneralStructure::init(Vec::new());
-//     let mut prefix = "> ";
-//     loop {
-//         let mut buf: String = String::new();
-//         io::stdout().write(prefix.as_bytes()).expect("Failed to write to stdout");
-//         io::stdout().flush().expect("Failed to flush to stdout");
-//         io::stdin().read_line(&mut buf).expect("Failed to read from stdin");
-//         if buf.len() == 1 {
-//             continue;
-//         }
-//         buf = buf[0..buf.len() - 2].to_string();
-//         if buf == "exit" {
-//             exit(0);
-//         }
-//         if buf.len() == 0 {
-//             _buffer.push('\n');
-//             let mut parse: Parser = Parser::init(String::from(&_buffer));
-//             parse.parse();
-//             let mut tokens =  Tokenizer::init(parse);
-//             let interpreted = Interpreter::interpret(tokens.tokenize());
-//             inter.next(interpreted);
-//             _buffer = String::new();
-//             prefix = "> "
-//         } else {
-//             prefix = "... ";
-//             _buffer.push('\n');
-//             _buffer.push_str(&buf.as_str());
-//         }
-//     }
-// }
+mod cli;
 
 fn main() {
-    print_banner();
-    let args: Vec<String> = std::env::args().collect();
-    let input = fs::read_to_string(&args[1]).expect("Panix");
-    let mut parse: Parser = Parser::init(String::from(input));
-    parse.parse();
-    let mut tokens = Tokenizer::init(parse);
-    let interpreted = Interpreter::interpret(tokens.tokenize());
-    let mut r = GeneralStructure::init(interpreted);
-    r.run();
-    //interpreter();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    exit(cli::main(&args));
 }
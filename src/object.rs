@@ -0,0 +1,252 @@
+use crate::structures::data_types::{DataType, GeneralData};
+use crate::structures::flow_structure::{FlowStructure, OpCode};
+use crate::structures::interpreter::{Program, Reference, Site};
+use crate::structures::registers::Register;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+
+/// Assembled programs as written by `vcpu asm`: this magic, then the code,
+/// the data, the labels and the label references, all little-endian. The
+/// last byte of the magic is the format version.
+const MAGIC: &[u8; 5] = b"VCPU\x01";
+
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC[..4])
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend(bytes);
+}
+
+fn put_data(out: &mut Vec<u8>, data: &GeneralData) {
+    out.push(data.data_type() as u8);
+    match data {
+        GeneralData::Uint32(_) | GeneralData::Int32(_) | GeneralData::Float(_) => {
+            put_u32(out, data.to_bits() as u32)
+        }
+        GeneralData::Char(c) => put_u32(out, *c as u32),
+        GeneralData::Uint64(_) | GeneralData::Int64(_) | GeneralData::Double(_) => {
+            out.extend(data.to_bits().to_le_bytes())
+        }
+        GeneralData::String(text) => put_bytes(out, text.as_bytes()),
+        GeneralData::Register(reg) => out.push(*reg as u8),
+        GeneralData::Memory(base, disp) => {
+            out.push(*base as u8);
+            out.extend(disp.to_le_bytes());
+        }
+    }
+}
+
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+
+    put_u32(&mut out, program.code.len() as u32);
+    for istr in &program.code {
+        out.push(istr.op_code as u8);
+        put_u32(&mut out, istr.line as u32);
+        out.push(istr.arguments.len() as u8);
+        for arg in &istr.arguments {
+            put_data(&mut out, arg);
+        }
+    }
+    put_bytes(&mut out, &program.data);
+
+    // Sorted so the same program always gives the same bytes.
+    let mut labels: Vec<(&String, &usize)> = program.labels.iter().collect();
+    labels.sort();
+    put_u32(&mut out, labels.len() as u32);
+    for (name, pos) in labels {
+        put_bytes(&mut out, name.as_bytes());
+        put_u32(&mut out, *pos as u32);
+    }
+
+    put_u32(&mut out, program.references.len() as u32);
+    for reference in &program.references {
+        put_bytes(&mut out, reference.label.as_bytes());
+        match reference.site {
+            Site::Operand(pc, arg) => {
+                out.push(0);
+                put_u32(&mut out, pc as u32);
+                put_u32(&mut out, arg as u32);
+            }
+            Site::Data(offset) => {
+                out.push(1);
+                put_u32(&mut out, offset as u32);
+            }
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos + n;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or("truncated object file")?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| String::from("bad string"))
+    }
+
+    fn register(&mut self) -> Result<Register, String> {
+        let id = self.u8()?;
+        FromPrimitive::from_u8(id).ok_or(format!("bad register {}", id))
+    }
+
+    fn data(&mut self) -> Result<GeneralData, String> {
+        let tag = self.u8()?;
+        let t: DataType = FromPrimitive::from_u8(tag).ok_or(format!("bad type {}", tag))?;
+        Ok(match t {
+            DataType::Uint32 => GeneralData::Uint32(self.u32()?),
+            DataType::Uint64 => GeneralData::Uint64(self.u64()?),
+            DataType::Int32 => GeneralData::Int32(self.u32()? as i32),
+            DataType::Int64 => GeneralData::Int64(self.u64()? as i64),
+            DataType::Float => GeneralData::Float(f32::from_bits(self.u32()?)),
+            DataType::Double => GeneralData::Double(f64::from_bits(self.u64()?)),
+            DataType::String => GeneralData::from(self.string()?.as_str()),
+            DataType::Char => {
+                let code = self.u32()?;
+                GeneralData::Char(char::from_u32(code).ok_or(format!("bad char {}", code))?)
+            }
+            DataType::Register => GeneralData::Register(self.register()?),
+            DataType::Memory => {
+                let base = self.register()?;
+                GeneralData::Memory(base, self.u64()? as i64)
+            }
+        })
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    if !is_object(bytes) {
+        return Err(String::from("not a vcpu object file"));
+    }
+    if bytes.get(MAGIC.len() - 1) != MAGIC.last() {
+        return Err(format!(
+            "object file version {} is not supported, reassemble it",
+            bytes.get(MAGIC.len() - 1).copied().unwrap_or(0)
+        ));
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+
+    let count = reader.u32()?;
+    let mut code: Vec<FlowStructure> = Vec::new();
+    for _ in 0..count {
+        let id = reader.u8()?;
+        let op_code: OpCode = match FromPrimitive::from_u8(id) {
+            Some(OpCode::COUNT) | None => return Err(format!("bad opcode {}", id)),
+            Some(op) => op,
+        };
+        let line = reader.u32()? as usize;
+        let argc = reader.u8()?;
+        let arguments = (0..argc)
+            .map(|_| reader.data())
+            .collect::<Result<Vec<GeneralData>, String>>()?;
+        code.push(FlowStructure {
+            op_code,
+            arguments,
+            line,
+        });
+    }
+    let data = reader.bytes()?.to_vec();
+
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        labels.insert(name, reader.u32()? as usize);
+    }
+
+    let mut references: Vec<Reference> = Vec::new();
+    for _ in 0..reader.u32()? {
+        let label = reader.string()?;
+        let site = match reader.u8()? {
+            0 => Site::Operand(reader.u32()? as usize, reader.u32()? as usize),
+            1 => Site::Data(reader.u32()? as usize),
+            tag => return Err(format!("bad reference site {}", tag)),
+        };
+        references.push(Reference { label, site });
+    }
+    Ok(Program {
+        code,
+        data,
+        labels,
+        references,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+    use crate::structures::interpreter::Program;
+    use crate::test_support::assemble;
+
+    #[test]
+    fn round_trip() {
+        let program = assemble(
+            "start: mov rax, 1.5\nmov rbx, [rsp-8]\nlea rcx, start\npush 'x'\n\
+             hlt 0\nmsg: db \"hi\", 0\nptr: dq start\n",
+        );
+        let bytes = encode(&program);
+        let decoded = decode(&bytes).unwrap();
+        let shown = |program: &Program| -> Vec<(String, usize)> {
+            program
+                .code
+                .iter()
+                .map(|istr| (istr.to_string(), istr.line))
+                .collect()
+        };
+        assert_eq!(shown(&decoded), shown(&program));
+        assert_eq!(decoded.data, program.data);
+        assert_eq!(decoded.labels, program.labels);
+        assert_eq!(decoded.references, program.references);
+        assert_eq!(encode(&decoded), bytes);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert_eq!(decode(b"ELF").err().unwrap(), "not a vcpu object file");
+        assert_eq!(
+            decode(b"VCPU\x00\0\0\0\0").err().unwrap(),
+            "object file version 0 is not supported, reassemble it"
+        );
+        assert_eq!(
+            decode(b"VCPU\x01\x01\0").err().unwrap(),
+            "truncated object file"
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use crate::cfg::Cfg;
+use crate::structures::data_types::GeneralData;
+use crate::structures::flow_structure::{FlowStructure, OpCode};
+use crate::structures::interpreter::{Program, Site};
+use crate::structures::registers::Register;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+const REGISTERS: usize = Register::NIL as usize;
+
+/// One rewrite, at the source line of the instruction it touched.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+/// What `optimize` did.
+pub struct Report {
+    pub changes: Vec<Change>,
+    /// Instruction counts before and after.
+    pub before: usize,
+    pub after: usize,
+}
+
+struct Pass<'a> {
+    code: &'a mut [FlowStructure],
+    removed: Vec<bool>,
+    changes: &'a mut Vec<Change>,
+}
+
+impl Pass<'_> {
+    fn note(&mut self, pc: usize, message: String) {
+        self.changes.push(Change {
+            line: self.code[pc].line,
+            message,
+        });
+    }
+
+    fn remove(&mut self, pc: usize, why: &str) {
+        self.removed[pc] = true;
+        let message = format!("removed {}, {}", self.code[pc], why);
+        self.note(pc, message);
+    }
+
+    /// The first instruction from `pc` on that is still there.
+    fn next_kept(&self, mut pc: usize) -> usize {
+        while pc < self.code.len() && self.removed[pc] {
+            pc += 1;
+        }
+        pc
+    }
+}
+
+/// `a + b` as ADD computes it for numbers and chars, `None` otherwise.
+fn fold_add(a: &GeneralData, b: &GeneralData) -> Option<GeneralData> {
+    use GeneralData::*;
+    Some(match (a, b) {
+        (Uint32(a), Uint32(b)) => Uint32(a.wrapping_add(*b)),
+        (Uint64(a), Uint64(b)) => Uint64(a.wrapping_add(*b)),
+        (Int32(a), Int32(b)) => Int32(a.wrapping_add(*b)),
+        (Int64(a), Int64(b)) => Int64(a.wrapping_add(*b)),
+        (Float(a), Float(b)) => Float(a + b),
+        (Double(a), Double(b)) => Double(a + b),
+        (Char(a), Char(b)) => Char((*a as u8).wrapping_add(*b as u8) as char),
+        _ => return None,
+    })
+}
+
+fn is_immediate(arg: &GeneralData) -> bool {
+    !matches!(arg, GeneralData::Register(_) | GeneralData::Memory(..))
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(op, OpCode::JMP | OpCode::JE | OpCode::JNE | OpCode::CALL)
+}
+
+/// Registers `istr` reads, counting a conditional write as a read.
+fn reads(istr: &FlowStructure) -> Vec<usize> {
+    let mut regs: Vec<usize> = Vec::new();
+    let written = written(istr);
+    for (i, arg) in istr.arguments.iter().enumerate() {
+        match arg {
+            GeneralData::Register(reg) if !(i == 0 && written.is_some()) => {
+                regs.push(*reg as usize)
+            }
+            GeneralData::Memory(base, _) if *base != Register::NIL => regs.push(*base as usize),
+            _ => {}
+        }
+    }
+    if istr.op_code == OpCode::SYSCALL {
+        regs.extend(
+            [Register::RAX, Register::RDI, Register::RSI, Register::RDX].map(|r| r as usize),
+        );
+    }
+    regs
+}
+
+/// The register `istr` overwrites whatever it held, if any.
+fn written(istr: &FlowStructure) -> Option<usize> {
+    match (istr.op_code, istr.arguments.first()) {
+        (OpCode::MOV | OpCode::LEA | OpCode::POP, Some(GeneralData::Register(reg))) => {
+            Some(*reg as usize)
+        }
+        _ => None,
+    }
+}
+
+/// Folds ADDs on registers holding known constants into MOVs, and drops
+/// `MOV r, r`. Constants are only followed within a block.
+fn fold(pass: &mut Pass, cfg: &Cfg) {
+    for block in &cfg.blocks {
+        let mut known: [Option<GeneralData>; REGISTERS] = Default::default();
+        for pc in block.start..block.end {
+            if pass.removed[pc] {
+                continue;
+            }
+            let istr = &pass.code[pc];
+            match (istr.op_code, istr.arguments.as_slice()) {
+                (OpCode::MOV, [GeneralData::Register(dst), GeneralData::Register(src)])
+                    if dst == src =>
+                {
+                    pass.remove(pc, "it moves a register onto itself");
+                    continue;
+                }
+                (OpCode::ADD, [GeneralData::Register(dst), src]) => {
+                    let value = match src {
+                        GeneralData::Register(reg) => known[*reg as usize].clone(),
+                        src if is_immediate(src) => Some(src.clone()),
+                        _ => None,
+                    };
+                    let folded = match (&known[*dst as usize], value) {
+                        (Some(a), Some(b)) => fold_add(a, &b),
+                        _ => None,
+                    };
+                    if let Some(folded) = folded {
+                        let before = istr.to_string();
+                        let dst = *dst;
+                        pass.code[pc].op_code = OpCode::MOV;
+                        pass.code[pc].arguments = vec![GeneralData::Register(dst), folded];
+                        let message = format!("folded {} into {}", before, pass.code[pc]);
+                        pass.note(pc, message);
+                    }
+                }
+                _ => {}
+            }
+
+            let istr = &pass.code[pc];
+            let value = match (istr.op_code, istr.arguments.as_slice()) {
+                (OpCode::MOV, [GeneralData::Register(_), GeneralData::Register(src)]) => {
+                    known[*src as usize].clone()
+                }
+                (OpCode::MOV, [GeneralData::Register(_), src]) if is_immediate(src) => {
+                    Some(src.clone())
+                }
+                _ => None,
+            };
+            match (istr.op_code, istr.arguments.first()) {
+                (
+                    OpCode::MOV
+                    | OpCode::LEA
+                    | OpCode::POP
+                    | OpCode::ADD
+                    | OpCode::SUB
+                    | OpCode::MUL
+                    | OpCode::DIV
+                    | OpCode::MOD
+                    | OpCode::INC
+                    | OpCode::OR
+                    | OpCode::AND
+                    | OpCode::XOR
+                    | OpCode::STDIN
+                    | OpCode::GETC,
+                    Some(GeneralData::Register(dst)),
+                ) => known[*dst as usize] = value,
+                (OpCode::SYSCALL, _) => known[Register::RAX as usize] = None,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Points jumps that land on a JMP straight at where that JMP goes.
+fn thread(pass: &mut Pass) {
+    for pc in 0..pass.code.len() {
+        let istr = &pass.code[pc];
+        let mut target = match istr.arguments.first() {
+            Some(GeneralData::Int64(target)) if !pass.removed[pc] && is_jump(istr.op_code) => {
+                *target
+            }
+            _ => continue,
+        };
+        let start = target;
+        let mut seen: BTreeSet<i64> = BTreeSet::from([target]);
+        let mut looped = false;
+        while let Some(next) = usize::try_from(target)
+            .ok()
+            .map(|t| pass.next_kept(t))
+            .and_then(|t| pass.code.get(t))
+        {
+            match (next.op_code, next.arguments.first()) {
+                (OpCode::JMP, Some(GeneralData::Int64(next))) => {
+                    target = *next;
+                    if !seen.insert(target) {
+                        looped = true;
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        // A chain of JMPs going round forever is left alone.
+        if target != start && !looped {
+            let before = pass.code[pc].to_string();
+            pass.code[pc].arguments[0] = GeneralData::Int64(target);
+            let message = format!("threaded {} to {}", before, pass.code[pc]);
+            pass.note(pc, message);
+        }
+    }
+}
+
+/// Drops jumps to the instruction that runs next anyway.
+fn branch_to_next(pass: &mut Pass) {
+    for pc in 0..pass.code.len() {
+        let istr = &pass.code[pc];
+        if pass.removed[pc] || !matches!(istr.op_code, OpCode::JMP | OpCode::JE | OpCode::JNE) {
+            continue;
+        }
+        if let Some(GeneralData::Int64(target)) = istr.arguments.first() {
+            let next = pass.next_kept(pc + 1);
+            if usize::try_from(*target).is_ok_and(|t| pass.next_kept(t) == next) {
+                pass.remove(pc, "it jumps to the next instruction");
+            }
+        }
+    }
+}
+
+/// Drops MOVs and LEAs into registers that are overwritten before being
+/// read again in the same block. Every register is live as a block ends.
+fn dead_stores(pass: &mut Pass, cfg: &Cfg) {
+    for block in &cfg.blocks {
+        let mut live = [true; REGISTERS];
+        for pc in (block.start..block.end).rev() {
+            if pass.removed[pc] {
+                continue;
+            }
+            let istr = &pass.code[pc];
+            let pure = match (istr.op_code, istr.arguments.as_slice()) {
+                (OpCode::MOV, [GeneralData::Register(_), src]) => {
+                    !matches!(src, GeneralData::Memory(..))
+                }
+                (OpCode::LEA, [GeneralData::Register(_), GeneralData::Int64(_)]) => true,
+                _ => false,
+            };
+            let written = written(istr);
+            if let Some(reg) = written.filter(|reg| pure && !live[*reg]) {
+                let why = format!("{:?} is overwritten before it is read", reg_name(reg));
+                pass.remove(pc, &why);
+                continue;
+            }
+            if let Some(reg) = written {
+                live[reg] = false;
+            }
+            for reg in reads(istr) {
+                live[reg] = true;
+            }
+        }
+    }
+}
+
+fn reg_name(id: usize) -> Register {
+    num_traits::FromPrimitive::from_usize(id).unwrap_or(Register::NIL)
+}
+
+/// Whether the program may hold the index of an instruction as a value,
+/// by jumping through a register or memory or by using a label other
+/// than as a jump target. Then instructions cannot move and removed ones
+/// are left as NOPs.
+fn addresses_escape(program: &Program) -> bool {
+    let indirect = program.code.iter().any(|istr| {
+        is_jump(istr.op_code) && !matches!(istr.arguments.first(), Some(GeneralData::Int64(_)))
+    });
+    let loaded = program
+        .references
+        .iter()
+        .any(|reference| match reference.site {
+            Site::Operand(pc, arg) => arg != 0 || !is_jump(program.code[pc].op_code),
+            Site::Data(_) => true,
+        });
+    indirect || loaded
+}
+
+/// Takes the removed instructions out, renumbering jump targets and
+/// labels, or turns them into NOPs when addresses must stay put.
+fn compact(program: &mut Program, removed: &[bool], keep_addresses: bool) {
+    if keep_addresses {
+        for (istr, removed) in program.code.iter_mut().zip(removed) {
+            if *removed {
+                istr.op_code = OpCode::NOP;
+                istr.arguments.clear();
+            }
+        }
+        program.references.retain(|reference| match reference.site {
+            Site::Operand(pc, _) => !removed[pc],
+            Site::Data(_) => true,
+        });
+        return;
+    }
+
+    // A removed instruction's index now names the one after it.
+    let mut index: Vec<usize> = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for removed in removed {
+        index.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    index.push(kept);
+    let remap = |pc: i64| match usize::try_from(pc) {
+        Ok(pc) if pc < index.len() => index[pc] as i64,
+        _ => pc,
+    };
+
+    let code = std::mem::take(&mut program.code);
+    for (mut istr, removed) in code.into_iter().zip(removed) {
+        if *removed {
+            continue;
+        }
+        if is_jump(istr.op_code) {
+            if let Some(GeneralData::Int64(target)) = istr.arguments.first_mut() {
+                *target = remap(*target);
+            }
+        }
+        program.code.push(istr);
+    }
+    for pos in program.labels.values_mut() {
+        *pos = remap(*pos as i64) as usize;
+    }
+    program
+        .references
+        .retain_mut(|reference| match &mut reference.site {
+            Site::Operand(pc, _) if removed[*pc] => false,
+            Site::Operand(pc, _) => {
+                *pc = index[*pc];
+                true
+            }
+            Site::Data(_) => true,
+        });
+}
+
+/// Rewrites `program` until none of the rewrites apply any more.
+pub fn optimize(mut program: Program) -> (Program, Report) {
+    let before = program.code.len();
+    let keep_addresses = addresses_escape(&program);
+    let mut changes: Vec<Change> = Vec::new();
+    loop {
+        let cfg = Cfg::build(&program);
+        let count = changes.len();
+        let mut pass = Pass {
+            removed: vec![false; program.code.len()],
+            code: &mut program.code,
+            changes: &mut changes,
+        };
+        fold(&mut pass, &cfg);
+        thread(&mut pass);
+        branch_to_next(&mut pass);
+        dead_stores(&mut pass, &cfg);
+        let removed = pass.removed;
+        changes[count..].sort_by_key(|change| change.line);
+        compact(&mut program, &removed, keep_addresses);
+        if changes.len() == count {
+            break;
+        }
+    }
+    let report = Report {
+        changes,
+        before,
+        after: program.code.len(),
+    };
+    (program, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::structures::interpreter::Program;
+    use crate::test_support::assemble;
+
+    fn optimized(source: &str) -> (Program, Vec<String>) {
+        let program = assemble(source);
+        let (program, _) = optimize(program);
+        let code = program.code.iter().map(|istr| istr.to_string()).collect();
+        (program, code)
+    }
+
+    #[test]
+    fn jumps_and_labels_are_remapped() {
+        let (program, code) = optimized(
+            "mov rax, 1\nmov rax, 2\njmp next\nnext: cmp rax, 2\njne done\n\
+             mov rbx, rbx\ncall done\ndone: hlt\n",
+        );
+        assert_eq!(code, ["MOV RAX, 2", "CMP RAX, 2", "JNE 4", "CALL 4", "HLT"]);
+        assert_eq!(program.labels["next"], 1);
+        assert_eq!(program.labels["done"], 4);
+    }
+
+    #[test]
+    fn folds_and_threads() {
+        let (_, code) = optimized("mov rax, 1\nadd rax, 2\njmp a\na: jmp b\nb: push rax\nhlt\n");
+        assert_eq!(code, ["MOV RAX, 3", "PUSH RAX", "HLT"]);
+    }
+
+    #[test]
+    fn loaded_labels_keep_addresses() {
+        let (_, code) = optimized("lea rax, done\njmp next\nnext: mov rbx, 1\ndone: hlt\n");
+        assert_eq!(code, ["LEA RAX, 3", "NOP", "MOV RBX, 1", "HLT"]);
+        let (_, code) = optimized("jmp next\nnext: hlt\nptr: dq next\n");
+        assert_eq!(code, ["NOP", "HLT"]);
+    }
+
+    #[test]
+    fn numbers_equal_to_a_label_do_not_keep_addresses() {
+        // 2 is the index of `done` but is not an address.
+        let (_, code) = optimized("mov rax, 2\njmp done\ndone: push rax\nhlt\nn: dq 2\n");
+        assert_eq!(code, ["MOV RAX, 2", "PUSH RAX", "HLT"]);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::structures::env_vars::VmError;
+use crate::structures::flow_structure::OpCode;
+use crate::structures::interpreter::Program;
+use crate::structures::structures::GeneralStructure;
+use std::collections::HashMap;
+
+/// Name given to code before the first label.
+const START: &str = "<start>";
+
+/// Rows shown in each table of the report.
+const HOT_SPOTS: usize = 20;
+
+/// A function being run, entered by a CALL or at the start.
+struct Frame {
+    /// The CALL that entered it, `None` for the outermost frame.
+    site: Option<usize>,
+    function: usize,
+    /// Instructions executed when it was entered.
+    start: u64,
+}
+
+/// Counts executions per instruction and per label while a program runs,
+/// following CALL and RET to attribute the cost of callees.
+pub struct Profiler {
+    /// Label names, a function being everything from its label to the
+    /// next one.
+    names: Vec<String>,
+    /// The function each instruction belongs to.
+    function_of: Vec<usize>,
+    lines: Vec<usize>,
+    text: Vec<String>,
+    steps: u64,
+    /// Times each instruction ran.
+    counts: Vec<u64>,
+    /// `counts`, plus for CALLs everything run until the callee returned.
+    totals: Vec<u64>,
+    self_costs: Vec<u64>,
+    total_costs: Vec<u64>,
+    /// Frames open per call site and per function, so that recursion
+    /// is only charged once to each.
+    open_sites: Vec<u32>,
+    open_functions: Vec<u32>,
+    frames: Vec<Frame>,
+    /// The call stacks seen, as a tree of (parent, function) nodes.
+    nodes: Vec<(usize, usize)>,
+    children: HashMap<(usize, usize), usize>,
+    node_counts: Vec<u64>,
+    node: usize,
+}
+
+impl Profiler {
+    pub fn init(program: &Program) -> Self {
+        let len = program.code.len();
+        let mut labels: Vec<(&String, &usize)> = program.labels.iter().collect();
+        labels.sort_by_key(|(name, pos)| (**pos, *name));
+        labels.dedup_by_key(|(_, pos)| **pos);
+
+        let mut names: Vec<String> = Vec::new();
+        if labels.first().is_none_or(|(_, pos)| **pos > 0) {
+            names.push(String::from(START));
+        }
+        let mut function_of: Vec<usize> = vec![0; len];
+        for (name, pos) in &labels {
+            if **pos < len {
+                function_of[**pos..].fill(names.len());
+                names.push((*name).clone());
+            }
+        }
+        if names.is_empty() {
+            names.push(String::from(START));
+        }
+
+        let root = function_of.first().copied().unwrap_or(0);
+        let mut open_functions = vec![0; names.len()];
+        open_functions[root] = 1;
+        Profiler {
+            lines: program.code.iter().map(|istr| istr.line).collect(),
+            text: program.code.iter().map(|istr| istr.to_string()).collect(),
+            steps: 0,
+            counts: vec![0; len],
+            totals: vec![0; len],
+            self_costs: vec![0; names.len()],
+            total_costs: vec![0; names.len()],
+            open_sites: vec![0; len],
+            open_functions,
+            frames: vec![Frame {
+                site: None,
+                function: root,
+                start: 0,
+            }],
+            nodes: vec![(0, root)],
+            children: HashMap::new(),
+            node_counts: vec![0],
+            node: 0,
+            names,
+            function_of,
+        }
+    }
+
+    /// Executes one instruction of `vm`, counting it.
+    pub fn step(&mut self, vm: &mut GeneralStructure) -> Result<(), VmError> {
+        let pc = vm.pc();
+        let op = vm.flow()[pc].op_code;
+        self.steps += 1;
+        self.counts[pc] += 1;
+        self.totals[pc] += 1;
+        self.self_costs[self.function_of[pc]] += 1;
+        self.node_counts[self.node] += 1;
+
+        vm.step()?;
+        match op {
+            OpCode::CALL if !vm.finished() => self.enter(pc, self.function_of[vm.pc()]),
+            OpCode::RET if self.frames.len() > 1 => self.leave(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, site: usize, function: usize) {
+        self.open_sites[site] += 1;
+        self.open_functions[function] += 1;
+        self.frames.push(Frame {
+            site: Some(site),
+            function,
+            start: self.steps,
+        });
+        let parent = self.node;
+        let next = self.nodes.len();
+        self.node = *self.children.entry((parent, function)).or_insert(next);
+        if self.node == next {
+            self.nodes.push((parent, function));
+            self.node_counts.push(0);
+        }
+    }
+
+    fn leave(&mut self) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        let elapsed = self.steps - frame.start;
+        self.open_functions[frame.function] -= 1;
+        if self.open_functions[frame.function] == 0 {
+            self.total_costs[frame.function] += elapsed;
+        }
+        if let Some(site) = frame.site {
+            self.open_sites[site] -= 1;
+            if self.open_sites[site] == 0 {
+                self.totals[site] += elapsed;
+            }
+        }
+        self.node = self.nodes[self.node].0;
+    }
+
+    /// Closes the frames still open when the program stopped.
+    pub fn finish(&mut self) {
+        while !self.frames.is_empty() {
+            self.leave();
+        }
+    }
+
+    /// The busiest instructions and functions, busiest first.
+    pub fn report(&self) -> String {
+        let percent = |n: u64| 100.0 * n as f64 / self.steps.max(1) as f64;
+        let mut out = format!("{} instructions executed\n\nhot spots:\n", self.steps);
+        out.push_str(&format!(
+            "{:>12} {:>12} {:>7}  {:>6}  instruction\n",
+            "count", "total", "%", "line"
+        ));
+        let mut pcs: Vec<usize> = (0..self.counts.len())
+            .filter(|pc| self.counts[*pc] > 0)
+            .collect();
+        pcs.sort_by_key(|pc| (std::cmp::Reverse(self.counts[*pc]), *pc));
+        for pc in pcs.into_iter().take(HOT_SPOTS) {
+            out.push_str(&format!(
+                "{:>12} {:>12} {:>6.2}%  {:>6}  {} ({})\n",
+                self.counts[pc],
+                self.totals[pc],
+                percent(self.counts[pc]),
+                self.lines[pc],
+                self.text[pc],
+                self.names[self.function_of[pc]]
+            ));
+        }
+
+        out.push_str(&format!(
+            "\nfunctions:\n{:>12} {:>12} {:>7}  name\n",
+            "self", "total", "%"
+        ));
+        // Labels never called are charged for the calls made from them.
+        let mut totals = self.total_costs.clone();
+        for (pc, function) in self.function_of.iter().enumerate() {
+            if self.total_costs[*function] == 0 {
+                totals[*function] += self.totals[pc] - self.counts[pc];
+            }
+        }
+        for (id, total) in totals.iter_mut().enumerate() {
+            if self.total_costs[id] == 0 {
+                *total += self.self_costs[id];
+            }
+        }
+        let mut functions: Vec<usize> =
+            (0..self.names.len()).filter(|id| totals[*id] > 0).collect();
+        functions.sort_by_key(|id| (std::cmp::Reverse(self.self_costs[*id]), *id));
+        for id in functions.into_iter().take(HOT_SPOTS) {
+            out.push_str(&format!(
+                "{:>12} {:>12} {:>6.2}%  {}\n",
+                self.self_costs[id],
+                totals[id],
+                percent(self.self_costs[id]),
+                self.names[id]
+            ));
+        }
+        out
+    }
+
+    /// One `outer;inner count` line per call stack, as flamegraph.pl and
+    /// inferno read.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for (id, count) in self.node_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let mut stack: Vec<&str> = Vec::new();
+            let mut node = id;
+            loop {
+                stack.push(&self.names[self.nodes[node].1]);
+                if node == 0 {
+                    break;
+                }
+                node = self.nodes[node].0;
+            }
+            stack.reverse();
+            lines.push(format!("{} {}", stack.join(";"), count));
+        }
+        lines.sort();
+        let mut out = lines.join("\n");
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profiler;
+    use crate::structures::structures::GeneralStructure;
+    use crate::test_support::assemble;
+
+    fn profile(source: &str) -> Profiler {
+        let program = assemble(source);
+        let mut profiler = Profiler::init(&program);
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        while !vm.finished() {
+            profiler.step(&mut vm).unwrap();
+        }
+        profiler.finish();
+        profiler
+    }
+
+    const CALLS: &str = "mov rcx, 0\ncall f\ncall f\nhlt\nf: call g\nret\ng: nop\nret\n";
+
+    #[test]
+    fn folded_stacks() {
+        assert_eq!(
+            profile(CALLS).folded(),
+            "<start> 4\n<start>;f 4\n<start>;f;g 4\n"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let report = profile(CALLS).report();
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some("12 instructions executed"));
+        let rows: Vec<Vec<&str>> = report
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert!(rows.contains(&vec!["1", "5", "8.33%", "2", "CALL", "4", "(<start>)"]));
+        assert!(rows.contains(&vec!["2", "6", "16.67%", "5", "CALL", "6", "(f)"]));
+        assert!(rows.contains(&vec!["4", "12", "33.33%", "<start>"]));
+        assert!(rows.contains(&vec!["4", "8", "33.33%", "f"]));
+        assert!(rows.contains(&vec!["4", "4", "33.33%", "g"]));
+    }
+
+    #[test]
+    fn recursion_is_charged_once() {
+        let profiler = profile(
+            "mov rax, 3\ncall down\nhlt\ndown: cmp rax, 0\nje out\nsub rax, 1\ncall down\n\
+             out: ret\n",
+        );
+        let rows: Vec<Vec<String>> = profiler
+            .report()
+            .lines()
+            .map(|line| line.split_whitespace().map(String::from).collect())
+            .collect();
+        let down = rows
+            .iter()
+            .find(|row| row.last().is_some_and(|n| n == "down"));
+        // Four calls of CMP and JE, three of SUB and CALL and four RETs,
+        // all charged to the outermost call.
+        assert_eq!(down.unwrap()[1], "18");
+    }
+}
@@ -0,0 +1,119 @@
+use serde_json::Value;
+use std::cell::RefCell;
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+/// A writer whose bytes stay readable through every clone, for output
+/// that is forwarded or inspected later.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// What was written so far, as text.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Why `read_message` has no message.
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+    /// The input ended or its framing is broken, nothing more can be read.
+    Closed,
+    /// A whole frame arrived but its body is not JSON. Reading can go on
+    /// with the next frame.
+    Parse(String),
+}
+
+/// Reads one `Content-Length` framed JSON message, as used by both DAP and
+/// LSP.
+pub fn read_message(reader: &mut dyn BufRead) -> Result<Value, ReadError> {
+    let mut length: usize = 0;
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header) {
+            Ok(n) if n > 0 => {}
+            _ => return Err(ReadError::Closed),
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().map_err(|_| ReadError::Closed)?;
+        }
+    }
+    let mut body = vec![0u8; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| ReadError::Closed)?;
+
+    serde_json::from_slice(&body).map_err(|e| ReadError::Parse(e.to_string()))
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Value) {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .expect("Failed to write to client");
+    writer.flush().expect("Failed to flush to client");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn messages_round_trip() {
+        let mut buffer: Vec<u8> = Vec::new();
+        write_message(&mut buffer, &json!({"id": 1, "text": "é"}));
+        write_message(&mut buffer, &json!([]));
+        assert!(buffer.starts_with(b"Content-Length: 20\r\n\r\n{"));
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader), Ok(json!({"id": 1, "text": "é"})));
+        assert_eq!(read_message(&mut reader), Ok(json!([])));
+        assert_eq!(read_message(&mut reader), Err(ReadError::Closed));
+    }
+
+    #[test]
+    fn other_headers_are_ignored() {
+        let frame = "Content-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(read_message(&mut Cursor::new(frame)), Ok(json!({})));
+    }
+
+    #[test]
+    fn malformed_frames_end_the_stream() {
+        assert_eq!(
+            read_message(&mut Cursor::new("Content-Length: x\r\n\r\n{}")),
+            Err(ReadError::Closed)
+        );
+        assert_eq!(
+            read_message(&mut Cursor::new("Content-Length: 9\r\n\r\n{}")),
+            Err(ReadError::Closed)
+        );
+    }
+
+    #[test]
+    fn bad_json_skips_only_its_frame() {
+        let mut reader = Cursor::new("Content-Length: 3\r\n\r\n{]}Content-Length: 2\r\n\r\n{}");
+        assert!(matches!(
+            read_message(&mut reader),
+            Err(ReadError::Parse(_))
+        ));
+        assert_eq!(read_message(&mut reader), Ok(json!({})));
+    }
+}
@@ -1,8 +1,7 @@
-mod registers {
+pub mod registers {
     use num_derive::FromPrimitive;
-    use num_traits::FromPrimitive;
 
-    #[derive(Clone, Copy, Debug, FromPrimitive)]
+    #[derive(Clone, Copy, Debug, PartialEq, FromPrimitive)]
     pub enum Register {
         RAX,
         RBX,
@@ -16,243 +15,218 @@ mod registers {
     }
 
     impl Register {
-        pub fn is_reg(name: &String) -> bool {
-            let archm: Vec<&str> = vec!["R", "E", ""];
-            let regs: Vec<&str> = vec!["AX", "BX", "CX", "DX", "SI", "DI", "SP", "BP"];
-            let mut reg_table: Vec<String> = Vec::with_capacity(archm.len() * regs.len());
-            for reg in regs {
-                for &modi in &archm {
-                    reg_table.push(format!("{}{}", modi, reg));
-                }
-            }
-
-            reg_table.contains(&name.to_uppercase())
+        pub fn is_reg(name: &str) -> bool {
+            Register::from_string(name).is_some()
         }
 
-        pub fn from_string(name: &String) -> Option<Register> {
-            let archm: Vec<&str> = vec!["R", "E", ""];
-            let regs: Vec<&str> = vec!["AX", "BX", "CX", "DX", "SI", "DI", "SP", "BP"];
-            let mut reg_table: Vec<String> = Vec::with_capacity(archm.len() * regs.len());
-            for reg in regs {
-                for &modi in &archm {
-                    reg_table.push(format!("{}{}", modi, reg));
-                }
-            }
-            let p: String = name.to_uppercase();
-            let i = reg_table.iter().position(|ins| &p == ins);
-            match i {
-                Some(idx) => FromPrimitive::from_usize(idx / archm.len()),
-                None => None,
+        /// Case-insensitive, `RAX`, `EAX` and `AX` all name `RAX`.
+        pub fn from_string(name: &str) -> Option<Register> {
+            let mut buf = [0u8; KEYWORD_LEN];
+            let name = upper_keyword(name, &mut buf)?;
+            let base = match name {
+                [b'R' | b'E', rest @ ..] if rest.len() == 2 => rest,
+                _ => name,
+            };
+            match base {
+                b"AX" => Some(Register::RAX),
+                b"BX" => Some(Register::RBX),
+                b"CX" => Some(Register::RCX),
+                b"DX" => Some(Register::RDX),
+                b"SI" => Some(Register::RSI),
+                b"DI" => Some(Register::RDI),
+                b"SP" => Some(Register::RSP),
+                b"BP" => Some(Register::RBP),
+                _ => None,
             }
         }
     }
+
+    /// Longest mnemonic, register or directive name.
+    pub const KEYWORD_LEN: usize = 9;
+
+    /// `name` upper-cased into `buf`, `None` when it is too long or not
+    /// ASCII and so cannot be a keyword.
+    pub fn upper_keyword<'a>(name: &str, buf: &'a mut [u8; KEYWORD_LEN]) -> Option<&'a [u8]> {
+        let bytes = name.as_bytes();
+        if bytes.len() > KEYWORD_LEN || !bytes.is_ascii() {
+            return None;
+        }
+        let upper = &mut buf[..bytes.len()];
+        upper.copy_from_slice(bytes);
+        upper.make_ascii_uppercase();
+        Some(upper)
+    }
 }
 
-mod data_types {
+pub mod data_types {
     use crate::structures::registers::Register;
+    use num_derive::FromPrimitive;
     use std::fmt::{Display, Formatter};
-    use std::mem::ManuallyDrop;
+    use std::rc::Rc;
 
-    pub struct GeneralData {
-        pub t: DataType,
-        pub d: AnyData,
+    /// A value in a register, an operand or on the stack. Strings are
+    /// shared, so copying a value never copies its text.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum GeneralData {
+        Uint32(u32),
+        Uint64(u64),
+        Int32(i32),
+        Int64(i64),
+        Float(f32),
+        Double(f64),
+        String(Rc<str>),
+        Char(char),
+        Register(Register),
+        /// A `[base+disp]` operand, the base being `NIL` when there is none.
+        Memory(Register, i64),
     }
 
     impl Display for GeneralData {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            match self.t {
-                DataType::Uint32 => write!(f, "{}", self.d.uint32),
-                DataType::Uint64 => write!(f, "{}", self.d.uint64),
-                DataType::Int32 => write!(f, "{}", self.d.int32),
-                DataType::Int64 => write!(f, "{}", self.d.int64),
-                DataType::Float => write!(f, "{}", self.d.float),
-                DataType::Double => write!(f, "{}", self.d.double),
-                DataType::String => write!(f, "{}", self.d.string.as_str()),
-                DataType::Char => write!(f, "{}", self.d.char),
-                DataType::Register => write!(f, "{:?}", self.d.register),
+            match self {
+                GeneralData::Uint32(v) => write!(f, "{}", v),
+                GeneralData::Uint64(v) => write!(f, "{}", v),
+                GeneralData::Int32(v) => write!(f, "{}", v),
+                GeneralData::Int64(v) => write!(f, "{}", v),
+                GeneralData::Float(v) => write!(f, "{}", v),
+                GeneralData::Double(v) => write!(f, "{}", v),
+                GeneralData::String(v) => write!(f, "{}", v),
+                GeneralData::Char(v) => write!(f, "{}", v),
+                GeneralData::Register(reg) => write!(f, "{:?}", reg),
+                GeneralData::Memory(Register::NIL, disp) => write!(f, "[{}]", disp),
+                GeneralData::Memory(base, 0) => write!(f, "[{:?}]", base),
+                GeneralData::Memory(base, disp) if *disp < 0 => {
+                    write!(f, "[{:?}-{}]", base, -disp)
+                }
+                GeneralData::Memory(base, disp) => write!(f, "[{:?}+{}]", base, disp),
             }
         }
     }
 
-    #[derive(Debug, PartialEq, Clone, Copy)]
-    pub enum DataType {
-        Uint32,
-        Uint64,
-        Int32,
-        Int64,
-        Float,
-        Double,
-        String,
-        Char,
-        Register,
+    impl GeneralData {
+        pub fn data_type(&self) -> DataType {
+            match self {
+                GeneralData::Uint32(_) => DataType::Uint32,
+                GeneralData::Uint64(_) => DataType::Uint64,
+                GeneralData::Int32(_) => DataType::Int32,
+                GeneralData::Int64(_) => DataType::Int64,
+                GeneralData::Float(_) => DataType::Float,
+                GeneralData::Double(_) => DataType::Double,
+                GeneralData::String(_) => DataType::String,
+                GeneralData::Char(_) => DataType::Char,
+                GeneralData::Register(_) => DataType::Register,
+                GeneralData::Memory(..) => DataType::Memory,
+            }
+        }
+
+        /// The value's raw bits, as stored in memory.
+        pub fn to_bits(&self) -> u64 {
+            match self {
+                GeneralData::Uint32(v) => *v as u64,
+                GeneralData::Uint64(v) => *v,
+                GeneralData::Int32(v) => *v as i64 as u64,
+                GeneralData::Int64(v) => *v as u64,
+                GeneralData::Float(v) => v.to_bits() as u64,
+                GeneralData::Double(v) => v.to_bits(),
+                GeneralData::Char(v) => *v as u64,
+                GeneralData::Register(reg) => *reg as u64,
+                GeneralData::String(_) | GeneralData::Memory(..) => 0,
+            }
+        }
+
+        /// Any integer widened to i64, `None` for everything else.
+        pub fn as_i64(&self) -> Option<i64> {
+            match self {
+                GeneralData::Uint32(v) => Some(*v as i64),
+                GeneralData::Uint64(v) => Some(*v as i64),
+                GeneralData::Int32(v) => Some(*v as i64),
+                GeneralData::Int64(v) => Some(*v),
+                _ => None,
+            }
+        }
     }
 
-    #[derive(Debug, Clone)]
-    pub struct AnyData {
-        pub uint32: u32,
-        pub uint64: u64,
-        pub int32: i32,
-        pub int64: i64,
-        pub float: f32,
-        pub double: f64,
-        pub string: ManuallyDrop<String>,
-        pub char: char,
-        pub register: Register,
-    }
-
-    impl From<u32> for AnyData {
+    impl From<u32> for GeneralData {
         fn from(val: u32) -> Self {
-            AnyData {
-                uint32: val,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Uint32(val)
         }
     }
 
-    impl From<u64> for AnyData {
+    impl From<u64> for GeneralData {
         fn from(val: u64) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: val,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Uint64(val)
         }
     }
 
-    impl From<i32> for AnyData {
+    impl From<i32> for GeneralData {
         fn from(val: i32) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: val,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Int32(val)
         }
     }
 
-    impl From<i64> for AnyData {
+    impl From<i64> for GeneralData {
         fn from(val: i64) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: val,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Int64(val)
         }
     }
 
-    impl From<f32> for AnyData {
+    impl From<f32> for GeneralData {
         fn from(val: f32) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: val,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Float(val)
         }
     }
 
-    impl From<f64> for AnyData {
+    impl From<f64> for GeneralData {
         fn from(val: f64) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: val,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: Register::NIL,
-            }
+            GeneralData::Double(val)
         }
     }
 
-    impl From<&String> for AnyData {
-        fn from(val: &String) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from(val)),
-                char: ' ',
-                register: Register::NIL,
-            }
+    impl From<&str> for GeneralData {
+        fn from(val: &str) -> Self {
+            GeneralData::String(Rc::from(val))
         }
     }
 
-    impl From<char> for AnyData {
+    impl From<char> for GeneralData {
         fn from(val: char) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: val,
-                register: Register::NIL,
-            }
+            GeneralData::Char(val)
         }
     }
 
-    impl From<Register> for AnyData {
+    impl From<Register> for GeneralData {
         fn from(val: Register) -> Self {
-            AnyData {
-                uint32: 0,
-                uint64: 0,
-                int32: 0,
-                int64: 0,
-                float: 0.0,
-                double: 0.0,
-                string: ManuallyDrop::new(String::from("")),
-                char: ' ',
-                register: val,
-            }
+            GeneralData::Register(val)
         }
     }
+
+    /// The kind of a `GeneralData`, without its value.
+    #[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+    pub enum DataType {
+        Uint32,
+        Uint64,
+        Int32,
+        Int64,
+        Float,
+        Double,
+        String,
+        Char,
+        Register,
+        Memory,
+    }
 }
 
-mod flow_structure {
+pub mod flow_structure {
     use crate::structures::data_types::GeneralData;
+    use crate::structures::registers::{upper_keyword, KEYWORD_LEN};
     use num_derive::FromPrimitive;
-    use num_traits::FromPrimitive;
+    use std::fmt::{Display, Formatter};
 
     #[derive(Debug, FromPrimitive, Clone, Copy, PartialEq)]
+    #[allow(non_camel_case_types)]
     pub enum OpCode {
         MOV,
+        LEA,
         PUSH,
         POP,
         ADD,
@@ -272,474 +246,2131 @@ mod flow_structure {
         RET,
         STDOUT,
         STDIN,
+        PUTC,
+        GETC,
         PNL,
         MALLOC,
         FREE,
+        SYSCALL,
+        NOP,
+        HLT,
+        EXIT,
+        ASSERT_EQ,
+        ASSERT_NE,
         COUNT,
     }
 
     impl OpCode {
-        pub fn isop(name: &String) -> bool {
-            let mut istr: Vec<String> = Vec::with_capacity(OpCode::COUNT as usize);
+        pub fn isop(name: &str) -> bool {
+            OpCode::from_string(name).is_some()
+        }
+
+        /// Case-insensitive.
+        pub fn from_string(name: &str) -> Option<OpCode> {
+            let mut buf = [0u8; KEYWORD_LEN];
+            Some(match upper_keyword(name, &mut buf)? {
+                b"MOV" => OpCode::MOV,
+                b"LEA" => OpCode::LEA,
+                b"PUSH" => OpCode::PUSH,
+                b"POP" => OpCode::POP,
+                b"ADD" => OpCode::ADD,
+                b"SUB" => OpCode::SUB,
+                b"MUL" => OpCode::MUL,
+                b"DIV" => OpCode::DIV,
+                b"MOD" => OpCode::MOD,
+                b"CMP" => OpCode::CMP,
+                b"JNE" => OpCode::JNE,
+                b"JMP" => OpCode::JMP,
+                b"JE" => OpCode::JE,
+                b"INC" => OpCode::INC,
+                b"OR" => OpCode::OR,
+                b"AND" => OpCode::AND,
+                b"XOR" => OpCode::XOR,
+                b"CALL" => OpCode::CALL,
+                b"RET" => OpCode::RET,
+                b"STDOUT" => OpCode::STDOUT,
+                b"STDIN" => OpCode::STDIN,
+                b"PUTC" => OpCode::PUTC,
+                b"GETC" => OpCode::GETC,
+                b"PNL" => OpCode::PNL,
+                b"MALLOC" => OpCode::MALLOC,
+                b"FREE" => OpCode::FREE,
+                b"SYSCALL" => OpCode::SYSCALL,
+                b"NOP" => OpCode::NOP,
+                b"HLT" => OpCode::HLT,
+                b"EXIT" => OpCode::EXIT,
+                b"ASSERT_EQ" => OpCode::ASSERT_EQ,
+                b"ASSERT_NE" => OpCode::ASSERT_NE,
+                _ => return None,
+            })
+        }
+    }
+
+    /// What an instruction accepts in one operand position.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Operand {
+        /// A register that gets written.
+        Register,
+        /// A register or memory operand that gets written.
+        Place,
+        /// A register or an immediate.
+        Value,
+        /// A register, an immediate or a memory operand.
+        Source,
+        /// A label, register, memory operand or instruction index to jump to.
+        Target,
+        /// A label or memory operand whose address is taken.
+        Address,
+        /// An optional number base, 2 to 36.
+        Base,
+        /// An optional exit status.
+        Status,
+    }
+
+    impl Operand {
+        pub fn optional(&self) -> bool {
+            matches!(self, Operand::Base | Operand::Status)
+        }
+    }
 
-            for i in 0..(OpCode::COUNT as i32) {
-                let oc: OpCode = FromPrimitive::from_i32(i).unwrap();
-                istr.push(format!("{:?}", oc))
+    impl OpCode {
+        pub fn operands(&self) -> &'static [Operand] {
+            use Operand::*;
+            match self {
+                OpCode::MOV => &[Place, Source],
+                OpCode::LEA => &[Register, Address],
+                OpCode::PUSH => &[Source],
+                OpCode::POP => &[Register],
+                OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD => {
+                    &[Register, Source]
+                }
+                OpCode::CMP => &[Source, Source],
+                OpCode::JNE | OpCode::JMP | OpCode::JE | OpCode::CALL => &[Target],
+                OpCode::INC => &[Register],
+                OpCode::OR | OpCode::AND | OpCode::XOR => &[Register, Source],
+                OpCode::RET => &[],
+                OpCode::STDOUT => &[Source, Base],
+                OpCode::STDIN => &[Place, Base],
+                OpCode::PUTC => &[Source],
+                OpCode::GETC => &[Place],
+                OpCode::PNL => &[Source],
+                OpCode::MALLOC => &[Register, Value],
+                OpCode::FREE => &[Register],
+                OpCode::SYSCALL | OpCode::NOP => &[],
+                OpCode::HLT => &[Status],
+                OpCode::EXIT => &[Source],
+                OpCode::ASSERT_EQ | OpCode::ASSERT_NE => &[Source, Source],
+                OpCode::COUNT => &[],
             }
-            let p: String = name.to_uppercase();
-            istr.contains(&p)
         }
 
-        pub fn from_string(name: &String) -> Option<OpCode> {
-            let mut istr: Vec<String> = Vec::with_capacity(OpCode::COUNT as usize);
+        /// Mnemonics that are reserved but have no behaviour yet.
+        pub fn unimplemented(&self) -> bool {
+            matches!(self, OpCode::MALLOC | OpCode::FREE)
+        }
 
-            for i in 0..(OpCode::COUNT as i32) {
-                let oc: OpCode = FromPrimitive::from_i32(i).unwrap();
+        /// How many of `operands()` must be given, the rest are optional.
+        pub fn required(&self) -> usize {
+            self.operands().iter().filter(|o| !o.optional()).count()
+        }
 
-                istr.push(format!("{:?}", oc))
+        pub fn doc(&self) -> &'static str {
+            match self {
+                OpCode::MOV => "MOV dst, src\n\nCopies `src` into `dst`, including its type. Memory holds 8-byte values and reads back as Int64.",
+                OpCode::LEA => "LEA dst, addr\n\nLoads the address of a label or memory operand into register `dst`.",
+                OpCode::PUSH => "PUSH value\n\nPushes `value` onto the stack.",
+                OpCode::POP => "POP dst\n\nPops the top of the stack into register `dst`.",
+                OpCode::ADD => "ADD dst, src\n\nAdds `src` to register `dst`. Both must have the same type, integers wrap around.",
+                OpCode::SUB => "SUB dst, src\n\nSubtracts `src` from register `dst`. Both must have the same numeric type, integers wrap around.",
+                OpCode::MUL => "MUL dst, src\n\nMultiplies register `dst` by `src`. Both must have the same numeric type, integers wrap around.",
+                OpCode::DIV => "DIV dst, src\n\nDivides register `dst` by `src`, rounding integers towards zero. Both must have the same numeric type, and an integer `src` must not be zero.",
+                OpCode::MOD => "MOD dst, src\n\nStores `dst % src` in register `dst` and sets ZF when it is zero.",
+                OpCode::CMP => "CMP a, b\n\nSets ZF when `a` equals `b`. Both must have the same type.",
+                OpCode::JNE => "JNE target\n\nJumps to `target` when ZF is clear.",
+                OpCode::JMP => "JMP target\n\nJumps to `target` unconditionally.",
+                OpCode::JE => "JE target\n\nJumps to `target` when ZF is set.",
+                OpCode::INC => "INC dst\n\nAdds one to the number in register `dst`, integers wrap around.",
+                OpCode::OR => "OR dst, src\n\nBitwise or of register `dst` with `src`. Both must have the same integer type.",
+                OpCode::AND => "AND dst, src\n\nBitwise and of register `dst` with `src`. Both must have the same integer type.",
+                OpCode::XOR => "XOR dst, src\n\nBitwise exclusive or of register `dst` with `src`. Both must have the same integer type.",
+                OpCode::CALL => "CALL target\n\nPushes the return address and jumps to `target`.",
+                OpCode::RET => "RET\n\nReturns to the address pushed by the matching CALL.",
+                OpCode::STDOUT => "STDOUT value[, base]\n\nWrites `value` to standard output without a newline, integers in `base` when given.",
+                OpCode::STDIN => "STDIN dst[, base]\n\nReads a line into `dst`, or an integer in `base` when given. Sets ZF when nothing could be read.",
+                OpCode::PUTC => "PUTC value\n\nWrites one character, given as a char or a code point.",
+                OpCode::GETC => "GETC dst\n\nReads one character into `dst`. Sets ZF at the end of input.",
+                OpCode::PNL => "PNL value\n\nPrints `value` followed by a newline.",
+                OpCode::MALLOC => "MALLOC dst, size\n\nNot implemented yet, programs using it do not assemble.",
+                OpCode::FREE => "FREE ptr\n\nNot implemented yet, programs using it do not assemble.",
+                OpCode::SYSCALL => "SYSCALL\n\nCalls the system function numbered in RAX, as on x86-64 Linux, with arguments in RDI, RSI and RDX. The result, or a negative errno, goes to RAX.",
+                OpCode::NOP => "NOP\n\nDoes nothing.",
+                OpCode::HLT => "HLT [status]\n\nStops the program, exiting with `status` or 0.",
+                OpCode::EXIT => "EXIT status\n\nStops the program, exiting with `status`.",
+                OpCode::ASSERT_EQ => "ASSERT_EQ a, b\n\nStops the program with an error unless `a` equals `b`, type included.",
+                OpCode::ASSERT_NE => "ASSERT_NE a, b\n\nStops the program with an error when `a` equals `b`, type included.",
+                OpCode::COUNT => "",
             }
+        }
+    }
 
-            let primitive = name.to_uppercase();
+    /// Assembler directives, which lay out memory instead of emitting code.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Directive {
+        DB,
+        DQ,
+        EQU,
+    }
 
-            let i = istr.iter().position(|ins| &primitive == ins);
-            match i {
-                Some(idx) => FromPrimitive::from_usize(idx),
-                None => None,
+    impl Directive {
+        pub fn from_string(name: &str) -> Option<Directive> {
+            let mut buf = [0u8; KEYWORD_LEN];
+            match upper_keyword(name, &mut buf)? {
+                b"DB" => Some(Directive::DB),
+                b"DQ" => Some(Directive::DQ),
+                b"EQU" => Some(Directive::EQU),
+                _ => None,
+            }
+        }
+
+        pub fn doc(&self) -> &'static str {
+            match self {
+                Directive::DB => "label: DB items\n\nLays out bytes, strings and chars in memory.",
+                Directive::DQ => {
+                    "label: DQ items\n\nLays out 8-byte numbers and addresses in memory."
+                }
+                Directive::EQU => "NAME: EQU value\n\nNames a constant computed at assembly time.",
             }
         }
     }
 
+    #[derive(Clone)]
     pub struct FlowStructure {
         pub op_code: OpCode,
         pub arguments: Vec<GeneralData>,
+        /// 1-based source line of the mnemonic.
+        pub line: usize,
+    }
+
+    impl Display for FlowStructure {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.op_code)?;
+            for (i, arg) in self.arguments.iter().enumerate() {
+                let sep = if i == 0 { " " } else { ", " };
+                match arg {
+                    GeneralData::String(text) => write!(f, "{}{:?}", sep, text)?,
+                    GeneralData::Char(c) => write!(f, "{}{:?}", sep, c)?,
+                    _ => write!(f, "{}{}", sep, arg)?,
+                }
+            }
+            Ok(())
+        }
     }
 
     pub trait IstrTraits {
-        fn mov(&mut self, register: &GeneralData, any: &GeneralData);
-        fn pop(&mut self, register: &GeneralData);
-        fn push(&mut self, any: GeneralData);
-        fn add(&mut self, left: &GeneralData, right: &GeneralData);
-        fn sub(&mut self, left: &GeneralData, right: &GeneralData);
-        fn mul(&mut self, left: &GeneralData, right: &GeneralData);
-        fn div(&mut self, left: &GeneralData, right: &GeneralData);
-        fn modu(&mut self, left: &GeneralData, right: &GeneralData);
-        fn jmp(&mut self, address: &GeneralData);
-        fn jne(&mut self, address: &GeneralData);
-        fn je(&mut self, address: &GeneralData);
-        fn pnl(&mut self, any: &GeneralData);
-        fn cmp(&mut self, left: &GeneralData, right: &GeneralData);
+        fn mov(&mut self, register: &GeneralData, any: &GeneralData) -> Result<(), String>;
+        fn pop(&mut self, register: &GeneralData) -> Result<(), String>;
+        fn push(&mut self, any: GeneralData) -> Result<(), String>;
+        fn add(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn sub(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn mul(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn div(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn modu(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn inc(&mut self, register: &GeneralData) -> Result<(), String>;
+        fn or(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn and(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn xor(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn lea(&mut self, register: &GeneralData, address: &GeneralData) -> Result<(), String>;
+        fn jmp(&mut self, address: &GeneralData) -> Result<(), String>;
+        fn jne(&mut self, address: &GeneralData) -> Result<(), String>;
+        fn je(&mut self, address: &GeneralData) -> Result<(), String>;
+        fn call(&mut self, address: &GeneralData) -> Result<(), String>;
+        fn ret(&mut self) -> Result<(), String>;
+        fn pnl(&mut self, any: &GeneralData) -> Result<(), String>;
+        fn stdout(&mut self, any: &GeneralData, base: Option<&GeneralData>) -> Result<(), String>;
+        fn stdin(&mut self, place: &GeneralData, base: Option<&GeneralData>) -> Result<(), String>;
+        fn putc(&mut self, any: &GeneralData) -> Result<(), String>;
+        fn getc(&mut self, place: &GeneralData) -> Result<(), String>;
+        fn cmp(&mut self, left: &GeneralData, right: &GeneralData) -> Result<(), String>;
+        fn assert(
+            &mut self,
+            left: &GeneralData,
+            right: &GeneralData,
+            equal: bool,
+        ) -> Result<(), String>;
     }
 }
 
-mod env_vars {
-    use crate::structures::data_types::{AnyData, DataType, GeneralData};
-    use crate::structures::flow_structure::{FlowStructure, IstrTraits, OpCode};
-    use crate::structures::registers::Register;
+pub mod lowering {
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::flow_structure::{FlowStructure, OpCode};
 
-    struct Flags {
-        pub zf: bool,
+    /// An instruction decoded ahead of time for `EnvVars::run_decoded`.
+    /// Registers are indices into the register file and jump targets are
+    /// instruction indices. Only the common register and immediate forms
+    /// get their own variant, and they only take the fast path while the
+    /// registers hold Int64 values.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Op {
+        MovReg(usize, usize),
+        MovImm(usize, GeneralData),
+        AddReg(usize, usize),
+        AddInt(usize, i64),
+        CmpReg(usize, usize),
+        CmpInt(usize, i64),
+        Jmp(i64),
+        Je(i64),
+        Jne(i64),
+        Call(i64),
+        Ret,
+        Nop,
+        /// Runs the original instruction through `IstrTraits`.
+        Generic,
     }
 
-    pub struct EnvVars {
-        flags: Flags,
-        registers: Vec<GeneralData>,
-        pub pc: i64,
-        //stack: Vec<GeneralData>,
+    fn lower_one(istr: &FlowStructure) -> Op {
+        use GeneralData::{Int64, Register as Reg};
+
+        match (istr.op_code, istr.arguments.as_slice()) {
+            (OpCode::MOV, [Reg(dst), Reg(src)]) => Op::MovReg(*dst as usize, *src as usize),
+            (OpCode::MOV, [Reg(_), GeneralData::Memory(..)]) => Op::Generic,
+            (OpCode::MOV, [Reg(dst), imm]) => Op::MovImm(*dst as usize, imm.clone()),
+            (OpCode::ADD, [Reg(dst), Reg(src)]) => Op::AddReg(*dst as usize, *src as usize),
+            (OpCode::ADD, [Reg(dst), Int64(n)]) => Op::AddInt(*dst as usize, *n),
+            (OpCode::CMP, [Reg(left), Reg(right)]) => Op::CmpReg(*left as usize, *right as usize),
+            (OpCode::CMP, [Reg(left), Int64(n)]) => Op::CmpInt(*left as usize, *n),
+            (OpCode::JMP, [Int64(target)]) => Op::Jmp(*target),
+            (OpCode::JE, [Int64(target)]) => Op::Je(*target),
+            (OpCode::JNE, [Int64(target)]) => Op::Jne(*target),
+            (OpCode::CALL, [Int64(target)]) => Op::Call(*target),
+            (OpCode::RET, []) => Op::Ret,
+            (OpCode::NOP, []) => Op::Nop,
+            _ => Op::Generic,
+        }
     }
 
-    impl EnvVars {
-        pub fn init() -> Self {
-            let mut this = EnvVars {
-                flags: Flags { zf: false },
-                registers: Vec::with_capacity(Register::NIL as usize),
-                pc: 0,
-                //stack: Vec::new(),
-            };
+    /// Decodes `flow` one to one, so `Op` indices are instruction indices.
+    pub fn lower(flow: &[FlowStructure]) -> Vec<Op> {
+        flow.iter().map(lower_one).collect()
+    }
+}
 
-            for _ in 0..this.registers.capacity() {
-                this.registers.push(GeneralData {
-                    t: DataType::Int32,
-                    d: AnyData::from(0),
-                });
-            }
+pub mod host {
+    use crate::vfs::{errno, FileSystem, MemoryFs, OpenMode, VirtualFile};
+    use std::collections::HashMap;
+    use std::io;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-            this
-        }
+    pub const ENOENT: i64 = 2;
+    pub const EIO: i64 = 5;
+    pub const EBADF: i64 = 9;
+    pub const ENOMEM: i64 = 12;
+    pub const EACCES: i64 = 13;
+    pub const EFAULT: i64 = 14;
+    pub const EEXIST: i64 = 17;
+    pub const EINVAL: i64 = 22;
+    pub const ESPIPE: i64 = 29;
+    pub const ENOSYS: i64 = 38;
 
-        pub fn execute_istr(&mut self, istr: &FlowStructure) {
-            match istr.op_code {
-                OpCode::MOV => self.mov(&istr.arguments[0], &istr.arguments[1]),
-                OpCode::PUSH => todo!(),
-                OpCode::POP => todo!(),
-                OpCode::ADD => self.add(&istr.arguments[0], &istr.arguments[1]),
-                OpCode::SUB => todo!(),
-                OpCode::MUL => todo!(),
-                OpCode::DIV => todo!(),
-                OpCode::MOD => self.modu(&istr.arguments[0], &istr.arguments[1]),
-                OpCode::CMP => self.cmp(&istr.arguments[0], &istr.arguments[1]),
-                OpCode::JNE => self.jne(&istr.arguments[0]),
-                OpCode::JMP => self.jmp(&istr.arguments[0]),
-                OpCode::JE => self.je(&istr.arguments[0]),
-                OpCode::INC => todo!(),
-                OpCode::OR => todo!(),
-                OpCode::AND => todo!(),
-                OpCode::XOR => todo!(),
-                OpCode::CALL => todo!(),
-                OpCode::RET => todo!(),
-                OpCode::STDOUT => todo!(),
-                OpCode::STDIN => todo!(),
-                OpCode::PNL => self.pnl(&istr.arguments[0]),
-                OpCode::MALLOC => todo!(),
-                OpCode::FREE => todo!(),
-                OpCode::COUNT => todo!(),
-            }
+    const O_ACCMODE: i64 = 0o3;
+    const O_WRONLY: i64 = 0o1;
+    const O_RDWR: i64 = 0o2;
+    const O_CREAT: i64 = 0o100;
+    const O_EXCL: i64 = 0o200;
+    const O_TRUNC: i64 = 0o1000;
+    const O_APPEND: i64 = 0o2000;
+
+    const CLOCK_REALTIME: i64 = 0;
+    const CLOCK_MONOTONIC: i64 = 1;
+
+    /// What SYSCALL reaches outside the VM. Descriptors 0 and 1 are the
+    /// program's input and output and never get here. Errors are errno
+    /// values.
+    pub trait Host {
+        fn open(&mut self, path: &str, flags: i64) -> Result<i64, i64>;
+        fn close(&mut self, fd: i64) -> Result<(), i64>;
+        fn read(&mut self, fd: i64, len: usize) -> Result<Vec<u8>, i64>;
+        fn write(&mut self, fd: i64, bytes: &[u8]) -> Result<usize, i64>;
+        fn lseek(&mut self, fd: i64, offset: i64, whence: i64) -> Result<i64, i64>;
+        fn random(&mut self, buf: &mut [u8]);
+        fn clock(&mut self, clock: i64) -> Result<Duration, i64>;
+    }
+
+    fn open_mode(flags: i64) -> OpenMode {
+        let access = flags & O_ACCMODE;
+        OpenMode {
+            read: access != O_WRONLY,
+            write: access == O_WRONLY || access == O_RDWR,
+            create: flags & O_CREAT != 0,
+            create_new: flags & O_CREAT != 0 && flags & O_EXCL != 0,
+            truncate: flags & O_TRUNC != 0,
+            append: flags & O_APPEND != 0,
         }
     }
 
-    impl IstrTraits for EnvVars {
-        fn mov(&mut self, register: &GeneralData, any: &GeneralData) {
-            assert!(register.t == DataType::Register);
-            let r_id = register.d.register.clone() as usize;
-            if any.t == DataType::Register {
-                let a_id = any.d.register.clone() as usize;
-                self.registers[r_id].t = self.registers[a_id].t.clone();
-                self.registers[r_id].d = self.registers[a_id].d.clone();
-            } else {
-                self.registers[r_id].t = any.t.clone();
-                self.registers[r_id].d = any.d.clone();
+    /// Open files by descriptor, shared by the hosts. Descriptor 2 is
+    /// always stderr.
+    struct Descriptors {
+        fs: Box<dyn FileSystem>,
+        files: HashMap<i64, Box<dyn VirtualFile>>,
+        next_fd: i64,
+    }
+
+    impl Descriptors {
+        fn init(fs: Box<dyn FileSystem>) -> Self {
+            Descriptors {
+                fs,
+                files: HashMap::new(),
+                next_fd: 3,
             }
         }
 
-        fn pop(&mut self, register: &GeneralData) {
-            todo!()
+        fn file(&mut self, fd: i64) -> Result<&mut Box<dyn VirtualFile>, i64> {
+            self.files.get_mut(&fd).ok_or(EBADF)
         }
 
-        fn push(&mut self, any: GeneralData) {
-            todo!()
+        fn open(&mut self, path: &str, flags: i64) -> Result<i64, i64> {
+            let file = self.fs.open(path, &open_mode(flags))?;
+            let fd = self.next_fd;
+            self.next_fd += 1;
+            self.files.insert(fd, file);
+            Ok(fd)
         }
 
-        fn add(&mut self, left: &GeneralData, right: &GeneralData) {
-            if left.t == DataType::Register {
-                let r_id = left.d.register as usize;
-                let data = &self.registers[r_id];
-
-                if right.t == DataType::Register {
-                    let o_id = right.d.register as usize;
-                    let o_data = &self.registers[o_id];
-                    assert!(o_data.t == data.t);
+        fn close(&mut self, fd: i64) -> Result<(), i64> {
+            self.files.remove(&fd).map(|_| ()).ok_or(EBADF)
+        }
 
-                    match data.t {
-                        DataType::Uint32 => self.registers[r_id].d.uint32 += o_data.d.uint32,
-                        DataType::Uint64 => self.registers[r_id].d.uint64 += o_data.d.uint64,
-                        DataType::Int32 => self.registers[r_id].d.int32 += o_data.d.int32,
-                        DataType::Int64 => self.registers[r_id].d.int64 += o_data.d.int64,
-                        DataType::Float => self.registers[r_id].d.float += o_data.d.float,
-                        DataType::Double => self.registers[r_id].d.double += o_data.d.double,
-                        DataType::Char => {
-                            self.registers[r_id].d.char =
-                                (self.registers[r_id].d.char as u8 + o_data.d.char as u8) as char
-                        }
-                        //TODO string + register
-                        _ => {}
-                    }
-                } else {
-                    assert!(right.t == data.t);
+        fn read(&mut self, fd: i64, len: usize) -> Result<Vec<u8>, i64> {
+            let mut bytes = vec![0; len];
+            let n = self.file(fd)?.read(&mut bytes).map_err(errno)?;
+            bytes.truncate(n);
+            Ok(bytes)
+        }
 
-                    match data.t {
-                        DataType::Uint32 => {
-                            self.registers[r_id].d.uint32 += right.d.uint32;
-                        }
-                        DataType::Uint64 => {
-                            self.registers[r_id].d.uint64 += right.d.uint64;
-                        }
-                        DataType::Int32 => {
-                            self.registers[r_id].d.int32 += right.d.int32;
-                        }
-                        DataType::Int64 => {
-                            self.registers[r_id].d.int64 += right.d.int64;
-                        }
-                        DataType::Float => {
-                            self.registers[r_id].d.float += right.d.float;
-                        }
-                        DataType::Double => {
-                            self.registers[r_id].d.double += right.d.double;
-                        }
-                        // DataType::String => {
-                        //     self.registers[r_id].d.string += right.d.string.clone();
-                        // }
-                        DataType::Char => {
-                            self.registers[r_id].d.char =
-                                (self.registers[r_id].d.char as u8 + right.d.char as u8) as char;
-                        }
-                        // DataType::Register => {
-                        //     self.registers[r_id].d.register += right.d.register;
-                        // }
-                        _ => {}
-                    }
-                }
+        fn write(&mut self, fd: i64, bytes: &[u8]) -> Result<usize, i64> {
+            if fd == 2 {
+                return io::stderr().write(bytes).map_err(errno);
             }
+            self.file(fd)?.write(bytes).map_err(errno)
         }
 
-        fn sub(&mut self, left: &GeneralData, right: &GeneralData) {
-            todo!()
+        fn lseek(&mut self, fd: i64, offset: i64, whence: i64) -> Result<i64, i64> {
+            let pos = match whence {
+                0 if offset >= 0 => SeekFrom::Start(offset as u64),
+                1 => SeekFrom::Current(offset),
+                2 => SeekFrom::End(offset),
+                _ => return Err(EINVAL),
+            };
+            let file = self.file(fd)?;
+            file.seek(pos).map(|p| p as i64).map_err(errno)
         }
+    }
 
-        fn mul(&mut self, left: &GeneralData, right: &GeneralData) {
-            todo!()
-        }
+    /// xorshift64, good enough for programs that want noise.
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
 
-        fn div(&mut self, left: &GeneralData, right: &GeneralData) {
-            todo!()
+    fn fill_random(state: &mut u64, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = next_random(state).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
         }
+    }
 
-        fn modu(&mut self, left: &GeneralData, right: &GeneralData) {
-            let mut left_data: &GeneralData = left;
-            let mut right_data: &GeneralData = right;
-
-            let is_reg: bool = left.t == DataType::Register;
-            let r_id: usize = left.d.register.clone() as usize;
+    /// The real clock and random seed, with files from `fs`.
+    pub struct SystemHost {
+        files: Descriptors,
+        started: Instant,
+        seed: u64,
+    }
 
-            if is_reg {
-                left_data = &self.registers[r_id];
+    impl SystemHost {
+        pub fn init(fs: Box<dyn FileSystem>) -> Self {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            SystemHost {
+                files: Descriptors::init(fs),
+                started: Instant::now(),
+                seed: nanos | 1,
             }
-            if right.t == DataType::Register {
-                right_data = &self.registers[right.d.register.clone() as usize];
-            }
-            assert!(left_data.t == right_data.t);
-
-            match left_data.t {
-                DataType::Uint32 => {
-                    let res: u32 = left_data.d.uint32 % right_data.d.uint32;
-
-                    if is_reg {
-                        self.registers[r_id].d.uint32 = res;
-                        self.flags.zf = res == 0u32;
-                    }
-                }
-                DataType::Uint64 => {
-                    let res: u64 = left_data.d.uint64 % right_data.d.uint64;
-
-                    if is_reg {
-                        self.registers[r_id].d.uint64 = res;
-                        self.flags.zf = res == 0u64;
-                    }
-                }
-                DataType::Int32 => {
-                    let res: i32 = left_data.d.int32 % right_data.d.int32;
+        }
+    }
 
-                    if is_reg {
-                        self.registers[r_id].d.int32 = res;
-                        self.flags.zf = res == 0i32;
-                    }
-                }
-                DataType::Int64 => {
-                    let res: i64 = left_data.d.int64 % right_data.d.int64;
+    impl Default for SystemHost {
+        /// Files are kept in memory, the real filesystem is only reached
+        /// through a `HostFs` given to `init`.
+        fn default() -> Self {
+            SystemHost::init(Box::new(MemoryFs::init()))
+        }
+    }
 
-                    if is_reg {
-                        self.registers[r_id].d.int64 = res;
-                        self.flags.zf = res == 0i64;
-                    }
-                }
-                DataType::Float => {
-                    let res: f32 = left_data.d.float % right_data.d.float;
+    impl Host for SystemHost {
+        fn open(&mut self, path: &str, flags: i64) -> Result<i64, i64> {
+            self.files.open(path, flags)
+        }
 
-                    if is_reg {
-                        self.registers[r_id].d.float = res;
-                        self.flags.zf = res == 0f32;
-                    }
-                }
-                DataType::Double => {
-                    let res: f64 = left_data.d.double % right_data.d.double;
+        fn close(&mut self, fd: i64) -> Result<(), i64> {
+            self.files.close(fd)
+        }
 
-                    if is_reg {
-                        self.registers[r_id].d.double = res;
-                        self.flags.zf = res == 0f64;
-                    }
-                }
-                _ => assert!(false, "Wrong type"),
-            }
+        fn read(&mut self, fd: i64, len: usize) -> Result<Vec<u8>, i64> {
+            self.files.read(fd, len)
         }
 
-        fn jmp(&mut self, address: &GeneralData) {
-            if address.t == DataType::Register {
-                let o_id: usize = address.d.register.clone() as usize;
-                let o_data: &GeneralData = &self.registers[o_id];
-                assert!(o_data.t == DataType::Int64);
-                self.pc = address.d.int64 - 1;
-            } else {
-                assert!(address.t == DataType::Int64);
-                self.pc = address.d.int64 - 1;
-            }
+        fn write(&mut self, fd: i64, bytes: &[u8]) -> Result<usize, i64> {
+            self.files.write(fd, bytes)
         }
 
-        fn jne(&mut self, address: &GeneralData) {
-            if address.t == DataType::Register {
-                let r_id: usize = address.d.register.clone() as usize;
-                let r_data: &GeneralData = &self.registers[r_id];
-                assert!(r_data.t == DataType::Int64);
-                if !self.flags.zf {
-                    self.pc = r_data.d.int64 - 1;
-                }
-            } else {
-                assert!(address.t == DataType::Int64);
-                if !self.flags.zf {
-                    self.pc = address.d.int64 - 1;
-                }
-            }
+        fn lseek(&mut self, fd: i64, offset: i64, whence: i64) -> Result<i64, i64> {
+            self.files.lseek(fd, offset, whence)
         }
 
-        fn je(&mut self, address: &GeneralData) {
-            if address.t == DataType::Register {
-                let r_data: &GeneralData = &self.registers[address.d.register as usize];
-                assert!(r_data.t == DataType::Int64);
-                if self.flags.zf {
-                    self.pc = r_data.d.int64 - 1
-                }
-            } else {
-                assert!(address.t == DataType::Int64);
-                if self.flags.zf {
-                    self.pc = address.d.int64 - 1
-                }
-            }
+        fn random(&mut self, buf: &mut [u8]) {
+            fill_random(&mut self.seed, buf);
         }
 
-        fn pnl(&mut self, any: &GeneralData) {
-            if any.t == DataType::Register {
-                let r_id = any.d.register as usize;
-                println!("{}", self.registers[r_id]);
-            } else {
-                println!("{}", any);
+        fn clock(&mut self, clock: i64) -> Result<Duration, i64> {
+            match clock {
+                CLOCK_REALTIME => Ok(SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()),
+                CLOCK_MONOTONIC => Ok(self.started.elapsed()),
+                _ => Err(EINVAL),
             }
         }
+    }
 
-        fn cmp(&mut self, left: &GeneralData, right: &GeneralData) {
-            let mut l_data: &GeneralData = left;
-            let mut r_data: &GeneralData = right;
+    /// Makes every run the same: a clock stopped at zero, random bytes
+    /// from a fixed seed and files from `fs`, by default an empty
+    /// `MemoryFs`.
+    pub struct SandboxHost {
+        files: Descriptors,
+        seed: u64,
+    }
 
-            if left.t == DataType::Register {
-                l_data = &self.registers[left.d.register as usize];
-            }
-            if right.t == DataType::Register {
-                r_data = &self.registers[right.d.register as usize];
-            }
-            assert!(l_data.t == r_data.t);
-            match l_data.t {
-                DataType::Uint32 => {
-                    self.flags.zf = l_data.d.uint32 == r_data.d.uint32;
-                }
-                DataType::Uint64 => {
-                    self.flags.zf = l_data.d.uint64 == r_data.d.uint64;
-                }
-                DataType::Int32 => {
-                    self.flags.zf = l_data.d.int32 == r_data.d.int32;
-                }
-                DataType::Int64 => {
-                    self.flags.zf = l_data.d.int64 == r_data.d.int64;
-                }
-                DataType::Float => {
-                    self.flags.zf = l_data.d.float == r_data.d.float;
-                }
-                DataType::Double => {
-                    self.flags.zf = l_data.d.double == r_data.d.double;
-                }
-                DataType::String => {
-                    self.flags.zf = l_data.d.string == r_data.d.string;
-                }
-                DataType::Char => {
-                    self.flags.zf = l_data.d.char == r_data.d.char;
-                }
-                DataType::Register => {
-                    self.flags.zf = false;
-                }
+    impl SandboxHost {
+        pub fn init(fs: Box<dyn FileSystem>) -> Self {
+            SandboxHost {
+                files: Descriptors::init(fs),
+                seed: 0x2545_f491_4f6c_dd1d,
             }
         }
     }
-}
-
-pub mod parser {
-    use crate::structures::data_types::DataType;
-    use std::io::Read;
 
-    #[derive(Debug)]
-    pub enum ParserError {
-        EOF,
-        NAC,
+    impl Default for SandboxHost {
+        fn default() -> Self {
+            SandboxHost::init(Box::new(MemoryFs::init()))
+        }
     }
 
-    pub struct Parser {
-        stream: Vec<char>,
-        pub tokens: Vec<String>,
-    }
+    impl Host for SandboxHost {
+        fn open(&mut self, path: &str, flags: i64) -> Result<i64, i64> {
+            self.files.open(path, flags)
+        }
 
-    impl Parser {
-        pub fn init(string: String) -> Self {
-            let vec: Vec<char> = string.chars().rev().take(string.len()).collect();
-            Parser {
-                stream: vec,
-                tokens: Vec::new(),
-            }
+        fn close(&mut self, fd: i64) -> Result<(), i64> {
+            self.files.close(fd)
         }
 
-        fn read(&self) -> Result<char, ParserError> {
-            if self.stream.len() <= 0 {
-                return Err(ParserError::EOF);
-            }
-            let c: char = self.stream[self.stream.len() - 1];
+        fn read(&mut self, fd: i64, len: usize) -> Result<Vec<u8>, i64> {
+            self.files.read(fd, len)
+        }
 
-            Ok(c)
+        fn write(&mut self, fd: i64, bytes: &[u8]) -> Result<usize, i64> {
+            self.files.write(fd, bytes)
         }
 
-        fn consume_char(&mut self) -> Result<char, ParserError> {
-            if self.stream.len() <= 0 {
-                return Err(ParserError::EOF);
-            }
+        fn lseek(&mut self, fd: i64, offset: i64, whence: i64) -> Result<i64, i64> {
+            self.files.lseek(fd, offset, whence)
+        }
 
-            let chr = self.stream.pop().unwrap();
+        fn random(&mut self, buf: &mut [u8]) {
+            fill_random(&mut self.seed, buf);
+        }
 
-            Ok(chr)
+        fn clock(&mut self, clock: i64) -> Result<Duration, i64> {
+            match clock {
+                CLOCK_REALTIME | CLOCK_MONOTONIC => Ok(Duration::ZERO),
+                _ => Err(EINVAL),
+            }
         }
+    }
+}
 
-        fn read_string(&mut self) -> String {
-            let mut str: String = String::new();
-            let mut stack: Vec<char> = vec![self.consume_char().unwrap()];
+pub mod env_vars {
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::flow_structure::{FlowStructure, IstrTraits, OpCode};
+    use crate::structures::host;
+    use crate::structures::host::{Host, SystemHost};
+    use crate::structures::lowering::Op;
+    use crate::structures::registers::Register;
+    use std::collections::VecDeque;
+    use std::fmt::{Display, Formatter};
+    use std::io;
+    use std::io::{BufRead, Write};
+    use std::ops::Range;
 
-            while stack.len() > 0 {
-                let c: char = self.consume_char().unwrap();
+    /// Instructions the undo journal keeps by default, so a long session
+    /// cannot grow it without bound.
+    pub const HISTORY_LIMIT: usize = 1 << 20;
 
-                if c == '"' {
-                    stack.pop();
-                    break;
-                }
-                str.push(c);
-            }
+    #[derive(Clone, Copy)]
+    pub struct Flags {
+        pub zf: bool,
+    }
 
-            str
-        }
+    /// Bytes of guest memory, addressed from 0.
+    pub const MEMORY_SIZE: usize = 1 << 16;
+    /// Where DB and DQ data is laid out.
+    pub const DATA_BASE: usize = 0x1000;
 
-        fn read_comment(&mut self) -> String {
-            let mut comment: String = String::new();
-            let trim: [char; 2] = ['\n', '\r'];
-            let mut c: char = self.consume_char().unwrap();
+    /// Exit code after the output pipe closed, 128 + SIGPIPE as a shell
+    /// reports it.
+    const SIGPIPE_EXIT: i32 = 141;
 
-            while self.stream.len() > 0 && !trim.contains(&c) {
-                comment.push(c);
-                c = self.consume_char().unwrap();
-            }
-            comment
-        }
+    const SYS_READ: i64 = 0;
+    const SYS_WRITE: i64 = 1;
+    const SYS_OPEN: i64 = 2;
+    const SYS_CLOSE: i64 = 3;
+    const SYS_LSEEK: i64 = 8;
+    const SYS_MMAP: i64 = 9;
+    const SYS_BRK: i64 = 12;
+    const SYS_EXIT: i64 = 60;
+    const SYS_CLOCK_GETTIME: i64 = 228;
+    const SYS_EXIT_GROUP: i64 = 231;
+    const SYS_GETRANDOM: i64 = 318;
+
+    /// A value as it was before an instruction overwrote it.
+    #[derive(Clone)]
+    pub enum Delta {
+        Register(usize, GeneralData),
+        Flags(Flags),
+        /// The bytes at an address.
+        Memory(usize, Vec<u8>),
+        /// Input that was read, undone by handing it back to the next read.
+        Input(Vec<u8>),
+        /// A value was pushed, undone by popping it.
+        Push,
+        /// A value was popped, undone by pushing it back.
+        Pop(GeneralData),
+        /// The program break and lowest mapping before BRK or MMAP.
+        Heap(usize, usize),
+    }
+
+    /// Everything needed to step back over one executed instruction.
+    pub struct UndoRecord {
+        pub pc: i64,
+        pub deltas: Vec<Delta>,
+    }
+
+    /// A runtime error raised by the program, such as a division by zero
+    /// or a jump out of the code.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct VmError {
+        /// The instruction that failed, `pc` is left pointing at it.
+        pub pc: usize,
+        pub message: String,
+    }
+
+    impl Display for VmError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.message)
+        }
+    }
+
+    /// What instructions return before the failing `pc` is known.
+    type Fault<T = ()> = Result<T, String>;
+
+    pub struct EnvVars {
+        flags: Flags,
+        registers: Vec<GeneralData>,
+        pub pc: i64,
+        stack: Vec<GeneralData>,
+        memory: Vec<u8>,
+        halted: bool,
+        exit_code: Option<i32>,
+        /// End of the heap grown by BRK, it starts after the data.
+        brk: usize,
+        brk_start: usize,
+        /// MMAP hands out memory downwards from the top.
+        mapped: usize,
+        recording: bool,
+        history: VecDeque<UndoRecord>,
+        /// Instructions kept in `history`, the oldest are dropped beyond it.
+        history_limit: usize,
+        out: Box<dyn Write>,
+        input: Box<dyn BufRead>,
+        /// Input given back by `undo`, next byte last.
+        unread: Vec<u8>,
+        host: Box<dyn Host>,
+    }
+
+    impl EnvVars {
+        pub fn init() -> Self {
+            let mut this = EnvVars {
+                flags: Flags { zf: false },
+                registers: Vec::with_capacity(Register::NIL as usize),
+                pc: 0,
+                stack: Vec::new(),
+                memory: vec![0; MEMORY_SIZE],
+                halted: false,
+                exit_code: None,
+                brk: DATA_BASE,
+                brk_start: DATA_BASE,
+                mapped: MEMORY_SIZE,
+                recording: false,
+                history: VecDeque::new(),
+                history_limit: HISTORY_LIMIT,
+                out: Box::new(io::BufWriter::new(io::stdout())),
+                input: Box::new(io::BufReader::new(io::stdin())),
+                unread: Vec::new(),
+                host: Box::new(SystemHost::default()),
+            };
+
+            for _ in 0..this.registers.capacity() {
+                this.registers.push(GeneralData::Int32(0));
+            }
+
+            this
+        }
+
+        pub fn register(&self, reg: Register) -> &GeneralData {
+            &self.registers[reg as usize]
+        }
+
+        pub fn flags(&self) -> Flags {
+            self.flags
+        }
+
+        pub fn stack(&self) -> &[GeneralData] {
+            &self.stack
+        }
+
+        pub fn memory(&self) -> &[u8] {
+            &self.memory
+        }
+
+        /// Overwrites memory from outside the program. While recording, the
+        /// old bytes join the last instruction's undo record.
+        pub fn set_memory(&mut self, addr: usize, bytes: &[u8]) {
+            self.write_at(addr, bytes);
+        }
+
+        /// True once HLT, EXIT or the exit syscall has run.
+        pub fn halted(&self) -> bool {
+            self.halted
+        }
+
+        /// The status given to HLT, EXIT or the exit syscall, 0 otherwise.
+        pub fn exit_code(&self) -> i32 {
+            self.exit_code.unwrap_or(0)
+        }
+
+        /// Lays out `args` after the data as NUL terminated strings and a
+        /// NULL terminated array of pointers to them, then passes argc in
+        /// RDI and that array in RSI. The heap starts after them. Fails when
+        /// they do not fit below the mappings.
+        pub fn set_args(&mut self, args: &[String]) -> Result<(), String> {
+            let strings: usize = args.iter().map(|a| a.len() + 1).sum();
+            let table = self.brk;
+            let mut next = table + (args.len() + 1) * 8;
+            let end = next + strings;
+            if end > self.mapped {
+                return Err(String::from("Arguments do not fit in memory"));
+            }
+            for (i, arg) in args.iter().enumerate() {
+                let slot = table + i * 8;
+                self.memory[slot..slot + 8].copy_from_slice(&(next as i64).to_le_bytes());
+                self.memory[next..next + arg.len()].copy_from_slice(arg.as_bytes());
+                self.memory[next + arg.len()] = 0;
+                next += arg.len() + 1;
+            }
+            let null = table + args.len() * 8;
+            self.memory[null..null + 8].fill(0);
+
+            for (reg, value) in [
+                (Register::RDI, args.len() as i64),
+                (Register::RSI, table as i64),
+            ] {
+                self.registers[reg as usize] = GeneralData::Int64(value);
+            }
+            self.set_break(end.next_multiple_of(8));
+            Ok(())
+        }
+
+        /// Starts the heap at `addr`, the end of the program's data.
+        pub fn set_break(&mut self, addr: usize) {
+            self.brk = addr;
+            self.brk_start = addr;
+        }
+
+        /// Replaces what SYSCALL can reach, the real system by default.
+        /// Effects on the host are not undone by `undo`.
+        pub fn set_host(&mut self, host: Box<dyn Host>) {
+            self.host = host;
+        }
+
+        /// Overwrites a register from outside the program, bypassing the journal.
+        pub fn set_register(&mut self, reg: Register, data: GeneralData) {
+            self.registers[reg as usize] = data;
+        }
+
+        pub fn set_flags(&mut self, flags: Flags) {
+            self.flags = flags;
+        }
+
+        /// Redirects what the program prints, buffered stdout by default.
+        pub fn set_output(&mut self, out: Box<dyn Write>) {
+            self.out = out;
+        }
+
+        /// Replaces what the program reads, stdin by default.
+        pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+            self.input = input;
+            self.unread.clear();
+        }
+
+        pub fn flush(&mut self) {
+            let result = self.out.flush();
+            self.output_error(result);
+        }
+
+        /// Writes program output. A reader that went away, as with `| head`,
+        /// ends the program the way SIGPIPE would.
+        fn print(&mut self, text: &[u8]) {
+            let result = self.out.write_all(text);
+            self.output_error(result);
+        }
+
+        fn output_error(&mut self, result: io::Result<()>) {
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    self.out = Box::new(io::sink());
+                    self.exit_code = Some(SIGPIPE_EXIT);
+                    self.halted = true;
+                }
+                other => other.expect("Failed to write program output"),
+            }
+        }
+
+        /// Turns undo journaling on or off. Turning it off drops the journal.
+        pub fn set_recording(&mut self, recording: bool) {
+            self.recording = recording;
+            if !recording {
+                self.history.clear();
+            }
+        }
+
+        pub fn history(&self) -> &VecDeque<UndoRecord> {
+            &self.history
+        }
+
+        /// Caps how many instructions the journal keeps, dropping the
+        /// oldest first.
+        pub fn set_history_limit(&mut self, limit: usize) {
+            self.history_limit = limit;
+            while self.history.len() > limit {
+                self.history.pop_front();
+            }
+        }
+
+        /// Reverts the most recently executed instruction, returns false when
+        /// there is nothing left to undo.
+        pub fn undo(&mut self) -> bool {
+            let record = match self.history.pop_back() {
+                Some(record) => record,
+                None => return false,
+            };
+
+            for delta in record.deltas.into_iter().rev() {
+                match delta {
+                    Delta::Register(id, data) => self.registers[id] = data,
+                    Delta::Flags(flags) => self.flags = flags,
+                    Delta::Memory(addr, bytes) => {
+                        self.memory[addr..addr + bytes.len()].copy_from_slice(&bytes)
+                    }
+                    Delta::Input(bytes) => self.unread.extend(bytes.iter().rev()),
+                    Delta::Push => {
+                        self.stack.pop();
+                    }
+                    Delta::Pop(data) => self.stack.push(data),
+                    Delta::Heap(brk, mapped) => {
+                        self.brk = brk;
+                        self.mapped = mapped;
+                    }
+                }
+            }
+            self.pc = record.pc;
+            // Only the last instruction can have halted.
+            self.halted = false;
+            self.exit_code = None;
+
+            true
+        }
+
+        /// Finds the newest journal entry that wrote `reg`, as its index in
+        /// `history()` together with the `pc` of the writing instruction.
+        pub fn last_write(&self, reg: Register) -> Option<(usize, i64)> {
+            let id = reg as usize;
+
+            self.history
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(i, record)| {
+                    record
+                        .deltas
+                        .iter()
+                        .any(|d| matches!(d, Delta::Register(r, _) if *r == id))
+                        .then_some((i, record.pc))
+                })
+        }
+
+        fn journal(&mut self, delta: Delta) {
+            if !self.recording {
+                return;
+            }
+            if let Some(record) = self.history.back_mut() {
+                let seen = record.deltas.iter().any(|d| match (d, &delta) {
+                    (Delta::Register(a, _), Delta::Register(b, _)) => a == b,
+                    (Delta::Flags(_), Delta::Flags(_)) => true,
+                    _ => false,
+                });
+                if !seen {
+                    record.deltas.push(delta);
+                }
+            }
+        }
+
+        fn register_mut(&mut self, id: usize) -> &mut GeneralData {
+            if self.recording {
+                self.journal(Delta::Register(id, self.registers[id].clone()));
+            }
+            &mut self.registers[id]
+        }
+
+        fn set_zf(&mut self, zf: bool) {
+            if self.recording {
+                self.journal(Delta::Flags(self.flags));
+            }
+            self.flags.zf = zf;
+        }
+
+        /// Pushes `data` itself, where PUSH would load an operand.
+        pub fn push_value(&mut self, data: GeneralData) {
+            self.journal(Delta::Push);
+            self.stack.push(data);
+        }
+
+        fn pop_value(&mut self) -> Fault<GeneralData> {
+            let data = self.stack.pop().ok_or("Pop from an empty stack")?;
+            if self.recording {
+                self.journal(Delta::Pop(data.clone()));
+            }
+            Ok(data)
+        }
+
+        /// The address a memory operand refers to, checked for `len` bytes.
+        fn effective_address(&self, memory: &GeneralData, len: usize) -> Fault<usize> {
+            let (base, disp) = match memory {
+                GeneralData::Memory(Register::NIL, disp) => (0, *disp),
+                GeneralData::Memory(reg, disp) => (self.integer(*reg)?, *disp),
+                _ => return Err(format!("Expected a memory operand, found {}", memory)),
+            };
+            let addr = base
+                .checked_add(disp)
+                .filter(|addr| *addr >= 0 && *addr as u64 + len as u64 <= MEMORY_SIZE as u64)
+                .ok_or_else(|| {
+                    format!(
+                        "Memory access out of bounds at {}",
+                        base as i128 + disp as i128
+                    )
+                })?;
+            Ok(addr as usize)
+        }
+
+        fn read_memory(&self, memory: &GeneralData) -> Fault<GeneralData> {
+            let addr = self.effective_address(memory, 8)?;
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&self.memory[addr..addr + 8]);
+            Ok(GeneralData::Int64(i64::from_le_bytes(bytes)))
+        }
+
+        /// An integer register as an i64, for addresses and syscall arguments.
+        fn integer(&self, reg: Register) -> Fault<i64> {
+            let data = &self.registers[reg as usize];
+            data.as_i64().ok_or_else(|| {
+                format!(
+                    "Register {:?} holds {:?}, not an integer",
+                    reg,
+                    data.data_type()
+                )
+            })
+        }
+
+        /// HLT and EXIT, `status` defaults to 0.
+        fn exit(&mut self, status: Option<&GeneralData>) -> Fault {
+            let code = match status.map(|s| self.load(s)).transpose()? {
+                Some(data) => match data.as_i64() {
+                    Some(code) => code as i32,
+                    None => {
+                        return Err(format!(
+                            "Exit status must be an integer, not {:?}",
+                            data.data_type()
+                        ))
+                    }
+                },
+                None => 0,
+            };
+            self.exit_code = Some(code);
+            self.halted = true;
+            Ok(())
+        }
+
+        fn write_bytes(&mut self, memory: &GeneralData, bytes: &[u8]) -> Fault {
+            let addr = self.effective_address(memory, bytes.len())?;
+            self.write_at(addr, bytes);
+            Ok(())
+        }
+
+        fn write_at(&mut self, addr: usize, bytes: &[u8]) {
+            let range = addr..addr + bytes.len();
+            if self.recording {
+                self.journal(Delta::Memory(addr, self.memory[range.clone()].to_vec()));
+            }
+            self.memory[range].copy_from_slice(bytes);
+        }
+
+        fn write_memory(&mut self, memory: &GeneralData, data: &GeneralData) -> Fault {
+            self.write_bytes(memory, &data.to_bits().to_le_bytes())
+        }
+
+        /// Writes a register or memory operand.
+        fn store(&mut self, place: &GeneralData, value: GeneralData) -> Fault {
+            match place {
+                GeneralData::Memory(..) => self.write_memory(place, &value),
+                GeneralData::Register(reg) => {
+                    *self.register_mut(*reg as usize) = value;
+                    Ok(())
+                }
+                _ => Err(format!("Cannot write to {}", place)),
+            }
+        }
+
+        fn peek_byte(&mut self) -> Option<u8> {
+            if let Some(byte) = self.unread.last() {
+                return Some(*byte);
+            }
+            self.input.fill_buf().ok()?.first().copied()
+        }
+
+        /// Consumes the next input byte, journaling it so `undo` can give
+        /// it back.
+        fn read_byte(&mut self) -> Option<u8> {
+            let byte = self.peek_byte()?;
+            if self.unread.pop().is_none() {
+                self.input.consume(1);
+            }
+            if !self.recording {
+                return Some(byte);
+            }
+            if let Some(record) = self.history.back_mut() {
+                match record.deltas.iter_mut().find_map(|d| match d {
+                    Delta::Input(bytes) => Some(bytes),
+                    _ => None,
+                }) {
+                    Some(bytes) => bytes.push(byte),
+                    None => record.deltas.push(Delta::Input(vec![byte])),
+                }
+            }
+            Some(byte)
+        }
+
+        /// Reads up to and excluding the next newline, `None` at end of input.
+        fn read_line(&mut self) -> Option<String> {
+            self.flush();
+            let mut line: Vec<u8> = Vec::new();
+            let mut read = false;
+            while let Some(byte) = self.read_byte() {
+                read = true;
+                if byte == b'\n' {
+                    break;
+                }
+                line.push(byte);
+            }
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            read.then(|| String::from_utf8_lossy(&line).into_owned())
+        }
+
+        fn read_char(&mut self) -> Option<char> {
+            self.flush();
+            let first = self.read_byte()?;
+            let len = match first.leading_ones() {
+                2 => 2,
+                3 => 3,
+                4 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![first];
+            while bytes.len() < len {
+                match self.peek_byte() {
+                    Some(byte) if byte & 0xC0 == 0x80 => bytes.extend(self.read_byte()),
+                    _ => break,
+                }
+            }
+            Some(
+                std::str::from_utf8(&bytes)
+                    .ok()
+                    .and_then(|s| s.chars().next())
+                    .unwrap_or(char::REPLACEMENT_CHARACTER),
+            )
+        }
+
+        /// Reads the next whitespace separated word and the line break after it,
+        /// `None` at end of input.
+        fn read_word(&mut self) -> Option<String> {
+            self.flush();
+            while self.peek_byte()?.is_ascii_whitespace() {
+                self.read_byte();
+            }
+            let mut word: Vec<u8> = Vec::new();
+            while let Some(byte) = self.peek_byte() {
+                if byte.is_ascii_whitespace() {
+                    break;
+                }
+                word.extend(self.read_byte());
+            }
+            // The newline ending the word belongs to it, not to the next line read.
+            if self.peek_byte() == Some(b'\r') {
+                self.read_byte();
+            }
+            if self.peek_byte() == Some(b'\n') {
+                self.read_byte();
+            }
+            Some(String::from_utf8_lossy(&word).into_owned())
+        }
+
+        fn base(&self, base: Option<&GeneralData>) -> Fault<u32> {
+            let base = match base.map(|b| self.load(b)).transpose()? {
+                Some(GeneralData::Int64(b)) => b,
+                Some(b) => return Err(format!("Base must be an Int64, not {:?}", b.data_type())),
+                None => return Ok(10),
+            };
+            if !(2..=36).contains(&base) {
+                return Err(format!("Base {} is not between 2 and 36", base));
+            }
+            Ok(base as u32)
+        }
+
+        /// Resolves a register or memory operand to the value it holds.
+        fn load(&self, any: &GeneralData) -> Fault<GeneralData> {
+            match any {
+                GeneralData::Register(reg) => Ok(self.registers[*reg as usize].clone()),
+                GeneralData::Memory(..) => self.read_memory(any),
+                _ => Ok(any.clone()),
+            }
+        }
+
+        /// Stores `f` of register `left` and `right` in `left`. Both must
+        /// hold the same type, one that `f` gives a result for.
+        fn arithmetic(
+            &mut self,
+            op: OpCode,
+            left: &GeneralData,
+            right: &GeneralData,
+            f: fn(&GeneralData, &GeneralData) -> Option<GeneralData>,
+        ) -> Fault {
+            let GeneralData::Register(reg) = left else {
+                return Err(format!(
+                    "{:?} needs a register, not {:?}",
+                    op,
+                    left.data_type()
+                ));
+            };
+            let id = *reg as usize;
+            let value = self.load(right)?;
+            let current = &self.registers[id];
+            if current.data_type() != value.data_type() {
+                return Err(format!(
+                    "{:?} mixes {:?} and {:?}",
+                    op,
+                    current.data_type(),
+                    value.data_type()
+                ));
+            }
+            match f(current, &value) {
+                Some(result) => *self.register_mut(id) = result,
+                None => return Err(format!("{:?} does not work on {:?}", op, value.data_type())),
+            }
+            Ok(())
+        }
+
+        /// Continues at the instruction index held by `target`, directly or
+        /// through a register or memory.
+        fn branch(&mut self, target: &GeneralData) -> Fault {
+            match self.load(target)? {
+                GeneralData::Int64(pc) => self.pc = pc,
+                other => {
+                    return Err(format!(
+                        "Jump target must be an Int64, not {:?}",
+                        other.data_type()
+                    ))
+                }
+            }
+            Ok(())
+        }
+
+        /// Fails when the instruction at `pc` left `pc` outside `0..=len`,
+        /// where `len` is a normal end of the program.
+        fn check_jump(&mut self, pc: usize, len: usize) -> Result<(), VmError> {
+            if self.pc < 0 || self.pc as u64 > len as u64 {
+                let message = format!("Jump out of program at #{}", self.pc);
+                return Err(self.fault(pc, message));
+            }
+            Ok(())
+        }
+
+        /// The error for the instruction at `pc`, which `pc` is reset to so
+        /// it can be inspected.
+        fn fault(&mut self, pc: usize, message: String) -> VmError {
+            self.pc = pc as i64;
+            VmError { pc, message }
+        }
+
+        /// Executes the instruction at `pc` in `flow`. `pc` already points
+        /// at the next instruction while it runs, so jumps simply replace it.
+        pub fn execute_istr(&mut self, flow: &[FlowStructure]) -> Result<(), VmError> {
+            let pc = self.pc as usize;
+            if self.recording {
+                if self.history.len() >= self.history_limit {
+                    self.history.pop_front();
+                }
+                self.history.push_back(UndoRecord {
+                    pc: self.pc,
+                    deltas: Vec::new(),
+                });
+            }
+            self.pc += 1;
+            self.dispatch(&flow[pc])
+                .map_err(|message| self.fault(pc, message))?;
+            self.check_jump(pc, flow.len())
+        }
+
+        fn dispatch(&mut self, istr: &FlowStructure) -> Fault {
+            match istr.op_code {
+                OpCode::MOV => self.mov(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::LEA => self.lea(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::PUSH => self.push(istr.arguments[0].clone()),
+                OpCode::POP => self.pop(&istr.arguments[0]),
+                OpCode::ADD => self.add(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::SUB => self.sub(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::MUL => self.mul(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::DIV => self.div(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::MOD => self.modu(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::CMP => self.cmp(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::JNE => self.jne(&istr.arguments[0]),
+                OpCode::JMP => self.jmp(&istr.arguments[0]),
+                OpCode::JE => self.je(&istr.arguments[0]),
+                OpCode::INC => self.inc(&istr.arguments[0]),
+                OpCode::OR => self.or(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::AND => self.and(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::XOR => self.xor(&istr.arguments[0], &istr.arguments[1]),
+                OpCode::CALL => self.call(&istr.arguments[0]),
+                OpCode::RET => self.ret(),
+                OpCode::STDOUT => self.stdout(&istr.arguments[0], istr.arguments.get(1)),
+                OpCode::STDIN => self.stdin(&istr.arguments[0], istr.arguments.get(1)),
+                OpCode::PUTC => self.putc(&istr.arguments[0]),
+                OpCode::GETC => self.getc(&istr.arguments[0]),
+                OpCode::PNL => self.pnl(&istr.arguments[0]),
+                OpCode::MALLOC | OpCode::FREE => {
+                    Err(format!("{:?} is not implemented yet", istr.op_code))
+                }
+                OpCode::SYSCALL => self.syscall(),
+                OpCode::NOP => Ok(()),
+                OpCode::HLT => self.exit(istr.arguments.first()),
+                OpCode::EXIT => self.exit(istr.arguments.first()),
+                OpCode::ASSERT_EQ => self.assert(&istr.arguments[0], &istr.arguments[1], true),
+                OpCode::ASSERT_NE => self.assert(&istr.arguments[0], &istr.arguments[1], false),
+                OpCode::COUNT => unreachable!("COUNT is not an opcode"),
+            }
+        }
+
+        /// `dispatch` for the instruction at `pc` when the fast path cannot
+        /// handle it.
+        fn slow(&mut self, pc: usize, istr: &FlowStructure) -> Result<(), VmError> {
+            self.dispatch(istr)
+                .map_err(|message| self.fault(pc, message))
+        }
+
+        /// Runs up to `budget` instructions of `code`, the lowered `flow`,
+        /// and returns how many ran. Stops early once the program halts or
+        /// `pc` reaches the end of the code. While recording, every
+        /// instruction goes through `execute_istr` so it is journaled.
+        pub fn run_decoded(
+            &mut self,
+            code: &[Op],
+            flow: &[FlowStructure],
+            budget: u64,
+        ) -> Result<u64, VmError> {
+            let mut steps: u64 = 0;
+            while steps < budget && !self.halted {
+                let pc = match usize::try_from(self.pc) {
+                    Ok(pc) if pc < code.len() => pc,
+                    _ => break,
+                };
+                steps += 1;
+                if self.recording {
+                    self.execute_istr(flow)?;
+                    continue;
+                }
+                self.pc += 1;
+
+                let regs = &mut self.registers;
+                match &code[pc] {
+                    Op::MovReg(dst, src) => regs[*dst] = regs[*src].clone(),
+                    Op::MovImm(dst, value) => regs[*dst] = value.clone(),
+                    Op::AddReg(dst, src) => match (&regs[*dst], &regs[*src]) {
+                        (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                            regs[*dst] = GeneralData::Int64(a.wrapping_add(*b))
+                        }
+                        _ => self.slow(pc, &flow[pc])?,
+                    },
+                    Op::AddInt(dst, n) => match &mut regs[*dst] {
+                        GeneralData::Int64(a) => *a = a.wrapping_add(*n),
+                        _ => self.slow(pc, &flow[pc])?,
+                    },
+                    Op::CmpReg(left, right) => match (&regs[*left], &regs[*right]) {
+                        (GeneralData::Int64(a), GeneralData::Int64(b)) => self.flags.zf = a == b,
+                        _ => self.slow(pc, &flow[pc])?,
+                    },
+                    Op::CmpInt(left, n) => match &regs[*left] {
+                        GeneralData::Int64(a) => self.flags.zf = a == n,
+                        _ => self.slow(pc, &flow[pc])?,
+                    },
+                    Op::Jmp(target) => self.pc = *target,
+                    Op::Je(target) => {
+                        if self.flags.zf {
+                            self.pc = *target;
+                        }
+                    }
+                    Op::Jne(target) => {
+                        if !self.flags.zf {
+                            self.pc = *target;
+                        }
+                    }
+                    Op::Call(target) => {
+                        self.stack.push(GeneralData::Int64(self.pc));
+                        self.pc = *target;
+                    }
+                    Op::Ret => match self.stack.last() {
+                        Some(GeneralData::Int64(target)) => {
+                            self.pc = *target;
+                            self.stack.pop();
+                        }
+                        _ => self.slow(pc, &flow[pc])?,
+                    },
+                    Op::Nop => {}
+                    Op::Generic => self.slow(pc, &flow[pc])?,
+                }
+                self.check_jump(pc, code.len())?;
+            }
+            Ok(steps)
+        }
+    }
+
+    /// SYSCALL, numbered as on x86-64 Linux with arguments in RDI, RSI and
+    /// RDX. Errors come back in RAX as negative errno values.
+    impl EnvVars {
+        fn syscall(&mut self) -> Fault {
+            let args = [
+                self.integer(Register::RDI)?,
+                self.integer(Register::RSI)?,
+                self.integer(Register::RDX)?,
+            ];
+            let result = match self.integer(Register::RAX)? {
+                SYS_READ => self.sys_read(args[0], args[1], args[2]),
+                SYS_WRITE => self.sys_write(args[0], args[1], args[2]),
+                SYS_OPEN => self.sys_open(args[0], args[1]),
+                SYS_CLOSE => match args[0] {
+                    0..=2 => Ok(0),
+                    fd => self.host.close(fd).map(|_| 0),
+                },
+                SYS_LSEEK => match args[0] {
+                    0 | 1 => Err(host::ESPIPE),
+                    fd => self.host.lseek(fd, args[1], args[2]),
+                },
+                SYS_MMAP => self.sys_mmap(args[1]),
+                SYS_BRK => self.sys_brk(args[0]),
+                SYS_EXIT | SYS_EXIT_GROUP => {
+                    self.exit_code = Some(args[0] as i32);
+                    self.halted = true;
+                    return Ok(());
+                }
+                SYS_CLOCK_GETTIME => self.sys_clock_gettime(args[0], args[1]),
+                SYS_GETRANDOM => self.guest_range(args[0], args[1]).map(|range| {
+                    let mut bytes = vec![0; range.len()];
+                    self.host.random(&mut bytes);
+                    self.write_at(range.start, &bytes);
+                    bytes.len() as i64
+                }),
+                _ => Err(host::ENOSYS),
+            };
+            *self.register_mut(Register::RAX as usize) =
+                GeneralData::Int64(result.unwrap_or_else(|errno| -errno));
+            Ok(())
+        }
+
+        fn guest_range(&self, addr: i64, len: i64) -> Result<Range<usize>, i64> {
+            if addr < 0 || len < 0 || addr.saturating_add(len) > MEMORY_SIZE as i64 {
+                return Err(host::EFAULT);
+            }
+            Ok(addr as usize..(addr + len) as usize)
+        }
+
+        /// A NUL terminated string in guest memory.
+        fn guest_string(&self, addr: i64) -> Result<String, i64> {
+            let range = self.guest_range(addr, 0)?;
+            let bytes = &self.memory[range.start..];
+            let end = bytes.iter().position(|b| *b == 0).ok_or(host::EFAULT)?;
+            Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+        }
+
+        fn sys_read(&mut self, fd: i64, buf: i64, len: i64) -> Result<i64, i64> {
+            let range = self.guest_range(buf, len)?;
+            let bytes = match fd {
+                // Like a terminal, reading the input stops after a line.
+                0 => {
+                    self.flush();
+                    let mut bytes: Vec<u8> = Vec::new();
+                    while bytes.len() < range.len() {
+                        match self.read_byte() {
+                            Some(byte) => bytes.push(byte),
+                            None => break,
+                        }
+                        if bytes.last() == Some(&b'\n') {
+                            break;
+                        }
+                    }
+                    bytes
+                }
+                1 => return Err(host::EBADF),
+                fd => self.host.read(fd, range.len())?,
+            };
+            self.write_at(range.start, &bytes);
+            Ok(bytes.len() as i64)
+        }
+
+        fn sys_write(&mut self, fd: i64, buf: i64, len: i64) -> Result<i64, i64> {
+            let range = self.guest_range(buf, len)?;
+            let bytes = self.memory[range].to_vec();
+            match fd {
+                0 => Err(host::EBADF),
+                1 => {
+                    self.print(&bytes);
+                    Ok(bytes.len() as i64)
+                }
+                fd => {
+                    // Keep stderr in order with what was printed before.
+                    self.flush();
+                    self.host.write(fd, &bytes).map(|n| n as i64)
+                }
+            }
+        }
+
+        fn sys_open(&mut self, path: i64, flags: i64) -> Result<i64, i64> {
+            let path = self.guest_string(path)?;
+            self.host.open(&path, flags)
+        }
+
+        /// Anonymous mappings only, the address hint and flags are ignored.
+        fn sys_mmap(&mut self, len: i64) -> Result<i64, i64> {
+            if len <= 0 {
+                return Err(host::EINVAL);
+            }
+            let len = (len as usize)
+                .checked_next_multiple_of(8)
+                .ok_or(host::ENOMEM)?;
+            let start = self.mapped.checked_sub(len).ok_or(host::ENOMEM)?;
+            if start < self.brk {
+                return Err(host::ENOMEM);
+            }
+            self.journal(Delta::Heap(self.brk, self.mapped));
+            self.mapped = start;
+            self.write_at(start, &vec![0; len]);
+            Ok(start as i64)
+        }
+
+        /// Moves the program break, answering with the break in effect.
+        fn sys_brk(&mut self, addr: i64) -> Result<i64, i64> {
+            if addr >= self.brk_start as i64 && addr <= self.mapped as i64 {
+                self.journal(Delta::Heap(self.brk, self.mapped));
+                self.brk = addr as usize;
+            }
+            Ok(self.brk as i64)
+        }
+
+        fn sys_clock_gettime(&mut self, clock: i64, tp: i64) -> Result<i64, i64> {
+            let range = self.guest_range(tp, 16)?;
+            let time = self.host.clock(clock)?;
+            let mut bytes = (time.as_secs() as i64).to_le_bytes().to_vec();
+            bytes.extend((time.subsec_nanos() as i64).to_le_bytes());
+            self.write_at(range.start, &bytes);
+            Ok(0)
+        }
+    }
+
+    /// `value` in `base`, with lowercase digits.
+    fn radix(value: i128, base: u32) -> String {
+        let mut digits: Vec<char> = Vec::new();
+        let mut rest = value.unsigned_abs();
+        loop {
+            digits.push(std::char::from_digit((rest % base as u128) as u32, base).unwrap());
+            rest /= base as u128;
+            if rest == 0 {
+                break;
+            }
+        }
+        if value < 0 {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    }
+
+    impl IstrTraits for EnvVars {
+        fn mov(&mut self, register: &GeneralData, any: &GeneralData) -> Fault {
+            let value = self.load(any)?;
+            self.store(register, value)
+        }
+
+        fn pop(&mut self, register: &GeneralData) -> Fault {
+            let GeneralData::Register(reg) = register else {
+                return Err(format!(
+                    "POP needs a register, not {:?}",
+                    register.data_type()
+                ));
+            };
+            let value = self.pop_value()?;
+            *self.register_mut(*reg as usize) = value;
+            Ok(())
+        }
+
+        fn push(&mut self, any: GeneralData) -> Fault {
+            let value = self.load(&any)?;
+            self.push_value(value);
+            Ok(())
+        }
+
+        fn add(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::ADD, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => {
+                        GeneralData::Uint32(a.wrapping_add(*b))
+                    }
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => {
+                        GeneralData::Uint64(a.wrapping_add(*b))
+                    }
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => {
+                        GeneralData::Int32(a.wrapping_add(*b))
+                    }
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                        GeneralData::Int64(a.wrapping_add(*b))
+                    }
+                    (GeneralData::Float(a), GeneralData::Float(b)) => GeneralData::Float(a + b),
+                    (GeneralData::Double(a), GeneralData::Double(b)) => GeneralData::Double(a + b),
+                    (GeneralData::Char(a), GeneralData::Char(b)) => {
+                        GeneralData::Char((*a as u8).wrapping_add(*b as u8) as char)
+                    }
+                    (GeneralData::String(a), GeneralData::String(b)) => {
+                        GeneralData::from(format!("{}{}", a, b).as_str())
+                    }
+                    _ => return None,
+                })
+            })
+        }
+
+        fn sub(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::SUB, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => {
+                        GeneralData::Uint32(a.wrapping_sub(*b))
+                    }
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => {
+                        GeneralData::Uint64(a.wrapping_sub(*b))
+                    }
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => {
+                        GeneralData::Int32(a.wrapping_sub(*b))
+                    }
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                        GeneralData::Int64(a.wrapping_sub(*b))
+                    }
+                    (GeneralData::Float(a), GeneralData::Float(b)) => GeneralData::Float(a - b),
+                    (GeneralData::Double(a), GeneralData::Double(b)) => GeneralData::Double(a - b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn mul(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::MUL, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => {
+                        GeneralData::Uint32(a.wrapping_mul(*b))
+                    }
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => {
+                        GeneralData::Uint64(a.wrapping_mul(*b))
+                    }
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => {
+                        GeneralData::Int32(a.wrapping_mul(*b))
+                    }
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                        GeneralData::Int64(a.wrapping_mul(*b))
+                    }
+                    (GeneralData::Float(a), GeneralData::Float(b)) => GeneralData::Float(a * b),
+                    (GeneralData::Double(a), GeneralData::Double(b)) => GeneralData::Double(a * b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn div(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            if self.load(right)?.as_i64() == Some(0) {
+                return Err(String::from("DIV by zero"));
+            }
+            self.arithmetic(OpCode::DIV, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => GeneralData::Uint32(a / b),
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => GeneralData::Uint64(a / b),
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => {
+                        GeneralData::Int32(a.wrapping_div(*b))
+                    }
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                        GeneralData::Int64(a.wrapping_div(*b))
+                    }
+                    (GeneralData::Float(a), GeneralData::Float(b)) => GeneralData::Float(a / b),
+                    (GeneralData::Double(a), GeneralData::Double(b)) => GeneralData::Double(a / b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn modu(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            if self.load(right)?.as_i64() == Some(0) {
+                return Err(String::from("MOD by zero"));
+            }
+            self.arithmetic(OpCode::MOD, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => GeneralData::Uint32(a % b),
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => GeneralData::Uint64(a % b),
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => {
+                        GeneralData::Int32(a.wrapping_rem(*b))
+                    }
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => {
+                        GeneralData::Int64(a.wrapping_rem(*b))
+                    }
+                    (GeneralData::Float(a), GeneralData::Float(b)) => GeneralData::Float(a % b),
+                    (GeneralData::Double(a), GeneralData::Double(b)) => GeneralData::Double(a % b),
+                    _ => return None,
+                })
+            })?;
+            let zero = match self.load(left)? {
+                GeneralData::Float(a) => a == 0.0,
+                GeneralData::Double(a) => a == 0.0,
+                other => other.as_i64() == Some(0),
+            };
+            self.set_zf(zero);
+            Ok(())
+        }
+
+        fn inc(&mut self, register: &GeneralData) -> Fault {
+            let GeneralData::Register(reg) = register else {
+                return Err(format!(
+                    "INC needs a register, not {:?}",
+                    register.data_type()
+                ));
+            };
+            let id = *reg as usize;
+            let next = match &self.registers[id] {
+                GeneralData::Uint32(a) => GeneralData::Uint32(a.wrapping_add(1)),
+                GeneralData::Uint64(a) => GeneralData::Uint64(a.wrapping_add(1)),
+                GeneralData::Int32(a) => GeneralData::Int32(a.wrapping_add(1)),
+                GeneralData::Int64(a) => GeneralData::Int64(a.wrapping_add(1)),
+                GeneralData::Float(a) => GeneralData::Float(a + 1.0),
+                GeneralData::Double(a) => GeneralData::Double(a + 1.0),
+                other => return Err(format!("INC does not work on {:?}", other.data_type())),
+            };
+            *self.register_mut(id) = next;
+            Ok(())
+        }
+
+        fn or(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::OR, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => GeneralData::Uint32(a | b),
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => GeneralData::Uint64(a | b),
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => GeneralData::Int32(a | b),
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => GeneralData::Int64(a | b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn and(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::AND, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => GeneralData::Uint32(a & b),
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => GeneralData::Uint64(a & b),
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => GeneralData::Int32(a & b),
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => GeneralData::Int64(a & b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn xor(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            self.arithmetic(OpCode::XOR, left, right, |a, b| {
+                Some(match (a, b) {
+                    (GeneralData::Uint32(a), GeneralData::Uint32(b)) => GeneralData::Uint32(a ^ b),
+                    (GeneralData::Uint64(a), GeneralData::Uint64(b)) => GeneralData::Uint64(a ^ b),
+                    (GeneralData::Int32(a), GeneralData::Int32(b)) => GeneralData::Int32(a ^ b),
+                    (GeneralData::Int64(a), GeneralData::Int64(b)) => GeneralData::Int64(a ^ b),
+                    _ => return None,
+                })
+            })
+        }
+
+        fn lea(&mut self, register: &GeneralData, address: &GeneralData) -> Fault {
+            let GeneralData::Register(reg) = register else {
+                return Err(format!(
+                    "LEA needs a register, not {:?}",
+                    register.data_type()
+                ));
+            };
+            let addr = match address {
+                GeneralData::Memory(..) => self.effective_address(address, 0)? as i64,
+                GeneralData::Int64(addr) => *addr,
+                other => return Err(format!("LEA needs an address, not {:?}", other.data_type())),
+            };
+            *self.register_mut(*reg as usize) = GeneralData::Int64(addr);
+            Ok(())
+        }
+
+        fn jmp(&mut self, address: &GeneralData) -> Fault {
+            self.branch(address)
+        }
+
+        fn jne(&mut self, address: &GeneralData) -> Fault {
+            if !self.flags.zf {
+                return self.branch(address);
+            }
+            Ok(())
+        }
+
+        fn je(&mut self, address: &GeneralData) -> Fault {
+            if self.flags.zf {
+                return self.branch(address);
+            }
+            Ok(())
+        }
+
+        fn call(&mut self, address: &GeneralData) -> Fault {
+            let target = self.load(address)?;
+            self.push_value(GeneralData::Int64(self.pc));
+            self.branch(&target)
+        }
+
+        fn ret(&mut self) -> Fault {
+            let address = self.pop_value()?;
+            self.branch(&address)
+        }
+
+        fn pnl(&mut self, any: &GeneralData) -> Fault {
+            let value = self.load(any)?;
+            self.print(format!("{}\n", value).as_bytes());
+            Ok(())
+        }
+
+        fn stdout(&mut self, any: &GeneralData, base: Option<&GeneralData>) -> Fault {
+            let value = self.load(any)?;
+            let base = self.base(base)?;
+            let text = match (&value, base) {
+                (_, 10) => value.to_string(),
+                (GeneralData::Uint64(v), _) => radix(*v as i128, base),
+                (v, _) => match v.as_i64() {
+                    Some(n) => radix(n as i128, base),
+                    None => {
+                        return Err(format!(
+                            "Only integers can be written in base {}, not {:?}",
+                            base,
+                            v.data_type()
+                        ))
+                    }
+                },
+            };
+            self.print(text.as_bytes());
+            Ok(())
+        }
+
+        /// Sets ZF at the end of input. A word that is not a number in
+        /// `base` is an error rather than the end.
+        fn stdin(&mut self, place: &GeneralData, base: Option<&GeneralData>) -> Fault {
+            let in_memory = matches!(place, GeneralData::Memory(..));
+            let value = match base {
+                None => match self.read_line() {
+                    Some(line) => {
+                        if in_memory {
+                            let mut bytes = line.clone().into_bytes();
+                            bytes.push(0);
+                            self.write_bytes(place, &bytes)?;
+                        }
+                        Some(GeneralData::from(line.as_str()))
+                    }
+                    None => None,
+                },
+                Some(_) => {
+                    let base = self.base(base)?;
+                    match self.read_word() {
+                        Some(word) => match i64::from_str_radix(&word, base) {
+                            Ok(n) => Some(GeneralData::Int64(n)),
+                            Err(_) => {
+                                return Err(format!(
+                                    "STDIN read {:?}, not a base {} integer",
+                                    word, base
+                                ))
+                            }
+                        },
+                        None => None,
+                    }
+                }
+            };
+            self.set_zf(value.is_none());
+            match value {
+                // A line in memory was already written out as bytes.
+                Some(_) if in_memory && base.is_none() => Ok(()),
+                Some(value) => self.store(place, value),
+                None => Ok(()),
+            }
+        }
+
+        fn putc(&mut self, any: &GeneralData) -> Fault {
+            let value = self.load(any)?;
+            let c = match value {
+                GeneralData::Char(c) => c,
+                // Any integer width, code points that are not chars print as U+FFFD.
+                other => match other.as_i64() {
+                    Some(n) => u32::try_from(n)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .unwrap_or(char::REPLACEMENT_CHARACTER),
+                    None => {
+                        return Err(format!(
+                            "PUTC takes a char or a code point, not {:?}",
+                            other.data_type()
+                        ))
+                    }
+                },
+            };
+            self.print(c.to_string().as_bytes());
+            Ok(())
+        }
+
+        fn getc(&mut self, place: &GeneralData) -> Fault {
+            let c = self.read_char();
+            self.set_zf(c.is_none());
+            match c {
+                Some(c) => self.store(place, GeneralData::Char(c)),
+                None => Ok(()),
+            }
+        }
+
+        fn cmp(&mut self, left: &GeneralData, right: &GeneralData) -> Fault {
+            let l_data = self.load(left)?;
+            let r_data = self.load(right)?;
+
+            if l_data.data_type() != r_data.data_type() {
+                return Err(format!(
+                    "CMP mixes {:?} and {:?}",
+                    l_data.data_type(),
+                    r_data.data_type()
+                ));
+            }
+            match l_data {
+                GeneralData::Register(_) | GeneralData::Memory(..) => self.set_zf(false),
+                _ => self.set_zf(l_data == r_data),
+            }
+            Ok(())
+        }
+
+        fn assert(&mut self, left: &GeneralData, right: &GeneralData, equal: bool) -> Fault {
+            let l_data = self.load(left)?;
+            let r_data = self.load(right)?;
+            if (l_data == r_data) == equal {
+                return Ok(());
+            }
+            let show = |data: &GeneralData| match data {
+                GeneralData::String(text) => format!("{:?}", text),
+                GeneralData::Char(c) => format!("{:?}", c),
+                _ => data.to_string(),
+            };
+            if equal {
+                return Err(format!(
+                    "ASSERT_EQ failed: {} ({:?}) is not {} ({:?})",
+                    show(&l_data),
+                    l_data.data_type(),
+                    show(&r_data),
+                    r_data.data_type()
+                ));
+            }
+            Err(format!("ASSERT_NE failed: both are {}", show(&l_data)))
+        }
+    }
+}
+
+pub mod parser {
+    use std::fmt::{Display, Formatter};
+
+    #[derive(Debug, PartialEq)]
+    pub enum ParserError {
+        EOF,
+        NAC,
+    }
+
+    /// A token that could not be read, at its 1-based line and 0-based column.
+    #[derive(Debug, Clone)]
+    pub struct LexError {
+        pub line: usize,
+        pub column: usize,
+        pub message: String,
+    }
+
+    impl Display for LexError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}:{}: {}", self.line, self.column + 1, self.message)
+        }
+    }
+
+    pub struct Parser {
+        stream: Vec<char>,
+        line: usize,
+        column: usize,
+        consumed: String,
+        pub tokens: Vec<String>,
+        /// Each token as written in the source, quotes included.
+        pub raw_tokens: Vec<String>,
+        pub lines: Vec<usize>,
+        pub columns: Vec<usize>,
+        /// Statement each token belongs to. A statement is one line, or
+        /// several joined by a trailing `\`.
+        pub statements: Vec<usize>,
+        /// Lines ending in a `\` continuation.
+        pub continuations: Vec<usize>,
+        pub errors: Vec<LexError>,
+    }
+
+    impl Parser {
+        pub fn init(string: String) -> Self {
+            let vec: Vec<char> = string.chars().rev().take(string.len()).collect();
+            Parser {
+                stream: vec,
+                line: 1,
+                column: 0,
+                consumed: String::new(),
+                tokens: Vec::new(),
+                raw_tokens: Vec::new(),
+                lines: Vec::new(),
+                columns: Vec::new(),
+                statements: Vec::new(),
+                continuations: Vec::new(),
+                errors: Vec::new(),
+            }
+        }
+
+        fn read(&self) -> Result<char, ParserError> {
+            if self.stream.is_empty() {
+                return Err(ParserError::EOF);
+            }
+            let c: char = self.stream[self.stream.len() - 1];
+
+            Ok(c)
+        }
+
+        fn consume_char(&mut self) -> Result<char, ParserError> {
+            if self.stream.is_empty() {
+                return Err(ParserError::EOF);
+            }
+
+            let chr = self.stream.pop().unwrap();
+            self.consumed.push(chr);
+            if chr == '\n' {
+                self.line += 1;
+                self.column = 0;
+            } else {
+                self.column += 1;
+            }
+
+            Ok(chr)
+        }
+
+        fn error(&mut self, line: usize, column: usize, message: String) {
+            self.errors.push(LexError {
+                line,
+                column,
+                message,
+            });
+        }
+
+        /// Reads `count` hex digits, or up to `count` when `closing` ends
+        /// the sequence early.
+        fn read_hex(&mut self, count: usize, closing: Option<char>) -> Option<u32> {
+            let mut digits = String::new();
+            while digits.len() < count {
+                match self.read() {
+                    Ok(c) if closing == Some(c) => break,
+                    Ok(c) if c.is_ascii_hexdigit() => digits.push(self.consume_char().ok()?),
+                    _ => break,
+                }
+            }
+            if closing.is_none() && digits.len() != count {
+                return None;
+            }
+            u32::from_str_radix(&digits, 16).ok()
+        }
+
+        /// Decodes the escape sequence starting at the `\`, reporting
+        /// unknown or malformed ones.
+        fn read_escape(&mut self) -> Option<char> {
+            let (line, column) = (self.line, self.column);
+            self.consume_char().ok()?;
+            let c = match self.read() {
+                Ok('\n') | Err(_) => return None,
+                Ok(c) => self.consume_char().unwrap_or(c),
+            };
+
+            let decoded = match c {
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                'r' => Some('\r'),
+                '0' => Some('\0'),
+                '\\' | '"' | '\'' => Some(c),
+                'x' => self.read_hex(2, None).and_then(char::from_u32),
+                'u' => {
+                    if self.read() == Ok('{') {
+                        self.consume_char().ok()?;
+                        let code = self.read_hex(6, Some('}'));
+                        if self.read() == Ok('}') {
+                            self.consume_char().ok()?;
+                            code.and_then(char::from_u32)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                }
+                _ => {
+                    self.error(line, column, format!("unknown escape '\\{}'", c));
+                    return Some(c);
+                }
+            };
+            if decoded.is_none() {
+                self.error(line, column, format!("malformed escape '\\{}'", c));
+            }
+            decoded
+        }
+
+        /// Reads a literal delimited by `quote`, decoding escapes. A literal
+        /// still open at the end of the line is reported and ends there.
+        fn read_quoted(&mut self, quote: char) -> String {
+            let (line, column) = (self.line, self.column);
+            let mut str: String = String::new();
+            let _ = self.consume_char();
+
+            loop {
+                match self.read() {
+                    Ok(c) if c == quote => {
+                        let _ = self.consume_char();
+                        break;
+                    }
+                    Ok('\\') => str.extend(self.read_escape()),
+                    Ok(c) if c != '\n' && c != '\r' => {
+                        let _ = self.consume_char();
+                        str.push(c);
+                    }
+                    _ => {
+                        self.error(line, column, String::from("unterminated literal"));
+                        break;
+                    }
+                }
+            }
+
+            str
+        }
+
+        fn read_string(&mut self) -> String {
+            self.read_quoted('"')
+        }
+
+        fn read_comment(&mut self) -> String {
+            let mut comment: String = String::new();
+            let trim: [char; 2] = ['\n', '\r'];
+
+            while let Ok(c) = self.consume_char() {
+                if trim.contains(&c) {
+                    break;
+                }
+                comment.push(c);
+            }
+            comment
+        }
 
         fn read_char(&mut self) -> String {
-            let mut str = String::new();
-            let mut stack: Vec<char> = vec![self.consume_char().unwrap()];
+            let (line, column) = (self.line, self.column);
+            let errors = self.errors.len();
+            let str = self.read_quoted('\'');
 
-            while stack.len() > 0 {
-                let c = self.consume_char().unwrap();
+            if errors == self.errors.len() && str.chars().count() != 1 {
+                let message = String::from("char literal must hold exactly one character");
+                self.error(line, column, message);
+            }
+            str
+        }
 
-                if c == '\'' {
-                    stack.pop();
-                    continue;
+        /// Reads a `[...]` memory operand or `(...)` expression as one
+        /// token, dropping the spaces inside.
+        fn read_group(&mut self, open: char, close: char) -> String {
+            let (line, column) = (self.line, self.column);
+            let mut str: String = String::new();
+            let mut depth = 0;
+
+            loop {
+                match self.read() {
+                    Ok(c) if c != '\n' && c != '\r' && c != ';' => {
+                        let _ = self.consume_char();
+                        if c == open {
+                            depth += 1;
+                        } else if c == close {
+                            depth -= 1;
+                        }
+                        if !c.is_whitespace() {
+                            str.push(c);
+                        }
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {
+                        let message = format!("missing '{}'", close);
+                        self.error(line, column, message);
+                        break;
+                    }
                 }
-                str.push(c);
             }
 
             str
@@ -748,11 +2379,17 @@ pub mod parser {
         fn read_raw(&mut self) -> String {
             let mut raw: String = String::new();
             let trim: [char; 5] = [' ', ',', '\n', '\r', '\t'];
-            let mut c: char = self.consume_char().unwrap();
 
-            while self.stream.len() > 0 && !trim.contains(&c) {
+            while let Ok(c) = self.read() {
+                // A `\` ends the token so `5\` continues the line too.
+                if c == '\\' {
+                    break;
+                }
+                let _ = self.consume_char();
+                if trim.contains(&c) {
+                    break;
+                }
                 raw.push(c);
-                c = self.consume_char().unwrap();
             }
 
             raw
@@ -764,6 +2401,15 @@ pub mod parser {
                 '"' => Some(self.read_string()),
                 ';' => Some(self.read_comment()),
                 '\'' => Some(self.read_char()),
+                '[' => Some(self.read_group('[', ']')),
+                '(' => {
+                    let group = self.read_group('(', ')');
+                    Some(group + &self.read_raw())
+                }
+                '\\' => {
+                    let _ = self.consume_char();
+                    Some(String::from("\\"))
+                }
                 _ => {
                     let raw = self.read_raw();
 
@@ -777,9 +2423,41 @@ pub mod parser {
         }
 
         pub fn parse(&mut self) -> &Vec<String> {
-            while self.stream.len() > 0 {
+            let mut statement: usize = 0;
+            let mut last_line: usize = 0;
+            let mut continued = false;
+
+            while !self.stream.is_empty() {
+                let (line, column) = (self.line, self.column);
+                self.consumed.clear();
                 match self.next() {
-                    Some(token) => self.tokens.push(token),
+                    Some(token) => {
+                        let delimiters: &[char] = &[' ', ',', '\n', '\r', '\t'];
+                        let raw = String::from(self.consumed.trim_end_matches(delimiters));
+                        let comment = raw.starts_with(';');
+
+                        if line != last_line {
+                            if !continued {
+                                statement += 1;
+                            }
+                            // Comment lines inside a continuation keep it going.
+                            continued = continued && comment;
+                            last_line = line;
+                        } else if continued && !comment {
+                            self.error(line, column, String::from("'\\' must end the line"));
+                        }
+                        if raw == "\\" {
+                            continued = true;
+                            self.continuations.push(line);
+                            continue;
+                        }
+
+                        self.raw_tokens.push(raw);
+                        self.tokens.push(token);
+                        self.lines.push(line);
+                        self.columns.push(column);
+                        self.statements.push(statement);
+                    }
                     None => continue,
                 }
             }
@@ -789,19 +2467,104 @@ pub mod parser {
     }
 }
 
+pub mod syntax {
+    use crate::structures::flow_structure::{Directive, OpCode};
+    use crate::structures::parser::Parser;
+    use crate::structures::tokenizer::Tokenizer;
+
+    /// One source line, with everything needed to write it back out.
+    #[derive(Debug, Default)]
+    pub struct Statement {
+        /// 1-based source line.
+        pub line: usize,
+        /// Labels defined on this line, without the `:`.
+        pub labels: Vec<String>,
+        pub op_code: Option<OpCode>,
+        pub directive: Option<Directive>,
+        /// The mnemonic or directive as written.
+        pub mnemonic: Option<String>,
+        /// Operands as written, quotes included.
+        pub operands: Vec<String>,
+        /// Trailing comment, `;` included.
+        pub comment: Option<String>,
+        pub comment_column: usize,
+        /// The line ends in a `\` and the statement goes on below.
+        pub continued: bool,
+    }
+
+    impl Statement {
+        pub fn is_blank(&self) -> bool {
+            self.labels.is_empty()
+                && self.mnemonic.is_none()
+                && self.operands.is_empty()
+                && self.comment.is_none()
+                && !self.continued
+        }
+    }
+
+    /// Comment-preserving, line-based view of a parsed program.
+    pub struct SyntaxTree {
+        pub statements: Vec<Statement>,
+    }
+
+    impl SyntaxTree {
+        pub fn from_parser(parser: &Parser) -> Self {
+            let last_line = parser
+                .lines
+                .last()
+                .max(parser.continuations.last())
+                .copied()
+                .unwrap_or(0);
+            let mut statements: Vec<Statement> = (1..=last_line)
+                .map(|line| Statement {
+                    line,
+                    continued: parser.continuations.contains(&line),
+                    ..Statement::default()
+                })
+                .collect();
+
+            for (i, raw) in parser.raw_tokens.iter().enumerate() {
+                let statement = &mut statements[parser.lines[i] - 1];
+
+                if raw.starts_with(';') {
+                    statement.comment = Some(raw.clone());
+                    statement.comment_column = parser.columns[i];
+                } else if let Some(label) = Tokenizer::label_name(raw) {
+                    statement.labels.push(String::from(label));
+                } else if statement.mnemonic.is_none()
+                    && statement.operands.is_empty()
+                    && (OpCode::isop(raw) || Directive::from_string(raw).is_some())
+                {
+                    statement.op_code = OpCode::from_string(raw);
+                    statement.directive = Directive::from_string(raw);
+                    statement.mnemonic = Some(raw.clone());
+                } else {
+                    statement.operands.push(raw.clone());
+                }
+            }
+
+            SyntaxTree { statements }
+        }
+    }
+}
+
 pub mod tokens {
-    use crate::structures::data_types::{AnyData, DataType};
-    use crate::structures::flow_structure::OpCode;
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::flow_structure::{Directive, OpCode};
     use crate::structures::registers::Register;
     use std::fmt::{Display, Formatter};
 
-    pub type TokensData = AnyData;
-
     pub enum Tokens {
         CHECKPOINT(String),
+        /// A label used as a value.
         GOTO(String),
-        DATA(DataType, TokensData),
+        /// An expression resolved once every label has an address.
+        EXPR(String),
+        /// `[base+disp]`, with the displacement still an expression.
+        MEMORY(Register, String),
+        DATA(GeneralData),
         INSTRUCTION(OpCode),
+        DIRECTIVE(Directive),
         REGISTER(Register),
         COMMENT(String),
     }
@@ -811,18 +2574,16 @@ pub mod tokens {
             match self {
                 Tokens::CHECKPOINT(str) => f.write_fmt(format_args!("<Checkpoint {}>", str)),
                 Tokens::GOTO(str) => f.write_fmt(format_args!("<Goto {}>", str)),
-                Tokens::DATA(t, d) => match t {
-                    DataType::Uint32 => f.write_fmt(format_args!("<Uint32 {}>", d.uint32)),
-                    DataType::Uint64 => f.write_fmt(format_args!("<Uint64 {}>", d.uint64)),
-                    DataType::Int32 => f.write_fmt(format_args!("<Int32 {}>", d.int32)),
-                    DataType::Int64 => f.write_fmt(format_args!("<Int64 {}>", d.int64)),
-                    DataType::Float => f.write_fmt(format_args!("<Float {}>", d.float)),
-                    DataType::Double => f.write_fmt(format_args!("<Double {}>", d.double)),
-                    DataType::String => f.write_fmt(format_args!("<String {}>", d.string.as_str())),
-                    DataType::Char => f.write_fmt(format_args!("<Char {}>", d.char)),
-                    DataType::Register => f.write_fmt(format_args!("<Register {:?}>", d.register)),
-                },
+                Tokens::EXPR(str) => f.write_fmt(format_args!("<Expr {}>", str)),
+                Tokens::MEMORY(reg, disp) => {
+                    f.write_fmt(format_args!("<Memory {:?}{}>", reg, disp))
+                }
+                Tokens::DATA(GeneralData::Memory(reg, disp)) => {
+                    f.write_fmt(format_args!("<Memory {:?}{:+}>", reg, disp))
+                }
+                Tokens::DATA(d) => f.write_fmt(format_args!("<{:?} {}>", d.data_type(), d)),
                 Tokens::INSTRUCTION(istr) => f.write_fmt(format_args!("<Instruction {:?}>", istr)),
+                Tokens::DIRECTIVE(dir) => f.write_fmt(format_args!("<Directive {:?}>", dir)),
                 Tokens::REGISTER(reg) => f.write_fmt(format_args!("<Register {:?}>", reg)),
                 Tokens::COMMENT(str) => f.write_fmt(format_args!("<Comment \"{}\"", str)),
             }
@@ -831,147 +2592,502 @@ pub mod tokens {
 }
 
 mod stoi {
+    use crate::structures::data_types::{DataType, GeneralData};
+
     pub struct Stoi {}
 
+    const SUFFIXES: [(&str, DataType); 6] = [
+        ("u32", DataType::Uint32),
+        ("u64", DataType::Uint64),
+        ("i32", DataType::Int32),
+        ("i64", DataType::Int64),
+        ("f32", DataType::Float),
+        ("f64", DataType::Double),
+    ];
+
     impl Stoi {
-        fn stoi(str: &String, r: i64) -> i64 {
-            let c_table: Vec<char> = vec![
-                '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F',
-            ];
-            let mut chars = str.chars();
-            let mut mlt: i64 = -1;
-            let mut val: i64 = 0;
+        /// True when `str` starts like a number, i.e. a digit after an
+        /// optional sign.
+        pub fn is_numeric(str: &str) -> bool {
+            str.trim_start_matches(['-', '+'])
+                .starts_with(|c: char| c.is_ascii_digit())
+        }
 
-            if !(chars.next() == Some('-')) {
-                mlt *= -1;
-                chars = str.chars();
+        fn split_suffix(body: &str, base: u32) -> (&str, Option<DataType>) {
+            for (suffix, t) in SUFFIXES {
+                // In hex the `f` suffixes would read as digits.
+                if base == 16 && suffix.starts_with('f') {
+                    continue;
+                }
+                if let Some(digits) = body.strip_suffix(suffix) {
+                    return (digits.trim_end_matches('_'), Some(t));
+                }
             }
-            let mut d: i64 = 1;
-            loop {
-                match chars.next_back() {
-                    Some(v) => match c_table.binary_search(&v) {
-                        Ok(i) => {
-                            val += i as i64 * d;
-                            d *= r;
-                        }
-                        Err(_) => break,
-                    },
-                    None => break,
+            (body, None)
+        }
+
+        fn int_data(t: DataType, value: i128) -> Option<GeneralData> {
+            Some(match t {
+                DataType::Uint32 => GeneralData::from(u32::try_from(value).ok()?),
+                DataType::Uint64 => GeneralData::from(u64::try_from(value).ok()?),
+                DataType::Int32 => GeneralData::from(i32::try_from(value).ok()?),
+                DataType::Int64 => GeneralData::from(i64::try_from(value).ok()?),
+                _ => return None,
+            })
+        }
+
+        /// Parses a numeric literal such as `-42`, `0xff_ff`, `0b1010u32`,
+        /// `1.5`, `1e-3` or `2.0f32`. Integers default to `Int64` and
+        /// floats to `Double` unless a `u32`/`u64`/`i32`/`i64`/`f32`/`f64`
+        /// suffix says otherwise. Values out of range for their type are an
+        /// error rather than wrapping.
+        pub fn to_number(str: &str) -> Result<GeneralData, String> {
+            let invalid = || format!("invalid number literal '{}'", str);
+            let (negative, unsigned) = match str.strip_prefix('-') {
+                Some(rest) => (true, rest),
+                None => (false, str.strip_prefix('+').unwrap_or(str)),
+            };
+            let lower = unsigned.to_lowercase();
+            let (base, body) = match lower.get(..2) {
+                Some("0x") => (16, &lower[2..]),
+                Some("0o") => (8, &lower[2..]),
+                Some("0b") => (2, &lower[2..]),
+                _ => (10, lower.as_str()),
+            };
+            let (digits, suffix) = Stoi::split_suffix(body, base);
+            let digits: String = digits.chars().filter(|c| *c != '_').collect();
+            if digits.is_empty() || (base == 10 && digits.starts_with(['.', 'e'])) {
+                return Err(invalid());
+            }
+
+            let is_float = base == 10 && digits.contains(['.', 'e']);
+            let t = suffix.unwrap_or(if is_float {
+                DataType::Double
+            } else {
+                DataType::Int64
+            });
+            let out_of_range = || format!("number literal '{}' out of range for {:?}", str, t);
+
+            if is_float || t == DataType::Float || t == DataType::Double {
+                if !matches!(t, DataType::Float | DataType::Double) {
+                    return Err(invalid());
                 }
+                let text = format!("{}{}", if negative { "-" } else { "" }, digits);
+                let data = if t == DataType::Float {
+                    let value: f32 = text.parse().map_err(|_| invalid())?;
+                    value.is_finite().then(|| GeneralData::from(value))
+                } else {
+                    let value: f64 = text.parse().map_err(|_| invalid())?;
+                    value.is_finite().then(|| GeneralData::from(value))
+                };
+                return data.ok_or_else(out_of_range);
+            }
+
+            let mut value: i128 = 0;
+            for c in digits.chars() {
+                let digit = c.to_digit(base).ok_or_else(invalid)?;
+                value = value
+                    .checked_mul(base as i128)
+                    .and_then(|v| v.checked_add(digit as i128))
+                    .ok_or_else(out_of_range)?;
+            }
+            if negative {
+                value = -value;
             }
-            val * mlt
+            Stoi::int_data(t, value).ok_or_else(out_of_range)
+        }
+    }
+}
+
+mod expr {
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::stoi::Stoi;
+
+    /// Integer expressions over numbers and symbols, such as `table+8`,
+    /// `end-start` or `(N*4)`, evaluated at assembly time.
+    pub struct Expr<'a> {
+        chars: Vec<char>,
+        pos: usize,
+        lookup: &'a dyn Fn(&str) -> Option<i64>,
+    }
+
+    impl<'a> Expr<'a> {
+        /// True when `token` can only be read as an expression.
+        pub fn is_expression(token: &str) -> bool {
+            token.starts_with('(')
+                || token
+                    .get(1..)
+                    .is_some_and(|rest| rest.contains(['+', '-', '*', '/', '%']))
         }
 
-        pub fn to_int(str: &String) -> Option<i64> {
-            let prefix: String = str.chars().take(2).collect();
-            let base: usize = match prefix.as_str() {
-                "0b" => 2,
-                "0o" => 8,
-                "0x" => 16,
-                _ => 10,
+        pub fn eval(text: &str, lookup: &'a dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+            let mut expr = Expr {
+                chars: text.chars().collect(),
+                pos: 0,
+                lookup,
             };
-            let chars: Vec<char> = vec![
-                '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F',
-            ];
-            let sub = &chars[0..base];
-            let mut cs = str.chars();
-            let fc = cs.next();
-            if !(fc == Some('-')) {
-                cs = str.chars();
+            let value = expr.sum()?;
+            match expr.peek() {
+                None => Ok(value),
+                Some(c) => Err(format!("unexpected '{}' in expression", c)),
+            }
+        }
+
+        fn peek(&self) -> Option<char> {
+            self.chars.get(self.pos).copied()
+        }
+
+        fn sum(&mut self) -> Result<i64, String> {
+            let mut value = self.product()?;
+            while let Some(op @ ('+' | '-')) = self.peek() {
+                self.pos += 1;
+                let right = self.product()?;
+                value = if op == '+' {
+                    value.checked_add(right)
+                } else {
+                    value.checked_sub(right)
+                }
+                .ok_or("expression overflows")?;
+            }
+            Ok(value)
+        }
+
+        fn product(&mut self) -> Result<i64, String> {
+            let mut value = self.unary()?;
+            while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+                self.pos += 1;
+                let right = self.unary()?;
+                if op != '*' && right == 0 {
+                    return Err(String::from("division by zero in expression"));
+                }
+                value = match op {
+                    '*' => value.checked_mul(right),
+                    '/' => value.checked_div(right),
+                    _ => value.checked_rem(right),
+                }
+                .ok_or("expression overflows")?;
             }
-            if base != 10 {
-                cs.next();
-                cs.next();
+            Ok(value)
+        }
+
+        fn unary(&mut self) -> Result<i64, String> {
+            match self.peek() {
+                Some('-') => {
+                    self.pos += 1;
+                    self.unary()?
+                        .checked_neg()
+                        .ok_or(String::from("expression overflows"))
+                }
+                Some('+') => {
+                    self.pos += 1;
+                    self.unary()
+                }
+                _ => self.atom(),
             }
-            for c in cs {
-                if !sub.contains(&c) {
-                    return None;
+        }
+
+        fn atom(&mut self) -> Result<i64, String> {
+            if self.peek() == Some('(') {
+                self.pos += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err(String::from("missing ')' in expression"));
                 }
+                self.pos += 1;
+                return Ok(value);
+            }
+
+            let start = self.pos;
+            while self
+                .peek()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.')
+            {
+                self.pos += 1;
+            }
+            let word: String = self.chars[start..self.pos].iter().collect();
+            if word.is_empty() {
+                return Err(match self.peek() {
+                    Some(c) => format!("unexpected '{}' in expression", c),
+                    None => String::from("unexpected end of expression"),
+                });
+            }
+            if !Stoi::is_numeric(&word) {
+                return (self.lookup)(&word).ok_or(format!("undefined symbol '{}'", word));
+            }
+            match Stoi::to_number(&word)? {
+                GeneralData::Uint64(v) => i64::try_from(v).map_err(|e| e.to_string()),
+                d => d.as_i64().ok_or(format!("'{}' is not an integer", word)),
             }
-            Some(Stoi::stoi(str, base as i64))
         }
     }
 }
 
 pub mod tokenizer {
-    use crate::structures::data_types::{AnyData, DataType};
+    use crate::structures::data_types::GeneralData;
     use crate::structures::registers::Register;
     // use crate::structures::data_types::DataType::Register;
-    use crate::structures::flow_structure::OpCode;
+    use crate::structures::expr::Expr;
+    use crate::structures::flow_structure::{Directive, OpCode, Operand};
+    use crate::structures::parser::LexError;
     use crate::structures::parser::Parser;
     use crate::structures::stoi::Stoi;
     use crate::structures::tokens::Tokens;
+    use std::collections::HashSet;
 
     pub struct Tokenizer {
         parser: Parser,
         pos: usize,
         tokens: Vec<Tokens>,
-        cp: Vec<String>,
-        lop: Option<OpCode>,
+        /// Label names, without the colon.
+        cp: HashSet<String>,
+        pub errors: Vec<LexError>,
     }
 
     impl Tokenizer {
         pub fn init(parsed_arg: Parser) -> Self {
             Tokenizer {
+                errors: parsed_arg.errors.clone(),
                 parser: parsed_arg,
                 pos: 0,
                 tokens: Vec::new(),
-                cp: Vec::new(),
-                lop: None,
+                cp: HashSet::new(),
             }
         }
 
-        fn iscp(token: &String) -> bool {
-            token.starts_with(":")
+        /// The name a `:name` or `name:` label token defines.
+        pub fn label_name(token: &str) -> Option<&str> {
+            if token.starts_with([';', '"', '\'']) {
+                return None;
+            }
+            token
+                .strip_prefix(':')
+                .or_else(|| token.strip_suffix(':'))
+                .filter(|name| !name.is_empty())
         }
 
-        fn iscomment(token: &String) -> bool {
-            token.starts_with(";")
+        /// Splits `[base]`, `[base+disp]`, `[base-disp]` or `[disp]` into
+        /// its base register and displacement expression.
+        fn memory(token: &str) -> Option<(Register, String)> {
+            let inner = token.strip_prefix('[')?.strip_suffix(']')?;
+            let split = inner.find(['+', '-']).unwrap_or(inner.len());
+            let (base, disp) = inner.split_at(split);
+
+            match Register::from_string(base) {
+                Some(reg) => Some((reg, String::from(disp))),
+                None if !inner.is_empty() => Some((Register::NIL, String::from(inner))),
+                None => None,
+            }
         }
 
-        fn isgoto(&self, tok: &String) -> bool {
-            let cp_name: String = format!(":{}", tok);
-            let jop = vec![OpCode::JMP, OpCode::JNE, OpCode::JE];
+        fn iscomment(token: &str) -> bool {
+            token.starts_with(";")
+        }
 
-            match self.lop {
-                Some(op) => self.cp.contains(&cp_name) && jop.contains(&op),
-                None => false,
-            }
+        fn isgoto(&self, tok: &str) -> bool {
+            self.cp.contains(tok)
         }
 
         fn next(&mut self) -> Tokens {
             let tok: &String = &self.parser.tokens[self.pos];
+            let raw: &String = &self.parser.raw_tokens[self.pos];
             self.pos += 1;
 
-            if Register::is_reg(tok) {
+            if raw.starts_with('"') {
+                Tokens::DATA(GeneralData::from(tok.as_str()))
+            } else if raw.starts_with('\'') {
+                Tokens::DATA(GeneralData::Char(tok.chars().next().unwrap_or('\0')))
+            } else if raw.starts_with('[') {
+                match Tokenizer::memory(tok) {
+                    Some((base, disp)) => Tokens::MEMORY(base, disp),
+                    None => {
+                        // An unterminated operand was reported by the parser.
+                        if tok.ends_with(']') {
+                            self.error(format!("invalid memory operand '{}'", tok));
+                        }
+                        Tokens::MEMORY(Register::NIL, String::new())
+                    }
+                }
+            } else if Register::is_reg(tok) {
                 Tokens::REGISTER(Register::from_string(tok).unwrap())
             } else if OpCode::isop(tok) {
-                self.lop = OpCode::from_string(tok);
                 Tokens::INSTRUCTION(OpCode::from_string(tok).unwrap())
-            } else if Tokenizer::iscp(tok) {
-                Tokens::CHECKPOINT(String::from(tok))
-            } else if Tokenizer::iscomment(tok) {
+            } else if let Some(directive) = Directive::from_string(tok) {
+                Tokens::DIRECTIVE(directive)
+            } else if Tokenizer::iscomment(raw) {
                 Tokens::COMMENT(String::from(tok))
+            } else if let Some(name) = Tokenizer::label_name(raw) {
+                Tokens::CHECKPOINT(format!(":{}", name))
             } else if self.isgoto(tok) {
                 Tokens::GOTO(String::from(tok))
-            } else {
-                match Stoi::to_int(tok) {
-                    Some(value) => return Tokens::DATA(DataType::Int64, AnyData::from(value)),
-                    None => {}
+            } else if Stoi::is_numeric(tok) {
+                match Stoi::to_number(tok) {
+                    Ok(d) => Tokens::DATA(d),
+                    Err(_) if Expr::is_expression(tok) => Tokens::EXPR(String::from(tok)),
+                    Err(message) => {
+                        self.error(message);
+                        Tokens::DATA(GeneralData::Int64(0))
+                    }
                 }
-
-                Tokens::DATA(DataType::String, AnyData::from(tok))
+            } else if Expr::is_expression(tok) {
+                Tokens::EXPR(String::from(tok))
+            } else {
+                Tokens::DATA(GeneralData::from(tok.as_str()))
             }
         }
 
+        /// Records an error against the token just read.
+        fn error(&mut self, message: String) {
+            self.error_at(self.pos - 1, message);
+        }
+
+        fn error_at(&mut self, i: usize, message: String) {
+            self.errors.push(LexError {
+                line: self.parser.lines[i],
+                column: self.parser.columns[i],
+                message,
+            });
+        }
+
         fn getcp(&mut self) {
-            for t in &self.parser.tokens {
-                if Tokenizer::iscp(t) {
-                    self.cp.push(String::from(t));
+            for t in &self.parser.raw_tokens {
+                if let Some(name) = Tokenizer::label_name(t) {
+                    self.cp.insert(String::from(name));
+                }
+            }
+        }
+
+        /// A word that is no mnemonic, register, label or literal, which
+        /// the tokenizer keeps as a string for the error messages.
+        fn bare_word(&self, i: usize) -> bool {
+            matches!(self.tokens[i], Tokens::DATA(GeneralData::String(_)))
+                && !self.parser.raw_tokens[i].starts_with('"')
+        }
+
+        /// Checks that `op` can take the token at `i` as an operand of the
+        /// `expected` kind.
+        fn check_operand(&mut self, op: OpCode, expected: Operand, i: usize) {
+            let raw = &self.parser.raw_tokens[i];
+            let message = match (&self.tokens[i], expected) {
+                (Tokens::REGISTER(_), Operand::Address) => {
+                    format!("{:?} expects a label or memory operand", op)
+                }
+                (Tokens::REGISTER(_), _) => return,
+                _ if self.bare_word(i) => format!("undefined symbol '{}'", raw),
+                (_, Operand::Register) => format!("{:?} expects a register here", op),
+                (Tokens::MEMORY(..), Operand::Value) => format!("{:?} cannot read memory", op),
+                (Tokens::MEMORY(..), _) => return,
+                (_, Operand::Place) => format!("{:?} expects a register or memory operand", op),
+                (Tokens::GOTO(_), _) => return,
+                (
+                    Tokens::DATA(GeneralData::Int64(_)) | Tokens::EXPR(_),
+                    Operand::Target | Operand::Base | Operand::Status,
+                ) => return,
+                (_, Operand::Base) => format!("{:?} expects a number base", op),
+                (_, Operand::Status) => format!("{:?} expects an exit status", op),
+                (_, Operand::Target | Operand::Address) => format!("{:?} expects a label", op),
+                (Tokens::DATA(_) | Tokens::EXPR(_), Operand::Value | Operand::Source) => return,
+                _ => format!("bad operand '{}'", raw),
+            };
+            self.error_at(i, message);
+        }
+
+        /// Checks that every statement is `label: mnemonic operands`, with
+        /// as many operands as the mnemonic takes and of the kinds it
+        /// takes, and that no label is defined twice.
+        fn check_statements(&mut self) {
+            let mut defined: HashSet<String> = HashSet::new();
+            let mut i = 0;
+            while i < self.tokens.len() {
+                let statement = self.parser.statements[i];
+                let mut body: Vec<usize> = Vec::new();
+                let mut labelled = false;
+                while i < self.tokens.len() && self.parser.statements[i] == statement {
+                    match &self.tokens[i] {
+                        Tokens::CHECKPOINT(name) => {
+                            labelled = true;
+                            if !defined.insert(String::from(&name[1..])) {
+                                self.error_at(i, format!("label '{}' defined twice", &name[1..]));
+                            }
+                        }
+                        Tokens::COMMENT(_) => {}
+                        _ => body.push(i),
+                    }
+                    i += 1;
+                }
+
+                let (head, operands) = match body.split_first() {
+                    Some(split) => split,
+                    None => continue,
+                };
+                let raw = self.parser.raw_tokens[*head].clone();
+                let (name, min, max) = match self.tokens[*head] {
+                    Tokens::INSTRUCTION(op) => {
+                        (format!("{:?}", op), op.required(), op.operands().len())
+                    }
+                    Tokens::DIRECTIVE(Directive::EQU) if !labelled => {
+                        self.error_at(*head, String::from("EQU needs a label to name"));
+                        continue;
+                    }
+                    Tokens::DIRECTIVE(Directive::EQU) => (String::from("EQU"), 1, 1),
+                    Tokens::DIRECTIVE(directive) => (format!("{:?}", directive), 1, usize::MAX),
+                    _ if self.bare_word(*head) => {
+                        self.error_at(*head, format!("unknown mnemonic '{}'", raw));
+                        continue;
+                    }
+                    _ => {
+                        self.error_at(*head, format!("expected a mnemonic, found '{}'", raw));
+                        continue;
+                    }
+                };
+                if let Some(extra) = operands
+                    .iter()
+                    .find(|id| matches!(self.tokens[**id], Tokens::INSTRUCTION(_)))
+                {
+                    let message = format!(
+                        "unexpected mnemonic '{}', one instruction per statement",
+                        self.parser.raw_tokens[*extra]
+                    );
+                    self.error_at(*extra, message);
+                } else if !(min..=max).contains(&operands.len()) {
+                    let message = if min == max {
+                        format!("{} takes {} operand(s)", name, min)
+                    } else if max == usize::MAX {
+                        format!("{} takes at least {} operand(s)", name, min)
+                    } else if min == 0 {
+                        format!("{} takes at most {} operand(s)", name, max)
+                    } else {
+                        format!("{} takes {} to {} operands", name, min, max)
+                    };
+                    self.error_at(*head, message);
+                }
+                if let Tokens::DIRECTIVE(_) = self.tokens[*head] {
+                    for id in operands {
+                        if self.bare_word(*id) {
+                            let message =
+                                format!("undefined symbol '{}'", self.parser.raw_tokens[*id]);
+                            self.error_at(*id, message);
+                        }
+                    }
+                }
+                if let Tokens::INSTRUCTION(op) = self.tokens[*head] {
+                    if op.unimplemented() {
+                        self.error_at(*head, format!("{:?} is not implemented yet", op));
+                    }
+                    for (id, kind) in operands.iter().zip(op.operands()) {
+                        self.check_operand(op, *kind, *id);
+                    }
                 }
             }
         }
 
+        pub fn tokens(&self) -> &Vec<Tokens> {
+            &self.tokens
+        }
+
+        pub fn parser(&self) -> &Parser {
+            &self.parser
+        }
+
         pub fn tokenize(&mut self) -> &Vec<Tokens> {
             self.getcp();
 
@@ -979,170 +3095,1078 @@ pub mod tokenizer {
                 let tok = self.next();
                 self.tokens.push(tok);
             }
+            self.check_statements();
             &self.tokens
         }
     }
 }
 
 pub mod interpreter {
-    use std::collections::{HashMap, VecDeque};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
     //use std::intrinsics::pref_align_of;
-    use crate::structures::data_types::{AnyData, DataType, GeneralData};
-    use crate::structures::flow_structure::{FlowStructure, OpCode};
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::env_vars::{DATA_BASE, MEMORY_SIZE};
+    use crate::structures::expr::Expr;
+    use crate::structures::flow_structure::{Directive, FlowStructure};
+    use crate::structures::parser::{LexError, Parser};
+    use crate::structures::tokenizer::Tokenizer;
     use crate::structures::tokens::Tokens;
 
     pub struct Interpreter {}
 
     type InterpretedCode = Vec<FlowStructure>;
 
-    impl Interpreter {
-        fn cp_pos(tokens: &Vec<Tokens>) -> HashMap<String, usize> {
-            let mut i: usize = 0;
-            let mut cp: HashMap<String, usize> = HashMap::new();
-
-            for t in tokens {
-                match t {
-                    Tokens::CHECKPOINT(cpo) => {
-                        let mut chars = cpo.chars();
-                        chars.next();
-                        cp.insert(String::from(chars.as_str()), i);
+    /// An assembled program.
+    pub struct Program {
+        pub code: InterpretedCode,
+        /// Initial memory contents, loaded at `DATA_BASE`.
+        pub data: Vec<u8>,
+        /// Instruction index of each code label.
+        pub labels: HashMap<String, usize>,
+        /// Every value computed from a code label, so that addresses can
+        /// be told from numbers that happen to equal one.
+        pub references: Vec<Reference>,
+    }
+
+    /// Where a value computed from a code label ended up.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Site {
+        /// Argument `.1` of instruction `.0`.
+        Operand(usize, usize),
+        /// The DB or DQ item at this offset into the data.
+        Data(usize),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Reference {
+        pub label: String,
+        pub site: Site,
+    }
+
+    /// One statement as token indices.
+    #[derive(Default)]
+    struct Group {
+        labels: Vec<usize>,
+        head: Option<usize>,
+        operands: Vec<usize>,
+    }
+
+    /// Resolves labels, constants and expressions to numbers.
+    struct Symbols<'a> {
+        tokens: &'a [Tokens],
+        parser: &'a Parser,
+        values: HashMap<String, i64>,
+        /// The code labels each EQU constant was computed from.
+        derived: HashMap<String, Vec<String>>,
+    }
+
+    impl Symbols<'_> {
+        /// Whether the token at `i` is a string literal rather than a bare
+        /// word.
+        fn quoted(&self, i: usize) -> bool {
+            self.parser.raw_tokens[i].starts_with('"')
+        }
+
+        fn value(&self, i: usize) -> Result<i64, String> {
+            let lookup = |name: &str| self.values.get(name).copied();
+            match &self.tokens[i] {
+                Tokens::GOTO(name) => lookup(name).ok_or(format!("undefined symbol '{}'", name)),
+                Tokens::EXPR(text) => Expr::eval(text, &lookup),
+                Tokens::DATA(d) => match d {
+                    GeneralData::Uint64(v) => i64::try_from(*v).map_err(|e| e.to_string()),
+                    GeneralData::String(name) if !self.quoted(i) => {
+                        Err(format!("undefined symbol '{}'", name))
                     }
-                    Tokens::INSTRUCTION(_) => {
-                        i += 1;
-                        continue;
+                    d => d
+                        .as_i64()
+                        .ok_or(format!("expected an integer, found {:?}", d.data_type())),
+                },
+                _ => Err(String::from("expected an integer")),
+            }
+        }
+
+        /// The code labels, among `labels`, that the value of the token at
+        /// `i` is computed from, through constants too.
+        fn references(&self, i: usize, labels: &HashMap<String, usize>) -> Vec<String> {
+            let used: RefCell<Vec<String>> = RefCell::new(Vec::new());
+            let lookup = |name: &str| {
+                used.borrow_mut().push(String::from(name));
+                self.values.get(name).copied()
+            };
+            let _ = match &self.tokens[i] {
+                Tokens::GOTO(name) => lookup(name).ok_or_else(String::new),
+                Tokens::EXPR(text) | Tokens::MEMORY(_, text) => Expr::eval(text, &lookup),
+                _ => Ok(0),
+            };
+
+            let mut found: Vec<String> = Vec::new();
+            for name in used.into_inner() {
+                let names = match self.derived.get(&name) {
+                    Some(names) => names.clone(),
+                    None if labels.contains_key(&name) => vec![name],
+                    None => Vec::new(),
+                };
+                for name in names {
+                    if !found.contains(&name) {
+                        found.push(name);
                     }
-                    _ => continue,
                 }
             }
-            cp
+            found
+        }
+
+        fn operand(&self, i: usize) -> Result<GeneralData, String> {
+            Ok(match &self.tokens[i] {
+                Tokens::GOTO(_) | Tokens::EXPR(_) => GeneralData::Int64(self.value(i)?),
+                Tokens::MEMORY(base, disp) => {
+                    let lookup = |name: &str| self.values.get(name).copied();
+                    let disp = match disp.as_str() {
+                        "" => 0,
+                        disp => Expr::eval(disp, &lookup)
+                            .map_err(|e| format!("invalid memory operand: {}", e))?,
+                    };
+                    GeneralData::Memory(*base, disp)
+                }
+                Tokens::REGISTER(reg) => GeneralData::Register(*reg),
+                Tokens::DATA(GeneralData::String(name)) if !self.quoted(i) => {
+                    return Err(format!("undefined symbol '{}'", name))
+                }
+                Tokens::DATA(d) => d.clone(),
+                _ => return Err(String::from("expected an operand")),
+            })
+        }
+
+        /// Appends one DB or DQ item to `data`.
+        fn encode(&self, directive: Directive, i: usize, data: &mut Vec<u8>) -> Result<(), String> {
+            match (directive, &self.tokens[i]) {
+                (Directive::DB, Tokens::DATA(GeneralData::String(text))) if self.quoted(i) => {
+                    data.extend_from_slice(text.as_bytes())
+                }
+                (Directive::DB, Tokens::DATA(GeneralData::Char(c))) => {
+                    data.extend_from_slice(c.to_string().as_bytes())
+                }
+                (Directive::DB, _) => {
+                    let value = self.value(i)?;
+                    if !(-128..=255).contains(&value) {
+                        return Err(format!("{} does not fit in a byte", value));
+                    }
+                    data.push(value as u8);
+                }
+                (_, Tokens::DATA(d @ (GeneralData::Float(_) | GeneralData::Double(_)))) => {
+                    data.extend_from_slice(&d.to_bits().to_le_bytes())
+                }
+                (_, _) => data.extend_from_slice(&self.value(i)?.to_le_bytes()),
+            }
+            Ok(())
         }
+    }
+
+    impl Interpreter {
+        /// Parses, tokenizes and assembles `source`.
+        pub fn from_source(source: String) -> Result<Program, Vec<LexError>> {
+            let mut parse: Parser = Parser::init(source);
+            parse.parse();
+            let mut tokens = Tokenizer::init(parse);
+            tokens.tokenize();
 
-        pub fn interpret(tokens: &Vec<Tokens>) -> InterpretedCode {
-            let cp = Interpreter::cp_pos(tokens);
-            let mut code: InterpretedCode = InterpretedCode::new();
-            let mut args: VecDeque<GeneralData> = VecDeque::new();
-            let mut queued_istr: OpCode = OpCode::COUNT;
+            let mut errors = if tokens.errors.is_empty() {
+                match Interpreter::interpret(tokens.tokens(), tokens.parser()) {
+                    Ok(program) => return Ok(program),
+                    Err(errors) => errors,
+                }
+            } else {
+                tokens.errors
+            };
+            errors.sort_by_key(|e| (e.line, e.column));
+            Err(errors)
+        }
 
-            for token in tokens {
+        fn groups(tokens: &[Tokens], parser: &Parser) -> Vec<Group> {
+            let mut groups: Vec<Group> = Vec::new();
+            for (i, token) in tokens.iter().enumerate() {
+                if i == 0 || parser.statements[i] != parser.statements[i - 1] {
+                    groups.push(Group::default());
+                }
+                let group = groups.last_mut().unwrap();
                 match token {
-                    Tokens::CHECKPOINT(_) => continue,
-                    Tokens::COMMENT(_) => continue,
-                    Tokens::GOTO(c_pos) => match cp.get(c_pos) {
-                        Some(i) => {
-                            args.push_back(GeneralData {
-                                t: DataType::Int64,
-                                d: AnyData::from(*i as i64),
-                            });
+                    Tokens::CHECKPOINT(_) => group.labels.push(i),
+                    Tokens::COMMENT(_) => {}
+                    _ if group.head.is_none() => group.head = Some(i),
+                    _ => group.operands.push(i),
+                }
+            }
+            groups
+        }
+
+        /// Assembles tokenized statements in two passes: the first gives
+        /// every label its instruction index or data address, the second
+        /// evaluates operands and lays out data. A label names the next
+        /// instruction or directive after it.
+        pub fn interpret(tokens: &[Tokens], parser: &Parser) -> Result<Program, Vec<LexError>> {
+            let groups = Interpreter::groups(tokens, parser);
+            let mut symbols = Symbols {
+                tokens,
+                parser,
+                values: HashMap::new(),
+                derived: HashMap::new(),
+            };
+            let mut labels: HashMap<String, usize> = HashMap::new();
+            let mut constants: Vec<(Vec<String>, usize)> = Vec::new();
+            let mut errors: Vec<LexError> = Vec::new();
+            let error = |errors: &mut Vec<LexError>, i: usize, message: String| {
+                errors.push(LexError {
+                    line: parser.lines[i],
+                    column: parser.columns[i],
+                    message,
+                })
+            };
+
+            let mut pending: Vec<String> = Vec::new();
+            let (mut code_len, mut data_len) = (0usize, 0usize);
+            for group in &groups {
+                for i in &group.labels {
+                    if let Tokens::CHECKPOINT(name) = &tokens[*i] {
+                        pending.push(String::from(&name[1..]));
+                    }
+                }
+                let value = match group.head.map(|head| &tokens[head]) {
+                    Some(Tokens::INSTRUCTION(_)) => {
+                        for name in &pending {
+                            labels.insert(name.clone(), code_len);
                         }
-                        None => {}
-                    },
-                    Tokens::DATA(t, d) => match t {
-                        DataType::Uint32 => args.push_back(GeneralData {
-                            t: DataType::Uint32,
-                            d: AnyData::from(d.uint32),
-                        }),
-                        DataType::Uint64 => args.push_back(GeneralData {
-                            t: DataType::Uint64,
-                            d: AnyData::from(d.uint64),
-                        }),
-                        DataType::Int32 => args.push_back(GeneralData {
-                            t: DataType::Int32,
-                            d: AnyData::from(d.int32),
-                        }),
-                        DataType::Int64 => args.push_back(GeneralData {
-                            t: DataType::Int64,
-                            d: AnyData::from(d.int64),
-                        }),
-                        DataType::Float => args.push_back(GeneralData {
-                            t: DataType::Float,
-                            d: AnyData::from(d.float),
-                        }),
-                        DataType::Double => args.push_back(GeneralData {
-                            t: DataType::Double,
-                            d: AnyData::from(d.double),
-                        }),
-                        DataType::String => args.push_back(GeneralData {
-                            t: DataType::String,
-                            d: AnyData::from(&d.string.to_string()),
-                        }),
-                        DataType::Char => args.push_back(GeneralData {
-                            t: DataType::Char,
-                            d: AnyData::from(d.char),
-                        }),
-                        DataType::Register => args.push_back(GeneralData {
-                            t: DataType::Register,
-                            d: AnyData::from(d.register),
-                        }),
-                    },
-                    Tokens::REGISTER(reg) => args.push_back(GeneralData {
-                        t: DataType::Register,
-                        d: AnyData::from(*reg),
-                    }),
-                    Tokens::INSTRUCTION(istr) => {
-                        if queued_istr != OpCode::COUNT {
-                            let mut vec: Vec<GeneralData> = Vec::with_capacity(args.len());
-                            while args.len() > 0 {
-                                vec.push(args.pop_front().unwrap());
-                            }
-                            code.push(FlowStructure {
-                                op_code: queued_istr,
-                                arguments: vec,
-                            });
+                        code_len += 1;
+                        code_len - 1
+                    }
+                    Some(Tokens::DIRECTIVE(Directive::EQU)) => {
+                        constants.push((std::mem::take(&mut pending), group.operands[0]));
+                        continue;
+                    }
+                    Some(Tokens::DIRECTIVE(directive)) => {
+                        let size: usize = group
+                            .operands
+                            .iter()
+                            .map(|i| match (directive, &tokens[*i]) {
+                                (Directive::DB, Tokens::DATA(GeneralData::String(text))) => {
+                                    text.len()
+                                }
+                                (Directive::DB, Tokens::DATA(GeneralData::Char(c))) => c.len_utf8(),
+                                (Directive::DB, _) => 1,
+                                _ => 8,
+                            })
+                            .sum();
+                        data_len += size;
+                        DATA_BASE + data_len - size
+                    }
+                    _ => continue,
+                };
+                for name in pending.drain(..) {
+                    symbols.values.insert(name, value as i64);
+                }
+            }
+            for name in pending {
+                labels.insert(name.clone(), code_len);
+                symbols.values.insert(name, code_len as i64);
+            }
+
+            for (names, operand) in constants {
+                match symbols.value(operand) {
+                    Ok(value) => {
+                        let from = symbols.references(operand, &labels);
+                        for name in names {
+                            symbols.values.insert(name.clone(), value);
+                            symbols.derived.insert(name, from.clone());
                         }
-                        queued_istr = *istr
                     }
+                    Err(message) => error(&mut errors, operand, message),
                 }
             }
-            if args.len() > 0 {
-                let mut vec: Vec<GeneralData> = Vec::with_capacity(args.len());
-                while args.len() > 0 {
-                    vec.push(args.pop_front().unwrap());
+
+            let mut code: InterpretedCode = InterpretedCode::with_capacity(code_len);
+            let mut data: Vec<u8> = Vec::with_capacity(data_len);
+            let mut references: Vec<Reference> = Vec::new();
+            let mut refer = |i: usize, site: Site| {
+                for label in symbols.references(i, &labels) {
+                    references.push(Reference { label, site });
+                }
+            };
+            for group in &groups {
+                let head = match group.head {
+                    Some(head) => head,
+                    None => continue,
+                };
+                match tokens[head] {
+                    Tokens::INSTRUCTION(op_code) => {
+                        let mut arguments: Vec<GeneralData> = Vec::new();
+                        for i in &group.operands {
+                            refer(*i, Site::Operand(code.len(), arguments.len()));
+                            match symbols.operand(*i) {
+                                Ok(arg) => arguments.push(arg),
+                                Err(message) => error(&mut errors, *i, message),
+                            }
+                        }
+                        code.push(FlowStructure {
+                            op_code,
+                            arguments,
+                            line: parser.lines[head],
+                        });
+                    }
+                    Tokens::DIRECTIVE(Directive::EQU) => {}
+                    Tokens::DIRECTIVE(directive) => {
+                        for i in &group.operands {
+                            refer(*i, Site::Data(data.len()));
+                            if let Err(message) = symbols.encode(directive, *i, &mut data) {
+                                error(&mut errors, *i, message);
+                            }
+                        }
+                        if DATA_BASE + data.len() > MEMORY_SIZE {
+                            error(
+                                &mut errors,
+                                head,
+                                String::from("data does not fit in memory"),
+                            );
+                        }
+                    }
+                    _ => {}
                 }
-                code.push(FlowStructure {
-                    op_code: queued_istr,
-                    arguments: vec,
-                });
             }
 
-            code
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+            Ok(Program {
+                code,
+                data,
+                labels,
+                references,
+            })
         }
     }
 }
 
+#[allow(clippy::module_inception)]
 pub mod structures {
-    // use crate::structures::data_types::GeneralData;
-    use crate::structures::env_vars::EnvVars;
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::env_vars::{EnvVars, VmError, DATA_BASE};
     use crate::structures::flow_structure::FlowStructure;
+    use crate::structures::lowering;
+    use crate::structures::lowering::Op;
 
-    type Flow = Vec<FlowStructure>;
+    pub type Flow = Vec<FlowStructure>;
 
     /* General Vars */
     pub struct GeneralStructure {
         env: EnvVars,
         flow: Flow,
+        /// `flow` lowered for `run`.
+        code: Vec<Op>,
     }
 
     impl GeneralStructure {
-        pub fn init(flow: Flow) -> Self {
-            GeneralStructure {
-                env: EnvVars::init(),
-                flow: flow,
+        /// `data` is loaded into memory at `DATA_BASE`.
+        pub fn init(flow: Flow, data: &[u8]) -> Self {
+            let mut env = EnvVars::init();
+            env.set_memory(DATA_BASE, data);
+            env.set_break((DATA_BASE + data.len()).next_multiple_of(8));
+            let code = lowering::lower(&flow);
+            GeneralStructure { env, flow, code }
+        }
+
+        /// Replaces the code and data, keeping registers, the stack and the
+        /// rest of memory, and starts over at the first instruction.
+        pub fn load(&mut self, flow: Flow, data: &[u8]) {
+            self.env.set_memory(DATA_BASE, data);
+            self.env.pc = 0;
+            self.code = lowering::lower(&flow);
+            self.flow = flow;
+        }
+
+        pub fn run(&mut self) -> Result<(), VmError> {
+            self.run_for(u64::MAX).map(|_| ())
+        }
+
+        /// Executes up to `budget` instructions, returns how many ran. On an
+        /// error `pc` is left at the failing instruction.
+        pub fn run_for(&mut self, budget: u64) -> Result<u64, VmError> {
+            let result = self.env.run_decoded(&self.code, &self.flow, budget);
+            if result.is_err() || self.finished() {
+                self.env.flush();
             }
+            result
         }
 
-        pub fn run(&mut self) {
-            while (self.env.pc as usize) < self.flow.len() {
-                let istr = &self.flow[self.env.pc as usize];
-                self.env.execute_istr(istr);
-                self.env.pc += 1;
+        /// Executes the instruction at `pc`, returns false once the program has ended.
+        pub fn step(&mut self) -> Result<bool, VmError> {
+            if self.finished() {
+                return Ok(false);
+            }
+            let result = self.env.execute_istr(&self.flow);
+            if result.is_err() || self.finished() {
+                self.env.flush();
             }
+            result.map(|_| true)
+        }
+
+        /// Undoes the last executed instruction. Needs `EnvVars::set_recording`.
+        pub fn reverse_step(&mut self) -> bool {
+            self.env.undo()
         }
 
-        pub fn next(&mut self, flow: Flow) {
+        /// Starts over at `pc` as though CALLed from past the last
+        /// instruction, so that returning ends the program.
+        pub fn call(&mut self, pc: usize) {
+            self.env
+                .push_value(GeneralData::Int64(self.flow.len() as i64));
+            self.env.pc = pc as i64;
+        }
+
+        pub fn finished(&self) -> bool {
+            self.env.halted() || self.env.pc < 0 || (self.env.pc as usize) >= self.flow.len()
+        }
+
+        pub fn pc(&self) -> usize {
+            self.env.pc as usize
+        }
+
+        pub fn env(&self) -> &EnvVars {
+            &self.env
+        }
+
+        pub fn env_mut(&mut self) -> &mut EnvVars {
+            &mut self.env
+        }
+
+        pub fn flow(&self) -> &Flow {
+            &self.flow
+        }
+
+        pub fn next(&mut self, flow: Flow) -> Result<(), VmError> {
             self.env.pc = 0;
+            self.code = lowering::lower(&flow);
             self.flow = flow;
 
-            self.run();
+            self.run()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    use crate::structures::data_types::GeneralData;
+    use crate::structures::env_vars::DATA_BASE;
+    use crate::structures::expr::Expr;
+    use crate::structures::flow_structure::{Directive, OpCode};
+    use crate::structures::interpreter::{Interpreter, Site};
+    use crate::structures::lowering::{lower, Op};
+    use crate::structures::parser::Parser;
+    use crate::structures::registers::{Register, KEYWORD_LEN};
+    use crate::structures::stoi::Stoi;
+    use crate::structures::structures::GeneralStructure;
+    use crate::test_support::{assemble, SharedBuffer};
+
+    fn vm(source: &str) -> GeneralStructure {
+        let program = assemble(source);
+        let mut vm = GeneralStructure::init(program.code, &program.data);
+        vm.env_mut().set_output(Box::new(std::io::sink()));
+        vm
+    }
+
+    /// Where running `source` fails and why.
+    fn fault(source: &str) -> (usize, String) {
+        let error = vm(source).run().unwrap_err();
+        (error.pc, error.message)
+    }
+
+    /// Runs `source` on `input` and returns what it printed.
+    fn output(source: &str, input: &str) -> String {
+        let out = SharedBuffer::default();
+        let mut vm = vm(source);
+        vm.env_mut().set_output(Box::new(out.clone()));
+        vm.env_mut()
+            .set_input(Box::new(Cursor::new(input.as_bytes().to_vec())));
+        vm.run().unwrap();
+        vm.env_mut().flush();
+        out.text()
+    }
+
+    #[test]
+    fn undo_restores_registers_flags_and_pc() {
+        let mut vm = vm("mov rax, 1\nadd rax, 2\ncmp rax, 3\n");
+        vm.env_mut().set_recording(true);
+        while vm.step().unwrap() {}
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(3));
+        assert!(vm.env().flags().zf);
+
+        assert!(vm.reverse_step());
+        assert!(!vm.env().flags().zf);
+        assert!(vm.reverse_step());
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(1));
+        assert_eq!(vm.pc(), 1);
+        assert!(vm.reverse_step());
+        assert!(!vm.reverse_step());
+        assert_eq!(vm.pc(), 0);
+    }
+
+    #[test]
+    fn journal_keeps_the_newest_instructions() {
+        let mut vm = vm("mov rax, 1\nmov rax, 2\nmov rax, 3\nmov rax, 4\n");
+        vm.env_mut().set_recording(true);
+        vm.env_mut().set_history_limit(2);
+        while vm.step().unwrap() {}
+        assert_eq!(vm.env().history().len(), 2);
+
+        assert!(vm.reverse_step());
+        assert!(vm.reverse_step());
+        assert!(!vm.reverse_step());
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(2));
+        assert_eq!(vm.pc(), 2);
+    }
+
+    #[test]
+    fn last_write_finds_the_writing_instruction() {
+        let mut vm = vm("mov rax, 1\nmov rbx, 2\ncmp rbx, 2\n");
+        vm.env_mut().set_recording(true);
+        while vm.step().unwrap() {}
+        assert_eq!(vm.env().last_write(Register::RAX), Some((0, 0)));
+        assert_eq!(vm.env().last_write(Register::RBX), Some((1, 1)));
+        assert_eq!(vm.env().last_write(Register::RCX), None);
+    }
+
+    #[test]
+    fn errors_stop_at_the_failing_instruction() {
+        let mut vm = vm("mov rax, 1\nmov rbx, \"x\"\ncmp rax, rbx\n");
+        vm.env_mut().set_recording(true);
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.pc, 2);
+        assert_eq!(error.message, "CMP mixes Int64 and String");
+        assert_eq!(vm.pc(), 2);
+        // The failed instruction is journaled too, undoing it stays put.
+        assert!(vm.reverse_step());
+        assert_eq!(vm.pc(), 2);
+        assert!(vm.reverse_step());
+        assert_eq!(vm.pc(), 1);
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        match Interpreter::from_source(source.to_string()) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn operands_are_checked_by_kind() {
+        assert_eq!(
+            errors("mov 5, rax\njmp nowhere\nlea rax, rbx\npop 1\nhlt 1.5\n"),
+            [
+                "1:5: MOV expects a register or memory operand",
+                "2:5: undefined symbol 'nowhere'",
+                "3:10: LEA expects a label or memory operand",
+                "4:5: POP expects a register here",
+                "5:5: HLT expects an exit status",
+            ]
+        );
+        assert_eq!(errors("a: a: nop\n"), ["1:4: label 'a' defined twice"]);
+        assert_eq!(
+            errors("malloc rax, 8\n"),
+            ["1:1: MALLOC is not implemented yet"]
+        );
+        assert!(errors("x: add rax, [x]\nexit rax\njmp x\nstdout rax, 16\n").is_empty());
+    }
+
+    #[test]
+    fn files_stay_in_memory_by_default() {
+        let path = "vcpu-default-host-test.txt";
+        let mut vm = vm(&format!(
+            "mov rax, 2\nlea rdi, path\nmov rsi, 65\nsyscall\nmov rbx, rax\n\
+             mov rdi, rax\nmov rax, 1\nlea rsi, path\nmov rdx, 4\nsyscall\n\
+             path: db \"{}\", 0\n",
+            path
+        ));
+        vm.run().unwrap();
+        assert_eq!(*vm.env().register(Register::RBX), GeneralData::Int64(3));
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(4));
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn console_io() {
+        assert_eq!(
+            output(
+                "stdout 255, 16\nputc 'x'\nputc 10\nstdout -5, 2\nputc ' '\nstdout 1.5\n",
+                ""
+            ),
+            "ffx\n-101 1.5"
+        );
+        assert_eq!(
+            output(
+                "stdin rax\nstdin rbx, 16\ngetc rcx\ngetc rcx\nstdout rax\nputc ','\n\
+                 stdout rbx\nputc ','\nputc rcx\nstdin [buf]\nstdin [buf]\nje done\nstdout 1\n\
+                 done: hlt\nbuf: dq 0, 0\n",
+                "hello\n  ff x\n"
+            ),
+            "hello,255,x"
+        );
+    }
+
+    #[test]
+    fn exit_codes() {
+        let code = |source: &str| {
+            let mut vm = vm(source);
+            vm.run().unwrap();
+            vm.env().exit_code()
+        };
+        assert_eq!(code("nop\n"), 0);
+        assert_eq!(code("hlt\n"), 0);
+        assert_eq!(code("mov rax, 3u32\nhlt rax\nhlt 1\n"), 3);
+        assert_eq!(code("exit -1\nhlt 1\n"), -1);
+        assert_eq!(code("mov rax, 60\nmov rdi, 9\nsyscall\nhlt 1\n"), 9);
+    }
+
+    #[test]
+    fn arguments_follow_the_data() {
+        let mut vm = vm("mov rbx, [rsi+8]\nhlt\nbyte: db 1\n");
+        vm.env_mut()
+            .set_args(&[String::from("prog"), String::from("hi")])
+            .unwrap();
+        vm.run().unwrap();
+        let env = vm.env();
+        assert_eq!(*env.register(Register::RDI), GeneralData::Int64(2));
+        let GeneralData::Int64(table) = *env.register(Register::RSI) else {
+            panic!("RSI is not a pointer");
+        };
+        assert!(table as usize > DATA_BASE);
+        assert_eq!(table % 8, 0);
+        let GeneralData::Int64(arg) = *env.register(Register::RBX) else {
+            panic!("argv[1] is not a pointer");
+        };
+        let memory = env.memory();
+        assert_eq!(&memory[arg as usize..arg as usize + 3], b"hi\0");
+        let null = table as usize + 16;
+        assert_eq!(&memory[null..null + 8], &[0; 8]);
+    }
+
+    #[test]
+    fn arguments_must_fit_in_memory() {
+        let mut vm = vm("hlt\n");
+        let args = [String::from("prog"), "x".repeat(1 << 16)];
+        assert_eq!(
+            vm.env_mut().set_args(&args),
+            Err(String::from("Arguments do not fit in memory"))
+        );
+    }
+
+    #[test]
+    fn syscalls_answer_with_a_result_or_an_errno() {
+        let mut vm = vm(
+            "mov rax, 1\nmov rdi, 1\nlea rsi, msg\nmov rdx, 2\nsyscall\n\
+                         mov rbx, rax\nmov rax, 99\nsyscall\nmsg: db \"hi\"\n",
+        );
+        let out = SharedBuffer::default();
+        vm.env_mut().set_output(Box::new(out.clone()));
+        vm.run().unwrap();
+        vm.env_mut().flush();
+        assert_eq!(out.text(), "hi");
+        assert_eq!(*vm.env().register(Register::RBX), GeneralData::Int64(2));
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(-38));
+    }
+
+    #[test]
+    fn console_errors_are_not_the_end_of_input() {
+        assert_eq!(
+            output("putc 65u32\nputc 0x263Au64\nputc -1\n", ""),
+            "A\u{263A}\u{FFFD}"
+        );
+        let mut vm = vm("stdin rax, 10\nje done\nstdout rax\ndone: hlt\n");
+        vm.env_mut()
+            .set_input(Box::new(Cursor::new(b"12x\n".to_vec())));
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.pc, 0);
+        assert_eq!(error.message, "STDIN read \"12x\", not a base 10 integer");
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_register_type() {
+        let mut vm = vm(
+            "mov rax, 7u32\nsub rax, 9u32\nmov rbx, -7i32\ndiv rbx, 2i32\n\
+                         mov rcx, 6\nmul rcx, rcx\ninc rcx\nmov rdx, 12\nand rdx, 10\n\
+                         or rdx, 1\nxor rdx, 2\n",
+        );
+        vm.run().unwrap();
+        let env = vm.env();
+        assert_eq!(
+            *env.register(Register::RAX),
+            GeneralData::Uint32(u32::MAX - 1)
+        );
+        assert_eq!(*env.register(Register::RBX), GeneralData::Int32(-3));
+        assert_eq!(*env.register(Register::RCX), GeneralData::Int64(37));
+        assert_eq!(*env.register(Register::RDX), GeneralData::Int64(11));
+    }
+
+    #[test]
+    fn arithmetic_needs_one_type() {
+        assert_eq!(
+            fault("mov rax, 1\nsub rax, 1.0\n"),
+            (1, String::from("SUB mixes Int64 and Double"))
+        );
+    }
+
+    #[test]
+    fn integer_division_by_zero_fails() {
+        assert_eq!(
+            fault("mov rax, 1\ndiv rax, 0\n"),
+            (1, String::from("DIV by zero"))
+        );
+    }
+
+    #[test]
+    fn integer_remainder_by_zero_fails() {
+        assert_eq!(
+            fault("mov rax, 1u32\nmod rax, 0u32\n"),
+            (1, String::from("MOD by zero"))
+        );
+    }
+
+    #[test]
+    fn comparison_needs_one_type() {
+        assert_eq!(
+            fault("mov rax, 1\ncmp rax, 'a'\n"),
+            (1, String::from("CMP mixes Int64 and Char"))
+        );
+    }
+
+    #[test]
+    fn mod_sets_zf_on_a_zero_remainder() {
+        let mut vm = vm("mov rax, -9223372036854775807\nsub rax, 1\nmod rax, -1\n");
+        vm.run().unwrap();
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(0));
+        assert!(vm.env().flags().zf);
+    }
+
+    #[test]
+    fn values_are_compact() {
+        assert_eq!(std::mem::size_of::<GeneralData>(), 24);
+        let text = GeneralData::from("shared");
+        let copy = text.clone();
+        let (GeneralData::String(a), GeneralData::String(b)) = (&text, &copy) else {
+            unreachable!();
+        };
+        assert!(Rc::ptr_eq(a, b));
+    }
+
+    #[test]
+    fn values_show_and_convert() {
+        let shown: Vec<String> = [
+            GeneralData::from(-3i32),
+            GeneralData::from(1.5f32),
+            GeneralData::from('x'),
+            GeneralData::from(Register::RAX),
+            GeneralData::Memory(Register::NIL, 16),
+            GeneralData::Memory(Register::RBP, -8),
+            GeneralData::Memory(Register::RSP, 0),
+        ]
+        .iter()
+        .map(|data| data.to_string())
+        .collect();
+        assert_eq!(shown, ["-3", "1.5", "x", "RAX", "[16]", "[RBP-8]", "[RSP]"]);
+
+        assert_eq!(GeneralData::from(-1i32).to_bits(), u64::MAX);
+        assert_eq!(GeneralData::from(1.0f64).to_bits(), 1.0f64.to_bits());
+        assert_eq!(GeneralData::from(u64::MAX).as_i64(), Some(-1));
+        assert_eq!(GeneralData::from(1.0f64).as_i64(), None);
+    }
+
+    #[test]
+    fn lowering_keeps_one_op_per_instruction() {
+        let program = assemble(
+            "top: mov rax, rbx\nmov rcx, 2\nadd rcx, 1\nadd rcx, rax\ncmp rcx, 3\n\
+             jne top\ncall top\nmov rax, [rsp]\nadd rax, 1.5\nret\n",
+        );
+        let rax = Register::RAX as usize;
+        let rbx = Register::RBX as usize;
+        let rcx = Register::RCX as usize;
+        assert_eq!(
+            lower(&program.code),
+            [
+                Op::MovReg(rax, rbx),
+                Op::MovImm(rcx, GeneralData::Int64(2)),
+                Op::AddInt(rcx, 1),
+                Op::AddReg(rcx, rax),
+                Op::CmpInt(rcx, 3),
+                Op::Jne(0),
+                Op::Call(0),
+                Op::Generic,
+                Op::Generic,
+                Op::Ret,
+            ]
+        );
+    }
+
+    #[test]
+    fn fast_path_matches_stepping() {
+        let source = "mov rax, 0\nmov rbx, 0u32\nloop: add rax, 1\nadd rbx, 1u32\n\
+                      cmp rax, 1000\njne loop\nmov rcx, rax\nadd rcx, rcx\n";
+        let mut fast = vm(source);
+        assert_eq!(fast.run_for(u64::MAX), Ok(4004));
+        let mut stepped = vm(source);
+        while stepped.step().unwrap() {}
+        for reg in [Register::RAX, Register::RBX, Register::RCX] {
+            assert_eq!(fast.env().register(reg), stepped.env().register(reg));
+        }
+        assert_eq!(
+            *fast.env().register(Register::RCX),
+            GeneralData::Int64(2000)
+        );
+        assert_eq!(fast.pc(), stepped.pc());
+
+        let mut limited = vm(source);
+        assert_eq!(limited.run_for(10), Ok(10));
+        assert!(!limited.env().halted());
+    }
+
+    #[test]
+    fn keywords_are_looked_up_case_insensitively() {
+        for id in 0..OpCode::COUNT as u8 {
+            let op: OpCode = num_traits::FromPrimitive::from_u8(id).unwrap();
+            let name = format!("{:?}", op);
+            assert!(name.len() <= KEYWORD_LEN);
+            assert_eq!(OpCode::from_string(&name.to_lowercase()), Some(op));
+        }
+        assert_eq!(OpCode::from_string("COUNT"), None);
+        assert_eq!(OpCode::from_string("assert_eqs"), None);
+        assert_eq!(OpCode::from_string("mö"), None);
+        assert_eq!(Directive::from_string("equ"), Some(Directive::EQU));
+
+        for name in ["rax", "EAX", "Ax"] {
+            assert_eq!(Register::from_string(name), Some(Register::RAX));
         }
+        assert_eq!(Register::from_string("ebp"), Some(Register::RBP));
+        assert_eq!(Register::from_string("rxx"), None);
+        assert_eq!(Register::from_string("nil"), None);
+    }
+
+    #[test]
+    fn number_literals_take_their_suffix_type() {
+        let number = |text: &str| Stoi::to_number(text).unwrap();
+        assert_eq!(number("-42"), GeneralData::Int64(-42));
+        assert_eq!(number("0xFF_ff"), GeneralData::Int64(0xffff));
+        assert_eq!(number("0b1010u32"), GeneralData::Uint32(10));
+        assert_eq!(number("0o17i32"), GeneralData::Int32(15));
+        assert_eq!(number("1_000u64"), GeneralData::Uint64(1000));
+        assert_eq!(number("0xf32"), GeneralData::Int64(0xf32));
+        assert_eq!(number("1.5"), GeneralData::Double(1.5));
+        assert_eq!(number("1e-3"), GeneralData::Double(0.001));
+        assert_eq!(number("2f32"), GeneralData::Float(2.0));
+    }
+
+    #[test]
+    fn bad_number_literals_are_errors() {
+        let error = |text: &str| Stoi::to_number(text).unwrap_err();
+        assert_eq!(
+            error("9223372036854775808"),
+            "number literal '9223372036854775808' out of range for Int64"
+        );
+        assert_eq!(
+            error("-1u32"),
+            "number literal '-1u32' out of range for Uint32"
+        );
+        assert_eq!(
+            error("1e400"),
+            "number literal '1e400' out of range for Double"
+        );
+        assert_eq!(error("300u32x"), "invalid number literal '300u32x'");
+        assert_eq!(error("1.5i32"), "invalid number literal '1.5i32'");
+        assert_eq!(error("0x"), "invalid number literal '0x'");
+    }
+
+    fn lex(source: &str) -> Parser {
+        let mut parser = Parser::init(source.to_string());
+        parser.parse();
+        parser
+    }
+
+    #[test]
+    fn escapes_are_decoded_in_literals() {
+        let parser = lex("pnl \"a\\n\\t\\x41\\u{263A}\\\"\\\\\"\nputc '\\''\n");
+        assert_eq!(parser.tokens[1], "a\n\tA\u{263A}\"\\");
+        assert_eq!(parser.raw_tokens[1], "\"a\\n\\t\\x41\\u{263A}\\\"\\\\\"");
+        assert_eq!(parser.tokens[3], "'");
+        assert!(parser.errors.is_empty());
+    }
+
+    #[test]
+    fn bad_literals_are_reported_where_they_start() {
+        let parser = lex("pnl \"open\npnl 'ab'\npnl \"\\q\"\npnl \"\\x4\"\npnl \"\\u{110000}\"\n");
+        let errors: Vec<String> = parser.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "1:5: unterminated literal",
+                "2:5: char literal must hold exactly one character",
+                "3:6: unknown escape '\\q'",
+                "4:6: malformed escape '\\x'",
+                "5:6: malformed escape '\\u'",
+            ]
+        );
+    }
+
+    #[test]
+    fn statements_are_lines() {
+        let program = assemble("start: nop ; first\n:next ret\n\n  hlt\nmov rax, \\\n    1\n");
+        let ops: Vec<String> = program.code.iter().map(|istr| istr.to_string()).collect();
+        assert_eq!(ops, ["NOP", "RET", "HLT", "MOV RAX, 1"]);
+        let lines: Vec<usize> = program.code.iter().map(|istr| istr.line).collect();
+        assert_eq!(lines, [1, 2, 4, 5]);
+        assert_eq!(program.labels["start"], 0);
+        assert_eq!(program.labels["next"], 1);
+    }
+
+    #[test]
+    fn label_references_are_recorded() {
+        let program = assemble(
+            "start: mov rax, 12\nmov rbx, after+1\nskip: equ after\n\
+             jmp skip\nafter: hlt\nbuf: db 1\ntable: dq start, 12, buf\n",
+        );
+        assert_eq!(program.labels["after"], 3);
+        let references: Vec<(&str, Site)> = program
+            .references
+            .iter()
+            .map(|reference| (reference.label.as_str(), reference.site))
+            .collect();
+        assert_eq!(
+            references,
+            [
+                ("after", Site::Operand(1, 1)),
+                ("after", Site::Operand(2, 0)),
+                ("start", Site::Data(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let lookup = |name: &str| (name == "N").then_some(10);
+        let eval = |text: &str| Expr::eval(text, &lookup);
+        assert_eq!(eval("1+2*3"), Ok(7));
+        assert_eq!(eval("(1+2)*3"), Ok(9));
+        assert_eq!(eval("N-N/3%2-0x10"), Ok(-7));
+        assert_eq!(eval("-(N*4)+1_000"), Ok(960));
+        assert_eq!(
+            eval("N/0"),
+            Err(String::from("division by zero in expression"))
+        );
+        assert_eq!(
+            eval("9223372036854775807+1"),
+            Err(String::from("expression overflows"))
+        );
+        assert_eq!(eval("(1+2"), Err(String::from("missing ')' in expression")));
+        assert_eq!(
+            eval("1+"),
+            Err(String::from("unexpected end of expression"))
+        );
+        assert_eq!(eval("2*M"), Err(String::from("undefined symbol 'M'")));
+        assert_eq!(eval("1.5+1"), Err(String::from("'1.5' is not an integer")));
+    }
+
+    #[test]
+    fn labels_are_values() {
+        let mut vm = vm(
+            "N: equ 4\nSIZE: equ N*8\nmov rax, end-start\nmov rbx, SIZE+1\n\
+                         lea rcx, [table+8]\nmov rdx, [rcx]\nstart: nop\nend: hlt\n\
+                         table: dq 1, start, end\n",
+        );
+        vm.run().unwrap();
+        let env = vm.env();
+        assert_eq!(*env.register(Register::RAX), GeneralData::Int64(1));
+        assert_eq!(*env.register(Register::RBX), GeneralData::Int64(33));
+        assert_eq!(
+            *env.register(Register::RCX),
+            GeneralData::Int64(DATA_BASE as i64 + 8)
+        );
+        assert_eq!(*env.register(Register::RDX), GeneralData::Int64(4));
+    }
+
+    #[test]
+    fn one_instruction_per_statement() {
+        assert_eq!(
+            errors("nop pnl rax\nmov rax\nret rax\n"),
+            [
+                "1:5: unexpected mnemonic 'pnl', one instruction per statement",
+                "2:1: MOV takes 2 operand(s)",
+                "3:1: RET takes 0 operand(s)",
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_go_through_registers_and_memory() {
+        let mut vm = vm("lea rax, second\njmp rax\nhlt 1\nsecond: jmp [table]\n\
+                         hlt 2\nthird: mov rbx, 3\ntable: dq third\n");
+        vm.run().unwrap();
+        assert_eq!(vm.env().exit_code(), 0);
+        assert_eq!(*vm.env().register(Register::RBX), GeneralData::Int64(3));
+    }
+
+    #[test]
+    fn call_and_ret_use_the_stack() {
+        let mut vm = vm("push 7\ncall double\npop rbx\nhlt\n\
+                         double: mov rax, 21\nadd rax, rax\nret\n");
+        vm.run().unwrap();
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(42));
+        assert_eq!(*vm.env().register(Register::RBX), GeneralData::Int64(7));
+    }
+
+    #[test]
+    fn memory_operands_use_base_and_displacement() {
+        let mut vm = vm("lea rbx, cells\nmov [rbx+8], 5\nmov rax, [cells+8]\n\
+                         cells: dq 0, 0\n");
+        vm.run().unwrap();
+        assert_eq!(*vm.env().register(Register::RAX), GeneralData::Int64(5));
+        let at = DATA_BASE + 8;
+        assert_eq!(vm.env().memory()[at..at + 8], 5i64.to_le_bytes());
+    }
+
+    #[test]
+    fn ret_on_an_empty_stack_fails() {
+        assert_eq!(fault("ret\n"), (0, String::from("Pop from an empty stack")));
+    }
+
+    #[test]
+    fn jumps_and_memory_accesses_stay_in_bounds() {
+        assert_eq!(
+            fault("nop\njmp 3\n"),
+            (1, String::from("Jump out of program at #3"))
+        );
+        assert_eq!(
+            fault("mov rax, -1\njmp rax\n"),
+            (1, String::from("Jump out of program at #-1"))
+        );
+        assert!(vm("jmp 1\n").run().is_ok());
+        assert_eq!(
+            fault("mov rax, [65530]\n"),
+            (0, String::from("Memory access out of bounds at 65530"))
+        );
+        assert_eq!(
+            fault("mov rbx, 9223372036854775807\nmov rax, [rbx+1]\n"),
+            (
+                1,
+                String::from("Memory access out of bounds at 9223372036854775808")
+            )
+        );
+    }
+    #[test]
+    fn bare_words_are_undefined_symbols() {
+        assert_eq!(
+            errors("mov rax, undefined_thing\nmsg: db hello, \"hi\"\nN: equ missing\n"),
+            [
+                "1:10: undefined symbol 'undefined_thing'",
+                "2:9: undefined symbol 'hello'",
+                "3:8: undefined symbol 'missing'",
+            ]
+        );
+        assert!(errors("mov rax, \"text\"\nmsg: db \"hi\", 0\n").is_empty());
     }
 }
@@ -0,0 +1,10 @@
+//! Fixtures shared by the unit tests.
+
+use crate::structures::interpreter::{Interpreter, Program};
+
+pub use crate::protocol::SharedBuffer;
+
+/// Assembles `source`, failing the test on the first error.
+pub fn assemble(source: &str) -> Program {
+    Interpreter::from_source(source.to_string()).unwrap_or_else(|errors| panic!("{}", errors[0]))
+}
@@ -0,0 +1,148 @@
+use crate::structures::interpreter::Program;
+use std::time::Duration;
+
+/// Labels starting with this are tests.
+pub const TEST_PREFIX: &str = "test_";
+
+/// The outcome of one test.
+pub struct TestCase {
+    /// The source file holding the test.
+    pub file: String,
+    pub name: String,
+    /// `file:line: message` when it failed.
+    pub failure: Option<String>,
+    pub time: Duration,
+}
+
+/// The tests in `program` as label names and instruction indices, in
+/// source order.
+pub fn discover(program: &Program) -> Vec<(String, usize)> {
+    let mut tests: Vec<(String, usize)> = program
+        .labels
+        .iter()
+        .filter(|(name, pos)| name.starts_with(TEST_PREFIX) && **pos < program.code.len())
+        .map(|(name, pos)| (name.clone(), *pos))
+        .collect();
+    tests.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    tests
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c if c.is_control() && c != '\n' && c != '\t' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A JUnit XML report, one test suite per file, as CI servers read.
+pub fn junit(cases: &[TestCase]) -> String {
+    let failures = |cases: &[&TestCase]| cases.iter().filter(|c| c.failure.is_some()).count();
+    let seconds = |cases: &[&TestCase]| cases.iter().map(|c| c.time.as_secs_f64()).sum::<f64>();
+
+    let mut files: Vec<&str> = Vec::new();
+    for case in cases {
+        if !files.contains(&case.file.as_str()) {
+            files.push(&case.file);
+        }
+    }
+    let all: Vec<&TestCase> = cases.iter().collect();
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"vcpu\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        all.len(),
+        failures(&all),
+        seconds(&all)
+    );
+    for file in files {
+        let suite: Vec<&TestCase> = cases.iter().filter(|c| c.file == file).collect();
+        out.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" time=\"{:.3}\">\n",
+            escape(file),
+            suite.len(),
+            failures(&suite),
+            seconds(&suite)
+        ));
+        for case in suite {
+            let open = format!(
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape(&case.name),
+                escape(&case.file),
+                case.time.as_secs_f64()
+            );
+            match &case.failure {
+                None => out.push_str(&format!("{}/>\n", open)),
+                Some(failure) => out.push_str(&format!(
+                    "{}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    open,
+                    escape(failure),
+                    escape(failure)
+                )),
+            }
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discover, escape, junit, TestCase};
+    use crate::test_support::assemble;
+    use std::time::Duration;
+
+    #[test]
+    fn tests_are_found_in_source_order() {
+        let program = assemble("hlt\ntest_b: ret\nhelper: ret\ntest_a: ret\ntest_data: dq 1\n");
+        assert_eq!(
+            discover(&program),
+            [(String::from("test_b"), 1), (String::from("test_a"), 3)]
+        );
+    }
+
+    #[test]
+    fn xml_is_escaped() {
+        assert_eq!(
+            escape("a<b> & \"c\" 'd'\u{1}\n"),
+            "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;\n"
+        );
+    }
+
+    #[test]
+    fn junit_report() {
+        let case = |file: &str, name: &str, failure: Option<&str>| TestCase {
+            file: file.to_string(),
+            name: name.to_string(),
+            failure: failure.map(String::from),
+            time: Duration::from_millis(5),
+        };
+        let report = junit(&[
+            case("a.asm", "test_ok", None),
+            case("b.asm", "test_<", Some("b.asm:3: ASSERT_EQ failed: 1 & 2")),
+            case("a.asm", "test_too", None),
+        ]);
+        assert_eq!(
+            report,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuites name=\"vcpu\" tests=\"3\" failures=\"1\" time=\"0.015\">\n\
+             \x20 <testsuite name=\"a.asm\" tests=\"2\" failures=\"0\" errors=\"0\" time=\"0.010\">\n\
+             \x20   <testcase name=\"test_ok\" classname=\"a.asm\" time=\"0.005\"/>\n\
+             \x20   <testcase name=\"test_too\" classname=\"a.asm\" time=\"0.005\"/>\n\
+             \x20 </testsuite>\n\
+             \x20 <testsuite name=\"b.asm\" tests=\"1\" failures=\"1\" errors=\"0\" time=\"0.005\">\n\
+             \x20   <testcase name=\"test_&lt;\" classname=\"b.asm\" time=\"0.005\">\n\
+             \x20     <failure message=\"b.asm:3: ASSERT_EQ failed: 1 &amp; 2\">b.asm:3: ASSERT_EQ failed: 1 &amp; 2</failure>\n\
+             \x20   </testcase>\n\
+             \x20 </testsuite>\n\
+             </testsuites>\n"
+        );
+    }
+}
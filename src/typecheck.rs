@@ -0,0 +1,379 @@
+use crate::cfg;
+use crate::structures::data_types::{DataType, GeneralData};
+use crate::structures::flow_structure::{FlowStructure, OpCode};
+use crate::structures::interpreter::Program;
+use crate::structures::registers::Register;
+use crate::testing;
+use num_traits::FromPrimitive;
+use std::fmt::{Display, Formatter};
+
+/// A set of `DataType`s a value may have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Types(u16);
+
+impl Types {
+    pub const NONE: Types = Types(0);
+    pub const ANY: Types = Types((1 << (DataType::Memory as u16 + 1)) - 1);
+
+    pub fn of(t: DataType) -> Types {
+        Types(1 << t as u16)
+    }
+
+    fn all(types: &[DataType]) -> Types {
+        types
+            .iter()
+            .fold(Types::NONE, |set, t| set.union(Types::of(*t)))
+    }
+
+    pub fn contains(self, t: DataType) -> bool {
+        self.0 & Types::of(t).0 != 0
+    }
+
+    pub fn union(self, other: Types) -> Types {
+        Types(self.0 | other.0)
+    }
+
+    pub fn intersect(self, other: Types) -> Types {
+        Types(self.0 & other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = DataType> {
+        (0..16u8)
+            .filter_map(FromPrimitive::from_u8)
+            .filter(move |t| self.contains(*t))
+    }
+}
+
+impl Display for Types {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if *self == Types::ANY {
+            return write!(f, "anything");
+        }
+        let names: Vec<String> = self.iter().map(|t| format!("{:?}", t)).collect();
+        write!(f, "{}", names.join(" or "))
+    }
+}
+
+const INTEGERS: [DataType; 4] = [
+    DataType::Uint32,
+    DataType::Uint64,
+    DataType::Int32,
+    DataType::Int64,
+];
+
+const REGISTERS: usize = Register::NIL as usize;
+
+/// What each register may hold, indexed by `Register`.
+pub type RegisterTypes = [Types; REGISTERS];
+
+/// An operation that fails whatever the registers hold when it runs.
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub pc: usize,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+/// Registers as a fresh VM has them. RDI and RSI may hold argc and argv.
+fn entry() -> RegisterTypes {
+    let mut regs = [Types::of(DataType::Int32); REGISTERS];
+    for reg in [Register::RDI, Register::RSI] {
+        regs[reg as usize] = regs[reg as usize].union(Types::of(DataType::Int64));
+    }
+    regs
+}
+
+/// The types an operand has once loaded, memory always reads as Int64.
+fn operand(regs: &RegisterTypes, arg: &GeneralData) -> Types {
+    match arg {
+        GeneralData::Register(reg) => regs[*reg as usize],
+        GeneralData::Memory(..) => Types::of(DataType::Int64),
+        other => Types::of(other.data_type()),
+    }
+}
+
+/// The registers after `istr` runs with `regs`.
+fn transfer(istr: &FlowStructure, regs: &RegisterTypes) -> RegisterTypes {
+    let mut out = *regs;
+    let args = istr.arguments.as_slice();
+    match (istr.op_code, args) {
+        (OpCode::MOV, [GeneralData::Register(dst), src]) => out[*dst as usize] = operand(regs, src),
+        (OpCode::LEA, [GeneralData::Register(dst), _]) => {
+            out[*dst as usize] = Types::of(DataType::Int64)
+        }
+        // The stack is not followed.
+        (OpCode::POP, [GeneralData::Register(dst)]) => out[*dst as usize] = Types::ANY,
+        // At the end of input the register keeps its value.
+        (OpCode::STDIN, [GeneralData::Register(dst), base @ ..]) => {
+            let read = if base.is_empty() {
+                DataType::String
+            } else {
+                DataType::Int64
+            };
+            out[*dst as usize] = regs[*dst as usize].union(Types::of(read));
+        }
+        (OpCode::GETC, [GeneralData::Register(dst)]) => {
+            out[*dst as usize] = regs[*dst as usize].union(Types::of(DataType::Char))
+        }
+        (OpCode::SYSCALL, _) => out[Register::RAX as usize] = Types::of(DataType::Int64),
+        _ => {}
+    }
+    out
+}
+
+/// Why `istr` is certain to fail with `regs`, if it is.
+fn check_istr(istr: &FlowStructure, regs: &RegisterTypes) -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+    let integers = Types::all(&INTEGERS);
+    let op = istr.op_code;
+
+    for arg in &istr.arguments {
+        if let GeneralData::Memory(base, _) = arg {
+            if *base != Register::NIL && regs[*base as usize].intersect(integers).is_empty() {
+                errors.push(format!(
+                    "memory base {:?} holds {}, not an integer",
+                    base, regs[*base as usize]
+                ));
+            }
+        }
+    }
+
+    let mut expect = |what: &str, types: Types, allowed: Types| {
+        if types.intersect(allowed).is_empty() {
+            errors.push(format!("{:?} needs {}, found {}", op, what, types));
+        }
+    };
+    match (op, istr.arguments.as_slice()) {
+        (
+            OpCode::ADD
+            | OpCode::SUB
+            | OpCode::MUL
+            | OpCode::DIV
+            | OpCode::MOD
+            | OpCode::OR
+            | OpCode::AND
+            | OpCode::XOR
+            | OpCode::CMP
+            | OpCode::ASSERT_EQ,
+            [left, right],
+        ) => {
+            let (left, right) = (operand(regs, left), operand(regs, right));
+            let common = left.intersect(right);
+            let numbers = integers.union(Types::all(&[DataType::Float, DataType::Double]));
+            if common.is_empty() {
+                errors.push(format!("{:?} mixes {} and {}", op, left, right));
+            } else if matches!(op, OpCode::OR | OpCode::AND | OpCode::XOR) {
+                expect("integers", common, integers);
+            } else if op == OpCode::ADD {
+                let text = Types::all(&[DataType::Char, DataType::String]);
+                expect("numbers or text", common, numbers.union(text));
+            } else if !matches!(op, OpCode::CMP | OpCode::ASSERT_EQ) {
+                expect("numbers", common, numbers);
+            }
+        }
+        (OpCode::INC, [dst]) => expect(
+            "a number",
+            operand(regs, dst),
+            integers.union(Types::all(&[DataType::Float, DataType::Double])),
+        ),
+        (OpCode::JMP | OpCode::JE | OpCode::JNE | OpCode::CALL, [target]) => expect(
+            "an Int64 target",
+            operand(regs, target),
+            Types::of(DataType::Int64),
+        ),
+        (OpCode::PUTC, [value]) => expect(
+            "a Char or an integer",
+            operand(regs, value),
+            integers.union(Types::of(DataType::Char)),
+        ),
+        (OpCode::HLT | OpCode::EXIT, [status]) => {
+            expect("an integer status", operand(regs, status), integers)
+        }
+        (OpCode::STDOUT | OpCode::STDIN, [_, base]) => expect(
+            "an Int64 base",
+            operand(regs, base),
+            Types::of(DataType::Int64),
+        ),
+        (OpCode::SYSCALL, _) => {
+            for reg in [Register::RAX, Register::RDI, Register::RSI, Register::RDX] {
+                let types = regs[reg as usize];
+                if types.intersect(integers).is_empty() {
+                    errors.push(format!(
+                        "SYSCALL needs an integer in {:?}, found {}",
+                        reg, types
+                    ));
+                }
+            }
+        }
+        _ => {}
+    }
+    errors
+}
+
+/// Where control may go after the instruction at `pc`. Jumps through a
+/// register or memory are assumed to land on a label, and RET on the
+/// instruction after any CALL.
+fn successors(program: &Program, pc: usize, returns: &[usize]) -> Vec<usize> {
+    let istr = &program.code[pc];
+    let target = || cfg::targets(program, istr.arguments.first());
+    let mut next: Vec<usize> = match istr.op_code {
+        OpCode::JMP | OpCode::CALL => target(),
+        OpCode::JE | OpCode::JNE => {
+            let mut next = target();
+            next.push(pc + 1);
+            next
+        }
+        OpCode::RET => returns.to_vec(),
+        OpCode::HLT | OpCode::EXIT => Vec::new(),
+        _ => vec![pc + 1],
+    };
+    next.retain(|pc| *pc < program.code.len());
+    next
+}
+
+/// The types each register may hold as each instruction starts, `None`
+/// for instructions that are never reached from the start or a test.
+pub fn infer(program: &Program) -> Vec<Option<RegisterTypes>> {
+    let mut states: Vec<Option<RegisterTypes>> = vec![None; program.code.len()];
+    if program.code.is_empty() {
+        return states;
+    }
+    let returns: Vec<usize> = (0..program.code.len())
+        .filter(|pc| program.code[*pc].op_code == OpCode::CALL)
+        .map(|pc| pc + 1)
+        .collect();
+
+    // Tests start afresh too.
+    let mut work: Vec<usize> = vec![0];
+    work.extend(testing::discover(program).into_iter().map(|(_, pc)| pc));
+    for pc in &work {
+        states[*pc] = Some(entry());
+    }
+    while let Some(pc) = work.pop() {
+        let out = match &states[pc] {
+            Some(regs) => transfer(&program.code[pc], regs),
+            None => continue,
+        };
+        for next in successors(program, pc, &returns) {
+            let joined = match &states[next] {
+                Some(regs) => {
+                    let mut joined = *regs;
+                    for (slot, types) in joined.iter_mut().zip(out) {
+                        *slot = slot.union(types);
+                    }
+                    joined
+                }
+                None => out,
+            };
+            if states[next] != Some(joined) {
+                states[next] = Some(joined);
+                work.push(next);
+            }
+        }
+    }
+    states
+}
+
+/// Every reachable instruction that would stop the VM with a type error.
+pub fn check(program: &Program) -> Vec<TypeError> {
+    infer(program)
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, regs)| regs.as_ref().map(|regs| (pc, regs)))
+        .flat_map(|(pc, regs)| {
+            let istr = &program.code[pc];
+            check_istr(istr, regs)
+                .into_iter()
+                .map(move |message| TypeError {
+                    pc,
+                    line: istr.line,
+                    message,
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, infer, Types};
+    use crate::structures::data_types::DataType;
+    use crate::structures::registers::Register;
+    use crate::test_support::assemble;
+
+    fn errors(source: &str) -> Vec<String> {
+        check(&assemble(source))
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn type_sets() {
+        let int = Types::of(DataType::Int64);
+        let both = int.union(Types::of(DataType::Double));
+        assert!(both.contains(DataType::Double) && !both.contains(DataType::Char));
+        assert_eq!(both.intersect(int), int);
+        assert!(int.intersect(Types::of(DataType::Char)).is_empty());
+        assert_eq!(both.to_string(), "Int64 or Double");
+        assert_eq!(Types::ANY.to_string(), "anything");
+        assert_eq!(Types::ANY.iter().count(), 10);
+    }
+
+    #[test]
+    fn branches_join_their_types() {
+        let program = assemble(
+            "cmp rdi, 1\nje other\nmov rax, 1\njmp done\nother: mov rax, 1.5\n\
+             done: add rax, 2\nskipped: hlt\nmov rbx, 'x'\n",
+        );
+        let states = infer(&program);
+        let rax = states[5].unwrap()[Register::RAX as usize];
+        assert_eq!(
+            rax,
+            Types::of(DataType::Int64).union(Types::of(DataType::Double))
+        );
+        assert!(states[7].is_none());
+        // Either branch may make the ADD work, so it is not an error.
+        assert!(check(&program).is_empty());
+    }
+
+    #[test]
+    fn certain_failures_are_reported() {
+        assert_eq!(
+            errors("mov rax, 1.5\nadd rax, 1\nmov rbx, \"s\"\nmov rcx, [rbx]\njmp rax\n"),
+            [
+                "2: ADD mixes Double and Int64",
+                "4: memory base RBX holds String, not an integer",
+                "5: JMP needs an Int64 target, found Double",
+            ]
+        );
+        assert_eq!(
+            errors("mov rax, 1.5\nand rax, 2.5\nputc rax\nhlt rax\n"),
+            [
+                "2: AND needs integers, found Double",
+                "3: PUTC needs a Char or an integer, found Double",
+                "4: HLT needs an integer status, found Double",
+            ]
+        );
+    }
+
+    #[test]
+    fn tests_start_from_a_fresh_machine() {
+        let program = assemble("mov rax, 1.5\nhlt\ntest_add: add rax, 1i32\nret\n");
+        let states = infer(&program);
+        assert_eq!(
+            states[2].unwrap()[Register::RAX as usize],
+            Types::of(DataType::Int32)
+        );
+        assert!(check(&program).is_empty());
+    }
+}
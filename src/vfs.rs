@@ -0,0 +1,348 @@
+use crate::structures::host::{EACCES, EBADF, EEXIST, EIO, ENOENT};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// How a file is opened, decoded from the open syscall's flags.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    /// Fail if the file already exists.
+    pub create_new: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+/// An open file, whatever backs it.
+pub trait VirtualFile: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> VirtualFile for T {}
+
+/// Where the open syscall finds files. Errors are errno values.
+pub trait FileSystem {
+    fn open(&mut self, path: &str, mode: &OpenMode) -> Result<Box<dyn VirtualFile>, i64>;
+}
+
+pub fn errno(e: io::Error) -> i64 {
+    if let Some(code) = e.raw_os_error() {
+        return code as i64;
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        _ => EIO,
+    }
+}
+
+/// `path` as plain names, with `.` and `..` resolved and leading `/`
+/// dropped. `None` when `..` climbs above the top.
+fn normalize(path: &str) -> Option<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                names.pop()?;
+            }
+            _ => {}
+        }
+    }
+    Some(names)
+}
+
+/// The real filesystem, optionally confined to one directory which then
+/// acts as `/` for the program.
+pub struct HostFs {
+    root: Option<PathBuf>,
+}
+
+impl HostFs {
+    pub fn init() -> Self {
+        HostFs { root: None }
+    }
+
+    pub fn restricted(root: &Path) -> io::Result<Self> {
+        Ok(HostFs {
+            root: Some(root.canonicalize()?),
+        })
+    }
+
+    /// Resolves `path` inside the root, refusing anything that leaves it,
+    /// through `..` or a symlink.
+    fn resolve(&self, path: &str) -> Result<PathBuf, i64> {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok(PathBuf::from(path)),
+        };
+        let names = normalize(path).ok_or(EACCES)?;
+        let mut resolved = root.clone();
+        resolved.extend(&names);
+
+        let real = match resolved.canonicalize() {
+            Ok(real) => real,
+            // A file about to be created, its directory has to exist.
+            Err(_) => match (resolved.parent(), resolved.file_name()) {
+                (Some(parent), Some(name)) => parent.canonicalize().map_err(|_| ENOENT)?.join(name),
+                _ => return Err(ENOENT),
+            },
+        };
+        if !real.starts_with(root) {
+            return Err(EACCES);
+        }
+        Ok(real)
+    }
+}
+
+impl FileSystem for HostFs {
+    fn open(&mut self, path: &str, mode: &OpenMode) -> Result<Box<dyn VirtualFile>, i64> {
+        let path = self.resolve(path)?;
+        let file = OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .create(mode.create && !mode.create_new)
+            .create_new(mode.create_new)
+            .truncate(mode.truncate)
+            .append(mode.append)
+            .open(path)
+            .map_err(errno)?;
+        Ok(Box::new(file))
+    }
+}
+
+type Contents = Rc<RefCell<Vec<u8>>>;
+
+/// Files kept in memory only. Clones share the same files, so an embedder
+/// can preload inputs, hand a clone to the VM and read the outputs back
+/// afterwards.
+#[derive(Clone, Default)]
+pub struct MemoryFs {
+    files: Rc<RefCell<HashMap<String, Contents>>>,
+}
+
+impl MemoryFs {
+    pub fn init() -> Self {
+        MemoryFs::default()
+    }
+
+    fn key(path: &str) -> Option<String> {
+        normalize(path).map(|names| names.join("/"))
+    }
+
+    /// Creates or replaces the file at `path`.
+    pub fn insert(&self, path: &str, contents: &[u8]) {
+        if let Some(key) = MemoryFs::key(path) {
+            let contents = Rc::new(RefCell::new(contents.to_vec()));
+            self.files.borrow_mut().insert(key, contents);
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let key = MemoryFs::key(path)?;
+        self.files.borrow().get(&key).map(|c| c.borrow().clone())
+    }
+
+    /// Every file path, sorted.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.files.borrow().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn open(&mut self, path: &str, mode: &OpenMode) -> Result<Box<dyn VirtualFile>, i64> {
+        let key = MemoryFs::key(path).ok_or(ENOENT)?;
+        let mut files = self.files.borrow_mut();
+        let contents = match files.get(&key) {
+            Some(_) if mode.create_new => return Err(EEXIST),
+            Some(contents) => contents.clone(),
+            None if mode.create || mode.create_new => {
+                let contents = Contents::default();
+                files.insert(key, contents.clone());
+                contents
+            }
+            None => return Err(ENOENT),
+        };
+        if mode.truncate && mode.write {
+            contents.borrow_mut().clear();
+        }
+        Ok(Box::new(MemoryFile {
+            contents,
+            pos: 0,
+            mode: *mode,
+        }))
+    }
+}
+
+struct MemoryFile {
+    contents: Contents,
+    pos: usize,
+    mode: OpenMode,
+}
+
+fn bad_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(EBADF as i32)
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(bad_descriptor());
+        }
+        let contents = self.contents.borrow();
+        let available = contents.get(self.pos..).unwrap_or_default();
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.mode.write {
+            return Err(bad_descriptor());
+        }
+        let mut contents = self.contents.borrow_mut();
+        if self.mode.append {
+            self.pos = contents.len();
+        }
+        // Writing past the end leaves a zero filled gap, like a sparse file.
+        let end = self.pos + buf.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.pos as i64, offset),
+            SeekFrom::End(offset) => (self.contents.borrow().len() as i64, offset),
+        };
+        match base.checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as usize;
+                Ok(pos as u64)
+            }
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, FileSystem, HostFs, MemoryFs, OpenMode};
+    use crate::structures::host::{EACCES, EBADF, EEXIST, ENOENT};
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    const READ: OpenMode = OpenMode {
+        read: true,
+        write: false,
+        create: false,
+        create_new: false,
+        truncate: false,
+        append: false,
+    };
+    const CREATE: OpenMode = OpenMode {
+        read: true,
+        write: true,
+        create: true,
+        ..READ
+    };
+
+    fn error<T>(result: Result<T, i64>) -> i64 {
+        result.err().unwrap()
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize("/a/./b/../c").unwrap(), ["a", "c"]);
+        assert_eq!(normalize("a/..").unwrap(), Vec::<String>::new());
+        assert!(normalize("../a").is_none());
+    }
+
+    #[test]
+    fn memory_files() {
+        let mut fs = MemoryFs::init();
+        fs.insert("/in.txt", b"hello");
+        assert_eq!(error(fs.open("missing", &READ)), ENOENT);
+        let new = OpenMode {
+            create_new: true,
+            ..CREATE
+        };
+        assert_eq!(error(fs.open("./in.txt", &new)), EEXIST);
+
+        let mut file = fs.open("in.txt", &READ).unwrap();
+        let mut text = String::new();
+        file.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello");
+        let e = file.write(b"x").unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(EBADF as i32));
+
+        let mut file = fs.open("dir/../out.txt", &CREATE).unwrap();
+        file.write_all(b"ab").unwrap();
+        file.seek(SeekFrom::Current(2)).unwrap();
+        file.write_all(b"cd").unwrap();
+        assert!(file.seek(SeekFrom::Current(-10)).is_err());
+        assert_eq!(fs.get("/out.txt").unwrap(), b"ab\0\0cd");
+
+        let append = OpenMode {
+            append: true,
+            ..CREATE
+        };
+        let mut file = fs.open("out.txt", &append).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"!").unwrap();
+        let truncate = OpenMode {
+            truncate: true,
+            ..CREATE
+        };
+        fs.open("in.txt", &truncate).unwrap();
+        assert_eq!(fs.get("out.txt").unwrap(), b"ab\0\0cd!");
+        assert_eq!(fs.get("in.txt").unwrap(), b"");
+        assert_eq!(fs.paths(), ["in.txt", "out.txt"]);
+    }
+
+    #[test]
+    fn restricted_host_stays_inside_its_root() {
+        let dir = std::env::temp_dir().join(format!("vcpu-vfs-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("secret"), b"no").unwrap();
+        fs::write(root.join("inside"), b"yes").unwrap();
+
+        let mut host = HostFs::restricted(&root).unwrap();
+        let mut text = String::new();
+        let mut file = host.open("/inside", &READ).unwrap();
+        file.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "yes");
+        host.open("new", &CREATE).unwrap();
+        assert!(root.join("new").exists());
+        assert_eq!(error(host.open("../secret", &READ)), EACCES);
+        assert_eq!(error(host.open("missing/file", &CREATE)), ENOENT);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret"), root.join("link")).unwrap();
+            assert_eq!(error(host.open("link", &READ)), EACCES);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
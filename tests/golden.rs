@@ -0,0 +1,178 @@
+//! Runs every program in `tests/golden` and compares what it prints, how
+//! it stops and its final registers with the `.expected` file beside it.
+//! A `.stdin` file beside a program is its input.
+//!
+//! `VCPU_BLESS=1 cargo test --test golden` writes the expected files from
+//! the current behaviour instead.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{env, fs};
+use vcpu::structures::data_types::GeneralData;
+use vcpu::structures::host::SandboxHost;
+use vcpu::structures::interpreter::Interpreter;
+use vcpu::structures::registers::Register;
+use vcpu::structures::structures::GeneralStructure;
+
+/// Instructions a program may run before it counts as stuck.
+const BUDGET: u64 = 100_000;
+
+/// Collects what the program prints.
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn show(data: &GeneralData) -> String {
+    match data {
+        GeneralData::String(text) => format!("{:?}", text),
+        GeneralData::Char(c) => format!("{:?}", c),
+        _ => data.to_string(),
+    }
+}
+
+/// Runs `source` with `input`, a whole run at a time or one step at a
+/// time, and describes the outcome as an expected file holds it.
+fn run(source: &str, input: &[u8], stepped: bool) -> String {
+    let program = match Interpreter::from_source(source.to_string()) {
+        Ok(program) => program,
+        Err(errors) => {
+            let mut out = String::from("== status\ndoes not assemble\n");
+            for e in errors {
+                writeln!(out, "{}", e).unwrap();
+            }
+            return out;
+        }
+    };
+    let mut vm = GeneralStructure::init(program.code, &program.data);
+    let output = Rc::new(RefCell::new(Vec::new()));
+    vm.env_mut().set_host(Box::new(SandboxHost::default()));
+    vm.env_mut().set_output(Box::new(Capture(output.clone())));
+    vm.env_mut()
+        .set_input(Box::new(io::Cursor::new(input.to_vec())));
+
+    let result = if stepped {
+        let mut steps = 0;
+        let mut stepped = Ok(true);
+        while steps < BUDGET && stepped == Ok(true) {
+            stepped = vm.step();
+            steps += 1;
+        }
+        stepped.map(|_| ())
+    } else {
+        vm.run_for(BUDGET).map(|_| ())
+    };
+    vm.env_mut().flush();
+
+    let mut out = String::from("== status\n");
+    match result {
+        Err(error) => writeln!(out, "error at #{}: {}", error.pc, error).unwrap(),
+        Ok(()) if !vm.finished() => writeln!(out, "ran out of steps at #{}", vm.pc()).unwrap(),
+        Ok(()) => writeln!(out, "exit {}", vm.env().exit_code()).unwrap(),
+    }
+    out.push_str("== stdout\n");
+    out.push_str(&String::from_utf8_lossy(&output.borrow()));
+    if !out.ends_with('\n') {
+        out.push_str("\n(no newline)\n");
+    }
+    out.push_str("== registers\n");
+    for i in 0..(Register::NIL as usize) {
+        let reg: Register = num_traits::FromPrimitive::from_usize(i).unwrap();
+        let data = vm.env().register(reg);
+        writeln!(out, "{:?} {:?} {}", reg, data.data_type(), show(data)).unwrap();
+    }
+    writeln!(out, "ZF {}", vm.env().flags().zf).unwrap();
+    out
+}
+
+fn programs(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// The first line where `expected` and `actual` differ, for the report.
+fn first_difference(expected: &str, actual: &str) -> String {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => continue,
+            (None, None) => break,
+            (e, a) => {
+                return format!(
+                    "line {}: expected {:?}, got {:?}",
+                    line,
+                    e.unwrap_or("<end>"),
+                    a.unwrap_or("<end>")
+                )
+            }
+        }
+    }
+    String::from("they differ in line endings")
+}
+
+#[test]
+fn golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = env::var_os("VCPU_BLESS").is_some();
+    let mut failures: Vec<String> = Vec::new();
+    let paths = programs(&dir);
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let source = fs::read_to_string(path).unwrap();
+        let input = fs::read(path.with_extension("stdin")).unwrap_or_default();
+
+        let actual = run(&source, &input, false);
+        let stepped = run(&source, &input, true);
+        if stepped != actual {
+            failures.push(format!(
+                "{}: stepping differs from running, {}",
+                name,
+                first_difference(&actual, &stepped)
+            ));
+        }
+
+        let expected_path = path.with_extension("expected");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{}: {}",
+                name,
+                first_difference(&expected, &actual)
+            )),
+            Err(_) => failures.push(format!(
+                "{}: no {}, run with VCPU_BLESS=1 to write it",
+                name,
+                expected_path.file_name().unwrap().to_string_lossy()
+            )),
+        }
+    }
+    assert!(!paths.is_empty(), "no programs in {}", dir.display());
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden programs failed:\n{}",
+        failures.len(),
+        paths.len(),
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,32 @@
+; ADD for each numeric type, from immediates, registers and memory.
+    mov rax, 1u32
+    add rax, 2u32
+    pnl rax
+    mov rax, 1u64
+    add rax, 2u64
+    pnl rax
+    mov rax, -1i32
+    add rax, 2i32
+    pnl rax
+    mov rax, -1
+    add rax, 2
+    mov rbx, 10
+    add rax, rbx
+    add rax, [slot]
+    pnl rax
+    mov rax, 1.5f32
+    add rax, 0.25f32
+    pnl rax
+    mov rax, 1.5
+    add rax, 0.25
+    pnl rax
+    mov rax, 'A'
+    add rax, ' '
+    pnl rax
+    ; Strings are joined.
+    mov rax, "ab"
+    add rax, "cd"
+    pnl rax
+    hlt
+
+slot: dq 100
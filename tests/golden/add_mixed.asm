@@ -0,0 +1,4 @@
+; ADD of an Int64 and a Double.
+    mov rax, 1
+    add rax, 1.0
+    hlt
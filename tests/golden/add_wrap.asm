@@ -0,0 +1,13 @@
+; ADD wraps around like SUB and MUL, through the fast path for Int64
+; registers too.
+    mov rax, 9223372036854775807
+    add rax, 1
+    pnl rax
+    mov rbx, 9223372036854775807
+    mov rcx, 2
+    add rbx, rcx
+    pnl rbx
+    mov rdx, 4294967295u32
+    add rdx, 1u32
+    pnl rdx
+    hlt
@@ -0,0 +1,16 @@
+; AND for each integer type.
+    mov rax, 7u32
+    and rax, 12u32
+    pnl rax
+    mov rax, 255u64
+    and rax, 0x0fu64
+    pnl rax
+    mov rax, -1i32
+    and rax, 5i32
+    pnl rax
+    mov rax, 0xff
+    and rax, [mask]
+    pnl rax
+    hlt
+
+mask: dq 0x3c
@@ -0,0 +1,4 @@
+; AND of floating point numbers.
+    mov rax, 1.0
+    and rax, 2.0
+    hlt
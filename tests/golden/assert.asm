@@ -0,0 +1,21 @@
+; ASSERT_EQ and ASSERT_NE that hold, for every data type.
+    mov rax, 5
+    assert_eq rax, 5
+    assert_ne rax, 6
+    assert_ne rax, 5i32
+    assert_eq 1u32, 1u32
+    assert_eq 2u64, 2u64
+    assert_eq -3i32, -3i32
+    assert_eq 0.5f32, 0.5f32
+    assert_eq 0.5, 0.5
+    assert_ne 0.5, 0.5f32
+    assert_eq "a", "a"
+    assert_ne "a", "b"
+    assert_eq 'z', 'z'
+    assert_eq [slot], 9
+    mov rbx, rax
+    assert_eq rax, rbx
+    pnl "held"
+    hlt
+
+slot: dq 9
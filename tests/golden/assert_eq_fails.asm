@@ -0,0 +1,5 @@
+; A failing ASSERT_EQ stops the program.
+    mov rax, 1
+    add rax, 1
+    assert_eq rax, 3
+    pnl "not reached"
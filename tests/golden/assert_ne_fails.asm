@@ -0,0 +1,2 @@
+; A failing ASSERT_NE stops the program.
+    assert_ne "same", "same"
@@ -0,0 +1,39 @@
+; CMP for each data type, with JE and JNE on the result.
+    mov rax, 3u32
+    cmp rax, 3u32
+    je equal_u32
+    pnl "wrong"
+equal_u32:
+    cmp 4u64, 5u64
+    jne differ_u64
+    pnl "wrong"
+differ_u64:
+    cmp -1i32, -1i32
+    je equal_i32
+    pnl "wrong"
+equal_i32:
+    mov rbx, 8
+    cmp rbx, [slot]
+    je equal_i64
+    pnl "wrong"
+equal_i64:
+    cmp 0.5f32, 0.5f32
+    je equal_f32
+    pnl "wrong"
+equal_f32:
+    cmp 0.5, 0.75
+    jne differ_f64
+    pnl "wrong"
+differ_f64:
+    cmp "a", "a"
+    je equal_string
+    pnl "wrong"
+equal_string:
+    cmp 'x', 'y'
+    jne differ_char
+    pnl "wrong"
+differ_char:
+    pnl "compared"
+    hlt
+
+slot: dq 8
@@ -0,0 +1,3 @@
+; CMP of a Uint32 and an Int32.
+    cmp 1u32, 1i32
+    hlt
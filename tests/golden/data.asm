@@ -0,0 +1,12 @@
+; DB and DQ data read back through memory operands.
+    pnl [words]
+    pnl [words+8]
+    lea rax, words
+    pnl [rax+16]
+    mov rbx, [bytes]
+    stdout rbx, 16
+    putc 10
+    hlt
+
+words: dq 1, -2, 3
+bytes: db 1, 2, 3, 4, 5, 6, 7, 8
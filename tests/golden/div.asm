@@ -0,0 +1,21 @@
+; DIV for each numeric type, integers rounding towards zero.
+    mov rax, 7u32
+    div rax, 2u32
+    pnl rax
+    mov rax, 100u64
+    div rax, 10u64
+    pnl rax
+    mov rax, -7i32
+    div rax, 2i32
+    pnl rax
+    mov rax, -9223372036854775807
+    sub rax, 1
+    div rax, -1
+    pnl rax
+    mov rax, 1.0f32
+    div rax, 4.0f32
+    pnl rax
+    mov rax, 1.0
+    div rax, 0.0
+    pnl rax
+    hlt
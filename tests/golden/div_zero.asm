@@ -0,0 +1,5 @@
+; DIV by an integer zero.
+    mov rax, 1
+    mov rbx, 0
+    div rax, rbx
+    hlt
@@ -0,0 +1,3 @@
+; EXIT stops with a status.
+    pnl "bye"
+    exit 42
@@ -0,0 +1,2 @@
+; Running past the last instruction ends the program with 0.
+    mov rax, 1
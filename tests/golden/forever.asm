@@ -0,0 +1,3 @@
+; A program that never stops runs out of steps.
+loop:
+    jmp loop
@@ -0,0 +1,3 @@
+; FREE is reserved but not implemented, so this does not assemble.
+    mov rax, 0
+    free rax
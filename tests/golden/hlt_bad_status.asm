@@ -0,0 +1,3 @@
+; HLT with a status that is not an integer.
+    mov rax, 1.5
+    hlt rax
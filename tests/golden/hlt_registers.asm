@@ -0,0 +1,5 @@
+; HLT with its status in a register of each integer type.
+    mov rax, 4u32
+    mov rbx, 5u64
+    mov rcx, 6i32
+    hlt rcx
@@ -0,0 +1,20 @@
+; INC for each numeric type, with integers wrapping around.
+    mov rax, 4294967295u32
+    inc rax
+    pnl rax
+    mov rax, 1u64
+    inc rax
+    pnl rax
+    mov rax, 2147483647i32
+    inc rax
+    pnl rax
+    mov rax, -1
+    inc rax
+    pnl rax
+    mov rax, 0.5f32
+    inc rax
+    pnl rax
+    mov rax, 0.25
+    inc rax
+    pnl rax
+    hlt
@@ -0,0 +1,4 @@
+; INC of a string.
+    mov rax, "a"
+    inc rax
+    hlt
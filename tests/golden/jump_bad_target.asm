@@ -0,0 +1,3 @@
+; Jump targets must be instruction indices.
+    mov rax, 1.0
+    jmp rax
@@ -0,0 +1,3 @@
+; A jump before the first instruction is an error, not the end.
+    jmp -5
+    hlt 3
@@ -0,0 +1,5 @@
+; Jumping beyond just past the last instruction is an error.
+    mov rax, done
+    add rax, 1
+    jmp rax
+done:
@@ -0,0 +1,4 @@
+; Jumping to just past the last instruction ends the program.
+    jmp done
+    hlt 3
+done:
@@ -0,0 +1,30 @@
+; JMP, JE and JNE to labels, through registers and memory, and CALL
+; and RET, directly and through a register.
+    jmp start
+    pnl "skipped"
+start:
+    lea rax, second
+    jmp rax
+    pnl "skipped"
+second:
+    lea rbx, table
+    jmp [rbx+8]
+    pnl "skipped"
+third:
+    cmp 1, 2
+    je third
+    jne fourth
+    pnl "skipped"
+fourth:
+    call twice
+    lea rcx, twice
+    call rcx
+    pnl rdx
+    hlt
+
+twice:
+    pnl "twice"
+    add rdx, 1i32
+    ret
+
+table: dq start, third
@@ -0,0 +1,2 @@
+; MALLOC is reserved but not implemented, so this does not assemble.
+    malloc rax, 16
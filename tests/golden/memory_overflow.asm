@@ -0,0 +1,3 @@
+; An address that overflows is out of bounds rather than wrapping around.
+    mov rbx, 0x7fffffffffffffff
+    mov rax, [rbx+8]
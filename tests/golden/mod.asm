@@ -0,0 +1,27 @@
+; MOD for each numeric type, with ZF set on a zero result.
+    mov rax, 7u32
+    mod rax, 3u32
+    pnl rax
+    mov rax, 9u64
+    mod rax, 3u64
+    je zero_u64
+    pnl "wrong"
+zero_u64:
+    pnl rax
+    mov rax, -7i32
+    mod rax, 4i32
+    pnl rax
+    mov rax, 17
+    mov rbx, 5
+    mod rax, rbx
+    jne nonzero_i64
+    pnl "wrong"
+nonzero_i64:
+    pnl rax
+    mov rax, 5.5f32
+    mod rax, 2.0f32
+    pnl rax
+    mov rax, 5.5
+    mod rax, 2.5
+    pnl rax
+    hlt
@@ -0,0 +1,4 @@
+; MOD is not defined for characters.
+    mov rax, 'a'
+    mod rax, 'b'
+    hlt
@@ -0,0 +1,4 @@
+; MOD by an integer zero.
+    mov rax, 1
+    mod rax, 0
+    hlt
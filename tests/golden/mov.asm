@@ -0,0 +1,40 @@
+; MOV of every data type into a register, between registers and through
+; memory, and LEA of labels and memory operands.
+    mov rax, 7u32
+    pnl rax
+    mov rax, 7u64
+    pnl rax
+    mov rax, -7i32
+    pnl rax
+    mov rax, -7
+    pnl rax
+    mov rax, 2.5f32
+    pnl rax
+    mov rax, 2.5
+    pnl rax
+    mov rax, "text"
+    pnl rax
+    mov rax, 'c'
+    pnl rax
+    mov rbx, rax
+    pnl rbx
+    mov rcx, [slot]
+    pnl rcx
+    mov [slot], 99
+    mov rcx, [slot]
+    pnl rcx
+    mov rdx, 123
+    mov [slot], rdx
+    lea rsi, slot
+    mov rdi, [rsi]
+    pnl rdi
+    lea rsi, [rsi+8]
+    mov [rsi], -1
+    mov rdi, [rsi]
+    pnl rdi
+    lea rbp, done
+    pnl rbp
+done:
+    hlt
+
+slot: dq 42, 0
@@ -0,0 +1,20 @@
+; Memory holds the 8-byte pattern of what is stored there and reads back
+; as Int64. Strings store as zero.
+    mov [slot], 7u32
+    pnl [slot]
+    mov [slot], 18446744073709551615u64
+    pnl [slot]
+    mov [slot], -7i32
+    pnl [slot]
+    mov [slot], 1.0f32
+    pnl [slot]
+    mov [slot], 1.0
+    pnl [slot]
+    mov [slot], 'A'
+    pnl [slot]
+    mov [slot], "text"
+    pnl [slot]
+    mov rax, [slot]
+    hlt
+
+slot: dq 5
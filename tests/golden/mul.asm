@@ -0,0 +1,21 @@
+; MUL for each numeric type, with integers wrapping around.
+    mov rax, 65536u32
+    mul rax, 65536u32
+    pnl rax
+    mov rax, 3u64
+    mul rax, 7u64
+    pnl rax
+    mov rax, -3i32
+    mul rax, 5i32
+    pnl rax
+    mov rax, 6
+    mov rbx, -7
+    mul rax, rbx
+    pnl rax
+    mov rax, 1.5f32
+    mul rax, 4.0f32
+    pnl rax
+    mov rax, 0.5
+    mul rax, 0.5
+    pnl rax
+    hlt
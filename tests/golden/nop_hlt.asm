@@ -0,0 +1,6 @@
+; NOP does nothing and HLT stops with a status.
+    mov rax, 1
+    nop
+    pnl rax
+    hlt 3
+    pnl "not reached"
@@ -0,0 +1,8 @@
+; Operands of the wrong kind and unknown labels do not assemble.
+    mov 5, rax
+    jmp nowhere
+    lea rax, rbx
+    pop 1
+again:
+again:
+    hlt
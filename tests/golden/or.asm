@@ -0,0 +1,15 @@
+; OR for each integer type.
+    mov rax, 1u32
+    or rax, 6u32
+    pnl rax
+    mov rax, 8u64
+    or rax, 1u64
+    pnl rax
+    mov rax, -16i32
+    or rax, 3i32
+    pnl rax
+    mov rax, 0x0f
+    mov rbx, 0xf0
+    or rax, rbx
+    pnl rax
+    hlt
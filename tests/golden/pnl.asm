@@ -0,0 +1,18 @@
+; PNL of every data type, immediate, in a register and in memory.
+    pnl 1u32
+    pnl 18446744073709551615u64
+    pnl -2147483648i32
+    pnl -9223372036854775808
+    pnl 3.25f32
+    pnl -0.125
+    pnl "line"
+    pnl 'q'
+    mov rax, 12
+    pnl rax
+    pnl [slot]
+    lea rbx, slot
+    pnl [rbx]
+    pnl ""
+    hlt
+
+slot: dq -5
@@ -0,0 +1,3 @@
+; POP with nothing on the stack.
+    pop rax
+    hlt
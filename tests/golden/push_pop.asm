@@ -0,0 +1,35 @@
+; PUSH of every data type, and POP back in reverse order.
+    mov rax, 5
+    push 1u32
+    push 2u64
+    push -3i32
+    push 4
+    push 0.5f32
+    push 0.25
+    push "s"
+    push 'p'
+    push rax
+    push [slot]
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    pop rbx
+    pnl rbx
+    hlt
+
+slot: dq 77
@@ -0,0 +1,13 @@
+; PUTC of characters and code points.
+    putc 'h'
+    putc 105
+    mov rax, 'é'
+    putc rax
+    putc 0x1F600
+    ; Any integer width, and code points past Unicode print U+FFFD.
+    putc 65u32
+    putc 66i32
+    putc 67u64
+    putc 0x110000
+    putc 10
+    hlt
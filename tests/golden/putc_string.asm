@@ -0,0 +1,3 @@
+; PUTC of a string.
+    putc "no"
+    hlt
@@ -0,0 +1,2 @@
+; RET without a CALL.
+    ret
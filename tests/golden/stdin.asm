@@ -0,0 +1,26 @@
+; STDIN of lines and of numbers in a base, into registers and memory,
+; and GETC up to the end of the input.
+    stdin rax
+    pnl rax
+    stdin rbx, 16
+    pnl rbx
+    stdin [slot], 2
+    pnl [slot]
+    getc rcx
+    pnl rcx
+    getc rcx
+    pnl rcx
+loop:
+    getc rdx
+    je eof
+    putc rdx
+    jmp loop
+eof:
+    pnl "eof"
+    stdin rax
+    je nothing
+    pnl "wrong"
+nothing:
+    hlt
+
+slot: dq 0
@@ -0,0 +1,5 @@
+; A word that is not a number is an error, unlike the end of input.
+    stdin rax, 10
+    pnl rax
+    stdin rax, 10
+    pnl "unreachable"
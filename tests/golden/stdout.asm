@@ -0,0 +1,37 @@
+; STDOUT of every data type, and of integers in other bases.
+    stdout 1u32
+    putc ' '
+    stdout 2u64
+    putc ' '
+    stdout -3i32
+    putc ' '
+    stdout 4
+    putc ' '
+    stdout 0.5f32
+    putc ' '
+    stdout 0.75
+    putc ' '
+    stdout "str"
+    putc ' '
+    stdout 'c'
+    putc 10
+    stdout 255, 2
+    putc ' '
+    stdout 255, 8
+    putc ' '
+    stdout -255, 16
+    putc ' '
+    stdout 35u32, 36
+    putc ' '
+    stdout 35u64, 36
+    putc ' '
+    stdout -35i32, 36
+    putc ' '
+    mov rax, 4096
+    stdout rax, 16
+    putc ' '
+    stdout [slot], 16
+    putc 10
+    hlt
+
+slot: dq 48879
@@ -0,0 +1,24 @@
+; SUB for each numeric type, with integers wrapping around.
+    mov rax, 5u32
+    sub rax, 7u32
+    pnl rax
+    mov rax, 5u64
+    sub rax, 2u64
+    pnl rax
+    mov rax, -2147483648i32
+    sub rax, 1i32
+    pnl rax
+    mov rax, 10
+    mov rbx, 25
+    sub rax, rbx
+    sub rax, [slot]
+    pnl rax
+    mov rax, 1.5f32
+    sub rax, 0.25f32
+    pnl rax
+    mov rax, 0.5
+    sub rax, 2.0
+    pnl rax
+    hlt
+
+slot: dq 100
@@ -0,0 +1,4 @@
+; Programs that do not assemble report their errors.
+    mov rax
+    jmp nowhere
+    bogus rax, 1
@@ -0,0 +1,57 @@
+; SYSCALL write, brk, mmap, clock_gettime, getrandom, open and close
+; under the sandbox, an unknown call and exit.
+    mov rax, 1
+    mov rdi, 1
+    lea rsi, msg
+    mov rdx, 6
+    syscall
+    pnl rax
+    mov rax, 12
+    mov rdi, 0
+    syscall
+    mov rbx, rax
+    add rbx, 64
+    mov rax, 12
+    mov rdi, rbx
+    syscall
+    pnl rax
+    mov rax, 9
+    mov rdi, 0
+    mov rsi, 4096
+    mov rdx, 3
+    syscall
+    pnl rax
+    mov rax, 228
+    mov rdi, 0
+    lea rsi, time
+    syscall
+    pnl rax
+    pnl [time]
+    mov rax, 318
+    lea rdi, random
+    mov rsi, 8
+    mov rdx, 0
+    syscall
+    pnl rax
+    pnl [random]
+    mov rax, 2
+    lea rdi, path
+    mov rsi, 65
+    syscall
+    mov rdi, rax
+    pnl rax
+    mov rax, 3
+    syscall
+    pnl rax
+    mov rax, 1000
+    syscall
+    pnl rax
+    mov rax, 60
+    mov rdi, 7
+    syscall
+    pnl "not reached"
+
+msg: db "hello", 10
+time: dq 0, 0
+random: dq 0
+path: db "file.txt", 0
@@ -0,0 +1,3 @@
+; SYSCALL needs integers in its registers.
+    mov rax, "write"
+    syscall
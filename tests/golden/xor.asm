@@ -0,0 +1,14 @@
+; XOR for each integer type, a register with itself clears it.
+    mov rax, 5u32
+    xor rax, 3u32
+    pnl rax
+    mov rax, 1u64
+    xor rax, 1u64
+    pnl rax
+    mov rax, -1i32
+    xor rax, 1i32
+    pnl rax
+    mov rax, 12345
+    xor rax, rax
+    pnl rax
+    hlt